@@ -1,6 +1,150 @@
-from typing import Union, Literal
+from typing import Dict, Iterator, List, Optional, Sequence, Tuple, Union, Literal
 
-def render_wave_from(soundfont_bytes: bytes, midi_bytes: bytes) -> bytes:
+import numpy
+
+class SoundFont:
+    """
+    A parsed SoundFont that can be shared across many renders.
+
+    Parsing a large General MIDI bank can take longer than rendering a short
+    clip. Load the bank once and pass the `SoundFont` object to the render
+    functions in place of the raw bytes.
+
+    Args:
+        soundfont_bytes (bytes): The raw bytes of a SoundFont (.sf2 or .sf3) file.
+
+    Attributes:
+        presets (List[Preset]): The presets, ordered by bank and program.
+        instruments (List[str]): The names of the instruments, in file order.
+        sample_count (int): The number of samples.
+        sample_memory (int): Bytes of 16-bit sample data held in memory, counting
+            SF3 samples once decoded.
+        info (Dict[str, str]): The INFO chunk: "version", "sound_engine", "bank_name",
+            "rom_name", "rom_version", "creation_date", "author", "product", "copyright",
+            "comments" and "tools". Fields the file leaves out are empty strings.
+
+    Raises:
+        ValueError: If the bytes are not a valid SoundFont.
+
+    Example:
+        >>> from pathlib import Path
+        >>> soundfont = SoundFont(Path("path/to/soundfont.sf2").read_bytes())
+        >>> for midi_path in Path("midi").glob("*.mid"):
+        ...     wav_data = render_wave_from(soundfont, midi_path.read_bytes())
+        >>> [(preset.bank, preset.program, preset.name) for preset in soundfont.presets]
+        [(0, 0, 'Grand Piano'), (0, 1, 'Bright Piano'), ...]
+    """
+    def __init__(self, soundfont_bytes: bytes) -> None: ...
+    @property
+    def presets(self) -> List[Preset]: ...
+    @property
+    def instruments(self) -> List[str]: ...
+    @property
+    def sample_count(self) -> int: ...
+    @property
+    def sample_memory(self) -> int: ...
+    @property
+    def info(self) -> Dict[str, str]: ...
+
+class Preset:
+    """
+    A preset of a `SoundFont`, as listed by `SoundFont.presets`.
+
+    Attributes:
+        name (str): The preset name.
+        bank (int): The bank number; drum kits sit in bank 128.
+        program (int): The program number, 0 to 127.
+    """
+
+    name: str
+    bank: int
+    program: int
+
+class SoundFontLayer:
+    """
+    A SoundFont in a list of SoundFonts, moved to other banks or limited to some channels.
+
+    Args:
+        soundfont (Union[bytes, SoundFont]): The raw bytes of a SoundFont (.sf2 or .sf3)
+            file, or a `SoundFont` loaded earlier.
+        bank_offset (int, optional): Added to the SoundFont's bank numbers, between 0 and
+            127, so a Bank Select of `bank_offset` reaches its bank 0 and its presets can
+            sit beside those of another SoundFont. Defaults to 0.
+        channels (Optional[Sequence[int]], optional): The MIDI channels, 0 to 15, that may
+            play from this SoundFont. Defaults to None, which allows all of them.
+
+    Raises:
+        ValueError: If the bytes are not a valid SoundFont or an argument is out of range.
+
+    Example:
+        >>> from pathlib import Path
+        >>> general_midi = SoundFont(Path("gm.sf2").read_bytes())
+        >>> piano = SoundFontLayer(Path("piano.sf2").read_bytes(), channels=[0])
+        >>> wav_data = render_wave_from([piano, general_midi], Path("song.mid").read_bytes())
+    """
+    def __init__(
+        self,
+        soundfont: Union[bytes, SoundFont],
+        bank_offset: int = 0,
+        channels: Optional[Sequence[int]] = None,
+    ) -> None: ...
+
+# One SoundFont, or several layered in order of priority.
+SoundFonts = Union[
+    bytes, SoundFont, SoundFontLayer, Sequence[Union[bytes, SoundFont, SoundFontLayer]]
+]
+
+class OverloadReport:
+    """
+    What overload handling did to a render, returned alongside it when `report` is True.
+
+    Attributes:
+        affected_samples (int): Samples of the output channels that were clipped, saturated
+            or turned down. With overload="clip" these are the samples beyond full scale,
+            which integer formats clip and float output keeps.
+        total_samples (int): Samples of the output channels, counting every channel.
+        peak_db (float): The highest sample level before overload handling, in dBFS. Above
+            0 when the render overloaded, and `-inf` when it is silent.
+    """
+
+    affected_samples: int
+    total_samples: int
+    peak_db: float
+
+def render_wave_from(
+    soundfont_bytes: SoundFonts,
+    midi_bytes: bytes,
+    stereo: bool = True,
+    *,
+    downmix: Literal["average", "pan_law", "left", "right"] = "average",
+    sample_format: Literal["s16", "s24", "s32", "f32"] = "s16",
+    sample_rate: int = 48000,
+    tail: Optional[Union[float, Literal["silent"]]] = None,
+    tail_threshold: float = -60.0,
+    max_tail: float = 10.0,
+    start: float = 0.0,
+    end: Optional[float] = None,
+    loop_count: int = 0,
+    loop_start: Optional[float] = None,
+    loop_end: Optional[float] = None,
+    loop_intro: bool = True,
+    enable_reverb_and_chorus: bool = True,
+    block_size: int = 64,
+    maximum_polyphony: int = 64,
+    target_lufs: Optional[float] = None,
+    true_peak: float = -1.0,
+    overload: Literal["clip", "soft", "limit"] = "clip",
+    limiter_ceiling: float = -1.0,
+    limiter_release: float = 0.05,
+    trim: Optional[Literal["start", "end", "both"]] = None,
+    trim_threshold: float = -60.0,
+    fade_in: float = 0.0,
+    fade_out: float = 0.0,
+    fade_curve: Literal["linear", "exponential"] = "linear",
+    dither: Literal["none", "tpdf", "shaped"] = "none",
+    dither_seed: Optional[int] = None,
+    report: bool = False,
+) -> Union[bytes, Tuple[bytes, OverloadReport]]:
     """
     Render a MIDI file to WAV format using the provided SoundFont.
 
@@ -9,11 +153,108 @@ def render_wave_from(soundfont_bytes: bytes, midi_bytes: bytes) -> bytes:
     using a high-performance Rust backend for optimal speed and efficiency.
 
     Args:
-        soundfont_bytes (bytes): The raw bytes of a SoundFont (.sf2) file.
+        soundfont_bytes (SoundFonts): The raw bytes of a SoundFont (.sf2 or .sf3) file, a
+            `SoundFont` loaded earlier, or a list of those and `SoundFontLayer`s to render
+            together. Each note plays from the first SoundFont in the list that is open to
+            its channel and has the channel's bank and program, such as a piano-only
+            SoundFont placed before a General MIDI bank. When none has it, the first with
+            the same program in bank 0 (or the standard drum kit) plays it, and failing
+            that the last one. Each SoundFont gets its own synthesizer, with its own
+            `maximum_polyphony` and effects.
         midi_bytes (bytes): The raw bytes of a MIDI (.mid) file.
+        stereo (bool, optional): Whether to write two channels. When False, the render is
+            downmixed to mono. Defaults to True.
+        downmix (Literal["average", "pan_law", "left", "right"], optional): How a mono output
+            is mixed from the stereo render. "average" takes half of each channel, "pan_law"
+            takes each channel at -3 dB (louder for centred sounds), and "left" or "right"
+            keep only that channel. Ignored when `stereo` is True. Defaults to "average".
+        sample_format (Literal["s16", "s24", "s32", "f32"], optional): The sample encoding:
+            16, 24 or 32-bit signed integers, or 32-bit float. Integer formats clip at full
+            scale; "f32" keeps louder peaks for later processing. Formats other than "s16"
+            are written with WAVE_FORMAT_EXTENSIBLE. Defaults to "s16".
+        sample_rate (int, optional): The synthesis and output sample rate in Hz,
+            between 16000 and 192000. Defaults to 48000.
+        tail (Optional[Union[float, Literal["silent"]]], optional): How long to keep rendering
+            after the last MIDI event so release envelopes and reverb can ring out.
+            A number of seconds, up to 60, renders a fixed tail; "silent" renders until the
+            output drops below `tail_threshold`. Defaults to None, which stops at the last
+            event.
+        tail_threshold (float, optional): The level in dBFS below which the "silent" tail
+            is considered finished. Defaults to -60.0.
+        max_tail (float, optional): The longest "silent" tail to render, in seconds, up to
+            60. Defaults to 10.0.
+        start (float, optional): Where to start rendering, in seconds. Programs, controllers
+            and pitch bend are brought up to date without rendering the skipped audio, and
+            notes still held at `start` are struck there. Defaults to 0.0.
+        end (Optional[float], optional): Where to stop rendering, in seconds. Notes still
+            sounding are released, so combine with `tail` to let them ring out.
+            Defaults to None, which renders to the end of the file.
+        loop_count (int, optional): How many times to play the loop between `loop_start`
+            and `loop_end`, as game music does, instead of `start` and `end`. Controllers
+            carry over each jump back, notes held at `loop_start` are struck again, and
+            `tail` follows the last repetition. The last repetition is marked for engines
+            that loop without gaps: WAV files get a `smpl` chunk, and FLAC, Opus and Vorbis
+            files LOOPSTART and LOOPLENGTH comments in samples (at 48kHz for Opus). MP3 and
+            PCM carry no loop points. Cannot be combined with `trim` or `fade_out`, which
+            would change the marked loop. At most 100. Defaults to 0, which plays the
+            song once.
+        loop_start (Optional[float], optional): Where the loop begins, in seconds.
+            Defaults to None, which takes a "loopStart" marker or, failing that, the first
+            CC111 in the file.
+        loop_end (Optional[float], optional): Where the loop ends and jumps back, in
+            seconds. Defaults to None, which takes a "loopEnd" marker or the end of the file.
+        loop_intro (bool, optional): Whether the render begins with the part of the song
+            before the loop. Defaults to True.
+        enable_reverb_and_chorus (bool, optional): Whether the synthesizer applies its reverb
+            and chorus effects. Disable for dry renders. Defaults to True.
+        block_size (int, optional): The synthesizer block size in samples, between 8 and 1024.
+            MIDI events are applied on block boundaries. Defaults to 64.
+        maximum_polyphony (int, optional): The maximum number of simultaneous voices,
+            between 8 and 256. Defaults to 64.
+        target_lufs (Optional[float], optional): Normalize the render to this integrated
+            loudness in LUFS, measured as in EBU R128 / ITU-R BS.1770 on the output channels,
+            such as -23 for broadcast or -14 for streaming. The gain is limited so the true
+            peak stays at or below `true_peak`, so very dynamic renders may land below the
+            target. Defaults to None, which leaves the level as rendered.
+        true_peak (float, optional): The true-peak ceiling in dBTP for `target_lufs`.
+            Defaults to -1.0.
+        overload (Literal["clip", "soft", "limit"], optional): How samples beyond full scale
+            are handled. "clip" clips them when converting to integer samples and leaves
+            float output as rendered; "soft" saturates samples above -6 dBFS along a
+            tanh curve towards full scale; "limit" runs a look-ahead brickwall limiter that
+            turns the render down just before each peak so none passes `limiter_ceiling`.
+            Applied after `target_lufs`. Defaults to "clip".
+        limiter_ceiling (float, optional): The highest sample level the "limit" mode lets
+            through, in dBFS. Defaults to -1.0.
+        limiter_release (float, optional): How quickly the "limit" mode recovers after a
+            peak, as a time constant in seconds. Defaults to 0.05.
+        trim (Optional[Literal["start", "end", "both"]], optional): Which ends of the render
+            lose their silence, such as a silent bar before the first note. Trimming happens
+            before `target_lufs` and `overload`. Defaults to None, which trims nothing.
+        trim_threshold (float, optional): The level in dBFS below which frames count as
+            silent for `trim`. Count-in clicks above it are kept, so raise it to cut them.
+            Defaults to -60.0.
+        fade_in (float, optional): The length of a fade from silence at the start of the
+            render, after trimming, in seconds. Defaults to 0.0.
+        fade_out (float, optional): The length of a fade to silence at the end of the
+            render, in seconds. Defaults to 0.0.
+        fade_curve (Literal["linear", "exponential"], optional): The gain curve of both
+            fades. "exponential" changes evenly in decibels over 60 dB, which sounds more
+            even than "linear". Defaults to "linear".
+        dither (Literal["none", "tpdf", "shaped"], optional): The noise added when reducing
+            to integer samples. "tpdf" adds triangular noise of up to one step before
+            rounding, which turns the distortion of quiet passages, such as piano fades, into
+            a steady hiss; "shaped" also moves that noise towards high frequencies, where it
+            is least audible. Float output ignores it. Defaults to "none", which truncates.
+        dither_seed (Optional[int], optional): Seeds the dither noise, so equal seeds give
+            identical files, as golden-file tests need. Needs `dither` other than "none".
+            Defaults to None, which picks a new seed for every render.
+        report (bool, optional): Whether to also return an `OverloadReport` of how many
+            samples overload handling affected. Defaults to False.
 
     Returns:
-        bytes: The rendered audio as WAV file bytes.
+        Union[bytes, Tuple[bytes, OverloadReport]]: The rendered audio as WAV file bytes,
+            paired with its `OverloadReport` when `report` is True.
 
     Raises:
         ValueError: If the input bytes are invalid or cannot be processed.
@@ -29,12 +270,122 @@ def render_wave_from(soundfont_bytes: bytes, midi_bytes: bytes) -> bytes:
     """
     ...
 
+def render_flac_from(
+    soundfont_bytes: SoundFonts,
+    midi_bytes: bytes,
+    *,
+    stereo: bool = True,
+    downmix: Literal["average", "pan_law", "left", "right"] = "average",
+    sample_format: Literal["s16", "s24"] = "s16",
+    compression_level: int = 5,
+    tags: Optional[Dict[str, str]] = None,
+    sample_rate: int = 48000,
+    tail: Optional[Union[float, Literal["silent"]]] = None,
+    tail_threshold: float = -60.0,
+    max_tail: float = 10.0,
+    start: float = 0.0,
+    end: Optional[float] = None,
+    loop_count: int = 0,
+    loop_start: Optional[float] = None,
+    loop_end: Optional[float] = None,
+    loop_intro: bool = True,
+    enable_reverb_and_chorus: bool = True,
+    block_size: int = 64,
+    maximum_polyphony: int = 64,
+    target_lufs: Optional[float] = None,
+    true_peak: float = -1.0,
+    overload: Literal["clip", "soft", "limit"] = "clip",
+    limiter_ceiling: float = -1.0,
+    limiter_release: float = 0.05,
+    trim: Optional[Literal["start", "end", "both"]] = None,
+    trim_threshold: float = -60.0,
+    fade_in: float = 0.0,
+    fade_out: float = 0.0,
+    fade_curve: Literal["linear", "exponential"] = "linear",
+    dither: Literal["none", "tpdf", "shaped"] = "none",
+    dither_seed: Optional[int] = None,
+    report: bool = False,
+) -> Union[bytes, Tuple[bytes, OverloadReport]]:
+    """
+    Render a MIDI file to FLAC format using the provided SoundFont.
+
+    The audio is the same as `render_wave_from` produces for the same sample format,
+    compressed losslessly.
+
+    Args:
+        soundfont_bytes (SoundFonts): The raw bytes of a SoundFont (.sf2 or .sf3) file, a
+            `SoundFont` loaded earlier, or a list of SoundFonts. See `render_wave_from`.
+        midi_bytes (bytes): The raw bytes of a MIDI (.mid) file.
+        stereo, downmix: See `render_wave_from`.
+        sample_format (Literal["s16", "s24"], optional): The sample encoding: 16 or 24-bit
+            signed integers. Defaults to "s16".
+        compression_level (int, optional): The compression level, from 0 (fastest) to 8
+            (smallest), following the reference encoder's presets. Defaults to 5.
+        tags (Optional[Dict[str, str]], optional): Vorbis comments to store, such as
+            {"TITLE": "...", "ARTIST": "..."}. Tag names must be printable ASCII without "=".
+            Defaults to None, which writes no tags.
+        sample_rate, tail, tail_threshold, max_tail, start, end, loop_count, loop_start,
+            loop_end, loop_intro, enable_reverb_and_chorus, block_size, maximum_polyphony,
+            target_lufs, true_peak, overload, limiter_ceiling, limiter_release, trim,
+            trim_threshold, fade_in, fade_out, fade_curve, dither, dither_seed, report:
+            See `render_wave_from`.
+
+    Returns:
+        Union[bytes, Tuple[bytes, OverloadReport]]: The rendered audio as FLAC file bytes,
+            paired with its `OverloadReport` when `report` is True.
+
+    Raises:
+        ValueError: If the input bytes are invalid or cannot be processed.
+        RuntimeError: If there's an error during the rendering process.
+
+    Example:
+        >>> from pathlib import Path
+        >>> soundfont_path = Path("path/to/soundfont.sf2")
+        >>> midi_path = Path("path/to/midi_file.mid")
+        >>> flac_data = render_flac_from(
+        ...     soundfont_path.read_bytes(),
+        ...     midi_path.read_bytes(),
+        ...     compression_level=8,
+        ...     tags={"TITLE": "Prelude"},
+        ... )
+        >>> with open("output.flac", "wb") as f:
+        ...     f.write(flac_data)
+    """
+    ...
+
 def render_opus_from(
-    soundfont_bytes: bytes,
+    soundfont_bytes: SoundFonts,
     midi_bytes: bytes,
     stereo: bool = True,
-    bitrate: Union[Literal["auto", "max"], str] = "auto"
-) -> bytes:
+    bitrate: Union[Literal["auto", "max"], str] = "auto",
+    *,
+    downmix: Literal["average", "pan_law", "left", "right"] = "average",
+    tags: Optional[Dict[str, str]] = None,
+    sample_rate: int = 48000,
+    tail: Optional[Union[float, Literal["silent"]]] = None,
+    tail_threshold: float = -60.0,
+    max_tail: float = 10.0,
+    start: float = 0.0,
+    end: Optional[float] = None,
+    loop_count: int = 0,
+    loop_start: Optional[float] = None,
+    loop_end: Optional[float] = None,
+    loop_intro: bool = True,
+    enable_reverb_and_chorus: bool = True,
+    block_size: int = 64,
+    maximum_polyphony: int = 64,
+    target_lufs: Optional[float] = None,
+    true_peak: float = -1.0,
+    overload: Literal["clip", "soft", "limit"] = "clip",
+    limiter_ceiling: float = -1.0,
+    limiter_release: float = 0.05,
+    trim: Optional[Literal["start", "end", "both"]] = None,
+    trim_threshold: float = -60.0,
+    fade_in: float = 0.0,
+    fade_out: float = 0.0,
+    fade_curve: Literal["linear", "exponential"] = "linear",
+    report: bool = False,
+) -> Union[bytes, Tuple[bytes, OverloadReport]]:
     """
     Render a MIDI file to Opus format using the provided SoundFont.
 
@@ -43,15 +394,34 @@ def render_opus_from(
     using a high-performance Rust backend for optimal speed and efficiency.
 
     Args:
-        soundfont_bytes (bytes): The raw bytes of a SoundFont (.sf2) file.
+        soundfont_bytes (SoundFonts): The raw bytes of a SoundFont (.sf2 or .sf3) file, a
+            `SoundFont` loaded earlier, or a list of SoundFonts. See `render_wave_from`.
         midi_bytes (bytes): The raw bytes of a MIDI (.mid) file.
         stereo (bool, optional): Whether to render in stereo. Defaults to True.
         bitrate (Union[Literal["auto", "max"], str], optional): The bitrate for Opus encoding.
             Can be "auto", "max", or a string representing bits per second (e.g., "128000" for 128 kbps).
             Defaults to "auto".
+        downmix (Literal["average", "pan_law", "left", "right"], optional): How a mono output
+            is mixed. See `render_wave_from`. Defaults to "average".
+        tags (Optional[Dict[str, str]], optional): Vorbis comments to store in the OpusTags
+            header, such as {"TITLE": "...", "ARTIST": "...", "ALBUM": "..."}. Tag names must be
+            printable ASCII without "=". TITLE and COPYRIGHT are filled from the MIDI file's
+            sequence name and copyright notice when present and not given here.
+            Defaults to None.
+        target_lufs (Optional[float], optional): See `render_wave_from`. For Opus the gain is
+            written to the header's output gain, which decoders apply, with an
+            R128_TRACK_GAIN tag relative to the -23 LUFS reference. Defaults to None.
+        sample_rate (int, optional): The synthesis sample rate in Hz. Rates that Opus supports
+            natively (8000, 12000, 16000, 24000 and 48000) are encoded as-is; any other rate
+            is resampled to 48000 before encoding. Defaults to 48000.
+        tail, tail_threshold, max_tail, start, end, loop_count, loop_start, loop_end,
+            loop_intro, enable_reverb_and_chorus, block_size, maximum_polyphony, true_peak,
+            overload, limiter_ceiling, limiter_release, trim, trim_threshold, fade_in,
+            fade_out, fade_curve, report: See `render_wave_from`.
 
     Returns:
-        bytes: The rendered audio as Opus file bytes.
+        Union[bytes, Tuple[bytes, OverloadReport]]: The rendered audio as Opus file bytes,
+            paired with its `OverloadReport` when `report` is True.
 
     Raises:
         ValueError: If the input bytes are invalid or cannot be processed.
@@ -72,6 +442,379 @@ def render_opus_from(
     """
     ...
 
+def render_vorbis_from(
+    soundfont_bytes: SoundFonts,
+    midi_bytes: bytes,
+    *,
+    stereo: bool = True,
+    quality: float = 5.0,
+    downmix: Literal["average", "pan_law", "left", "right"] = "average",
+    sample_rate: int = 48000,
+    tail: Optional[Union[float, Literal["silent"]]] = None,
+    tail_threshold: float = -60.0,
+    max_tail: float = 10.0,
+    start: float = 0.0,
+    end: Optional[float] = None,
+    loop_count: int = 0,
+    loop_start: Optional[float] = None,
+    loop_end: Optional[float] = None,
+    loop_intro: bool = True,
+    enable_reverb_and_chorus: bool = True,
+    block_size: int = 64,
+    maximum_polyphony: int = 64,
+    target_lufs: Optional[float] = None,
+    true_peak: float = -1.0,
+    overload: Literal["clip", "soft", "limit"] = "clip",
+    limiter_ceiling: float = -1.0,
+    limiter_release: float = 0.05,
+    trim: Optional[Literal["start", "end", "both"]] = None,
+    trim_threshold: float = -60.0,
+    fade_in: float = 0.0,
+    fade_out: float = 0.0,
+    fade_curve: Literal["linear", "exponential"] = "linear",
+    report: bool = False,
+) -> Union[bytes, Tuple[bytes, OverloadReport]]:
+    """
+    Render a MIDI file to Ogg Vorbis format using the provided SoundFont.
+
+    Vorbis plays in players and engines that predate Opus support.
+
+    Args:
+        soundfont_bytes (SoundFonts): The raw bytes of a SoundFont (.sf2 or .sf3) file, a
+            `SoundFont` loaded earlier, or a list of SoundFonts. See `render_wave_from`.
+        midi_bytes (bytes): The raw bytes of a MIDI (.mid) file.
+        stereo, downmix: See `render_wave_from`.
+        quality (float, optional): The variable bitrate quality on the `oggenc` scale, from
+            -1 (smallest) to 10 (best). Fractional values are allowed. Defaults to 5.0.
+        sample_rate (int, optional): The synthesis and output sample rate in Hz,
+            between 16000 and 192000. Defaults to 48000.
+        tail, tail_threshold, max_tail, start, end, loop_count, loop_start, loop_end,
+            loop_intro, enable_reverb_and_chorus, block_size, maximum_polyphony, target_lufs,
+            true_peak, overload, limiter_ceiling, limiter_release, trim, trim_threshold,
+            fade_in, fade_out, fade_curve, report: See `render_wave_from`.
+
+    Returns:
+        Union[bytes, Tuple[bytes, OverloadReport]]: The rendered audio as Ogg Vorbis file bytes,
+            paired with its `OverloadReport` when `report` is True.
+
+    Raises:
+        ValueError: If the input bytes are invalid or cannot be processed.
+        RuntimeError: If there's an error during the rendering process.
+
+    Example:
+        >>> from pathlib import Path
+        >>> soundfont_path = Path("path/to/soundfont.sf2")
+        >>> midi_path = Path("path/to/midi_file.mid")
+        >>> ogg_data = render_vorbis_from(
+        ...     soundfont_path.read_bytes(),
+        ...     midi_path.read_bytes(),
+        ...     quality=6.0,
+        ... )
+        >>> with open("output.ogg", "wb") as f:
+        ...     f.write(ogg_data)
+    """
+    ...
+
+def render_mp3_from(
+    soundfont_bytes: SoundFonts,
+    midi_bytes: bytes,
+    *,
+    stereo: bool = True,
+    bitrate: str = "192",
+    downmix: Literal["average", "pan_law", "left", "right"] = "average",
+    tags: Optional[Dict[str, str]] = None,
+    sample_rate: int = 48000,
+    tail: Optional[Union[float, Literal["silent"]]] = None,
+    tail_threshold: float = -60.0,
+    max_tail: float = 10.0,
+    start: float = 0.0,
+    end: Optional[float] = None,
+    loop_count: int = 0,
+    loop_start: Optional[float] = None,
+    loop_end: Optional[float] = None,
+    loop_intro: bool = True,
+    enable_reverb_and_chorus: bool = True,
+    block_size: int = 64,
+    maximum_polyphony: int = 64,
+    target_lufs: Optional[float] = None,
+    true_peak: float = -1.0,
+    overload: Literal["clip", "soft", "limit"] = "clip",
+    limiter_ceiling: float = -1.0,
+    limiter_release: float = 0.05,
+    trim: Optional[Literal["start", "end", "both"]] = None,
+    trim_threshold: float = -60.0,
+    fade_in: float = 0.0,
+    fade_out: float = 0.0,
+    fade_curve: Literal["linear", "exponential"] = "linear",
+    report: bool = False,
+) -> Union[bytes, Tuple[bytes, OverloadReport]]:
+    """
+    Render a MIDI file to MP3 format using the provided SoundFont.
+
+    The encoder is fed the same 16-bit samples as `render_wave_from` writes by default.
+    A LAME tag records the exact length, so gapless players drop the encoder padding.
+
+    Args:
+        soundfont_bytes (SoundFonts): The raw bytes of a SoundFont (.sf2 or .sf3) file, a
+            `SoundFont` loaded earlier, or a list of SoundFonts. See `render_wave_from`.
+        midi_bytes (bytes): The raw bytes of a MIDI (.mid) file.
+        stereo, downmix: See `render_wave_from`.
+        bitrate (str, optional): A constant bitrate in kbps, one of "8", "16", "24", "32",
+            "40", "48", "64", "80", "96", "112", "128", "160", "192", "224", "256" or "320",
+            or a variable bitrate level from "V0" (best) to "V9" (smallest), as in LAME's
+            `-V` option. Defaults to "192".
+        tags (Optional[Dict[str, str]], optional): ID3v2 tags to store. Names are
+            "TITLE", "ARTIST", "ALBUM", "YEAR" and "COMMENT", in any case, and values
+            Latin-1 text of up to 250 characters. Defaults to None, which writes no tags.
+        sample_rate (int, optional): The synthesis sample rate in Hz. Rates MP3 does not
+            support are resampled by the encoder. Defaults to 48000.
+        tail, tail_threshold, max_tail, start, end, loop_count, loop_start, loop_end,
+            loop_intro, enable_reverb_and_chorus, block_size, maximum_polyphony, target_lufs,
+            true_peak, overload, limiter_ceiling, limiter_release, trim, trim_threshold,
+            fade_in, fade_out, fade_curve, report: See `render_wave_from`.
+
+    Returns:
+        Union[bytes, Tuple[bytes, OverloadReport]]: The rendered audio as MP3 file bytes,
+            paired with its `OverloadReport` when `report` is True.
+
+    Raises:
+        ValueError: If the input bytes are invalid or cannot be processed.
+        RuntimeError: If there's an error during the rendering process.
+
+    Example:
+        >>> from pathlib import Path
+        >>> soundfont_path = Path("path/to/soundfont.sf2")
+        >>> midi_path = Path("path/to/midi_file.mid")
+        >>> mp3_data = render_mp3_from(
+        ...     soundfont_path.read_bytes(),
+        ...     midi_path.read_bytes(),
+        ...     bitrate="V2",
+        ...     tags={"TITLE": "Prelude", "ARTIST": "..."},
+        ... )
+        >>> with open("output.mp3", "wb") as f:
+        ...     f.write(mp3_data)
+    """
+    ...
+
+def render_pcm(
+    soundfont_bytes: SoundFonts,
+    midi_bytes: bytes,
+    *,
+    stereo: bool = True,
+    downmix: Literal["average", "pan_law", "left", "right"] = "average",
+    layout: Literal["interleaved", "planar"] = "interleaved",
+    sample_rate: int = 48000,
+    tail: Optional[Union[float, Literal["silent"]]] = None,
+    tail_threshold: float = -60.0,
+    max_tail: float = 10.0,
+    start: float = 0.0,
+    end: Optional[float] = None,
+    loop_count: int = 0,
+    loop_start: Optional[float] = None,
+    loop_end: Optional[float] = None,
+    loop_intro: bool = True,
+    enable_reverb_and_chorus: bool = True,
+    block_size: int = 64,
+    maximum_polyphony: int = 64,
+    target_lufs: Optional[float] = None,
+    true_peak: float = -1.0,
+    overload: Literal["clip", "soft", "limit"] = "clip",
+    limiter_ceiling: float = -1.0,
+    limiter_release: float = 0.05,
+    trim: Optional[Literal["start", "end", "both"]] = None,
+    trim_threshold: float = -60.0,
+    fade_in: float = 0.0,
+    fade_out: float = 0.0,
+    fade_curve: Literal["linear", "exponential"] = "linear",
+    report: bool = False,
+) -> Union[numpy.ndarray, Tuple[numpy.ndarray, OverloadReport]]:
+    """
+    Render a MIDI file to a numpy array of float32 samples using the provided SoundFont.
+
+    The samples are the synthesizer output as rendered: not clipped unless `overload` asks
+    for it, not quantised, and not wrapped in a container. The array owns the rendered buffer, so no copy is made.
+    Requires numpy, available with `pip install midirenderer[numpy]`.
+
+    Args:
+        soundfont_bytes (SoundFonts): The raw bytes of a SoundFont (.sf2 or .sf3) file, a
+            `SoundFont` loaded earlier, or a list of SoundFonts. See `render_wave_from`.
+        midi_bytes (bytes): The raw bytes of a MIDI (.mid) file.
+        stereo, downmix: See `render_wave_from`.
+        layout (Literal["interleaved", "planar"], optional): The array shape. "interleaved"
+            returns shape (frames, channels) and "planar" returns (channels, frames), both
+            C-contiguous. Defaults to "interleaved".
+        sample_rate, tail, tail_threshold, max_tail, start, end, loop_count, loop_start,
+            loop_end, loop_intro, enable_reverb_and_chorus, block_size, maximum_polyphony,
+            target_lufs, true_peak, overload, limiter_ceiling, limiter_release, trim,
+            trim_threshold, fade_in, fade_out, fade_curve, report: See `render_wave_from`.
+
+    Returns:
+        Union[numpy.ndarray, Tuple[numpy.ndarray, OverloadReport]]: The rendered float32
+            samples, with one column or row per channel, paired with their `OverloadReport`
+            when `report` is True.
+
+    Raises:
+        ImportError: If numpy is not installed.
+        ValueError: If the input bytes are invalid or cannot be processed.
+        RuntimeError: If there's an error during the rendering process.
+
+    Example:
+        >>> from pathlib import Path
+        >>> soundfont_path = Path("path/to/soundfont.sf2")
+        >>> midi_path = Path("path/to/midi_file.mid")
+        >>> samples = render_pcm(
+        ...     soundfont_path.read_bytes(),
+        ...     midi_path.read_bytes(),
+        ...     layout="planar",
+        ... )
+        >>> left, right = samples
+    """
+    ...
+
+def render_stems(
+    soundfont_bytes: SoundFonts,
+    midi_bytes: bytes,
+    *,
+    by: Literal["channel", "track"] = "channel",
+    format: Literal["wav", "opus"] = "wav",
+    stereo: bool = True,
+    bitrate: Union[Literal["auto", "max"], str] = "auto",
+    downmix: Literal["average", "pan_law", "left", "right"] = "average",
+    sample_format: Literal["s16", "s24", "s32", "f32"] = "s16",
+    sample_rate: int = 48000,
+    tail: Optional[Union[float, Literal["silent"]]] = None,
+    tail_threshold: float = -60.0,
+    max_tail: float = 10.0,
+    start: float = 0.0,
+    end: Optional[float] = None,
+    loop_count: int = 0,
+    loop_start: Optional[float] = None,
+    loop_end: Optional[float] = None,
+    loop_intro: bool = True,
+    enable_reverb_and_chorus: bool = True,
+    block_size: int = 64,
+    maximum_polyphony: int = 64,
+    dither: Literal["none", "tpdf", "shaped"] = "none",
+    dither_seed: Optional[int] = None,
+) -> Dict[int, bytes]:
+    """
+    Render each MIDI channel or track to its own audio file.
+
+    The MIDI and SoundFont are parsed once, and every channel (or track) that plays
+    at least one note is rendered separately. Controllers and program changes from
+    the whole file still apply, so each stem sounds exactly as it does in the full mix,
+    and all stems share the same timeline.
+
+    Args:
+        soundfont_bytes (SoundFonts): The raw bytes of a SoundFont (.sf2 or .sf3) file, a
+            `SoundFont` loaded earlier, or a list of SoundFonts. See `render_wave_from`.
+        midi_bytes (bytes): The raw bytes of a MIDI (.mid) file.
+        by (Literal["channel", "track"], optional): Whether to split by MIDI channel
+            or by track. Defaults to "channel".
+        format (Literal["wav", "opus"], optional): The output format of each stem.
+            Defaults to "wav".
+        stereo, downmix: See `render_wave_from`.
+        sample_format: See `render_wave_from`. Only used for WAV stems.
+        bitrate: See `render_opus_from`. Only used for Opus stems.
+        sample_rate, tail, tail_threshold, max_tail, start, end, enable_reverb_and_chorus,
+            block_size, maximum_polyphony: See `render_wave_from`.
+        loop_count, loop_start, loop_end, loop_intro: See `render_wave_from`. Only WAV stems
+            carry loop points.
+        dither, dither_seed: See `render_wave_from`. Only used for WAV stems, each of which
+            derives a seed of its own, so the noise does not add up when they are mixed.
+
+    Returns:
+        Dict[int, bytes]: The rendered stems, keyed by zero-based channel or track index.
+
+    Raises:
+        ValueError: If the input bytes, `by`, `format` or `bitrate` are invalid.
+        RuntimeError: If there's an error during the rendering process.
+
+    Example:
+        >>> from pathlib import Path
+        >>> soundfont = SoundFont(Path("path/to/soundfont.sf2").read_bytes())
+        >>> stems = render_stems(soundfont, Path("path/to/midi_file.mid").read_bytes())
+        >>> for channel, wav_data in stems.items():
+        ...     Path(f"channel_{channel}.wav").write_bytes(wav_data)
+    """
+    ...
+
+class RenderIterator(Iterator[bytes]):
+    """An iterator over the encoded chunks of a render, returned by `iter_render`."""
+    def __iter__(self) -> "RenderIterator": ...
+    def __next__(self) -> bytes: ...
+
+def iter_render(
+    soundfont_bytes: SoundFonts,
+    midi_bytes: bytes,
+    *,
+    format: Literal["pcm", "wav", "opus"] = "pcm",
+    chunk_size: int = 16384,
+    stereo: bool = True,
+    bitrate: Union[Literal["auto", "max"], str] = "auto",
+    downmix: Literal["average", "pan_law", "left", "right"] = "average",
+    sample_format: Literal["s16", "s24", "s32", "f32"] = "s16",
+    sample_rate: int = 48000,
+    tail: Optional[Union[float, Literal["silent"]]] = None,
+    tail_threshold: float = -60.0,
+    max_tail: float = 10.0,
+    start: float = 0.0,
+    end: Optional[float] = None,
+    loop_count: int = 0,
+    loop_start: Optional[float] = None,
+    loop_end: Optional[float] = None,
+    loop_intro: bool = True,
+    enable_reverb_and_chorus: bool = True,
+    block_size: int = 64,
+    maximum_polyphony: int = 64,
+    dither: Literal["none", "tpdf", "shaped"] = "none",
+    dither_seed: Optional[int] = None,
+) -> RenderIterator:
+    """
+    Render a MIDI file incrementally, yielding encoded chunks as they are produced.
+
+    Only one chunk of audio is held in memory at a time, so long files can be streamed
+    straight into an HTTP response or a file. The GIL is released while each chunk renders.
+
+    Args:
+        soundfont_bytes (SoundFonts): The raw bytes of a SoundFont (.sf2 or .sf3) file, a
+            `SoundFont` loaded earlier, or a list of SoundFonts. See `render_wave_from`.
+        midi_bytes (bytes): The raw bytes of a MIDI (.mid) file.
+        format (Literal["pcm", "wav", "opus"], optional): The encoding of the chunks.
+            "pcm" yields raw interleaved little-endian samples in `sample_format`. "wav" yields
+            the same samples after a WAV header; when `tail="silent"` the final length is
+            unknown up front, so the header carries the maximum sizes used for streaming.
+            "opus" yields Ogg Opus pages. Defaults to "pcm".
+        chunk_size (int, optional): The number of frames rendered per step. Defaults to 16384.
+        stereo, downmix: See `render_wave_from`.
+        sample_format: See `render_wave_from`. Only used for "pcm" and "wav".
+        bitrate: See `render_opus_from`. Only used for "opus".
+        sample_rate, tail, tail_threshold, max_tail, start, end, enable_reverb_and_chorus,
+            block_size, maximum_polyphony: See `render_wave_from`.
+        loop_count, loop_start, loop_end, loop_intro: See `render_wave_from`. "wav" streams
+            end with a `smpl` chunk, which the header must count, so they need a fixed
+            `tail` rather than "silent". "opus" streams carry loop comments, and "pcm"
+            streams no loop points.
+        dither, dither_seed: See `render_wave_from`. Only used for "pcm" and "wav".
+
+    Returns:
+        RenderIterator: An iterator of `bytes` chunks. Joined together, they form the
+            complete file.
+
+    Raises:
+        ValueError: If the input bytes, `format` or `bitrate` are invalid, or a looped
+            "wav" stream has `tail="silent"`.
+        RuntimeError: If there's an error during the rendering process.
+
+    Example:
+        >>> from pathlib import Path
+        >>> soundfont = SoundFont(Path("path/to/soundfont.sf2").read_bytes())
+        >>> with open("output.opus", "wb") as f:
+        ...     for chunk in iter_render(soundfont, Path("song.mid").read_bytes(), format="opus"):
+        ...         f.write(chunk)
+    """
+    ...
+
 # Version of the midirenderer package
 __version__: str
 
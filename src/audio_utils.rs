@@ -1,15 +1,65 @@
+use flacenc::bitsink::ByteSink;
+use flacenc::component::{BitRepr, MetadataBlockData, Stream, StreamInfo};
+use flacenc::constant::MIN_BLOCK_SIZE;
+use flacenc::error::Verify;
+use flacenc::source::{Fill, FrameBuf};
+use md5::{Digest, Md5};
+use mp3lame_encoder::{
+    Bitrate as LameBitrate, FlushGap, Id3Tag, InterleavedPcm, MonoPcm, Quality, VbrMode,
+};
 use ogg::{writing::PacketWriteEndInfo, PacketWriter};
 use opus::{Application, Bitrate, Channels, Encoder};
-use rustysynth::{MidiFile, MidiFileSequencer, SoundFont, Synthesizer, SynthesizerSettings};
+use rustysynth::{SoundFont, SynthesizerSettings};
 use std::io::{Cursor, Write};
+use std::num::{NonZeroU32, NonZeroU8};
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 use thiserror::Error;
+use vorbis_rs::{VorbisBitrateManagementStrategy, VorbisEncoderBuilder};
 
-const SAMPLE_RATE: u16 = 48000;
-const FRAME_SIZE: usize = 960; // 20ms at 48kHz
-// Maximum size of an Opus packet puts a ceiling on bitrate?
-// This is calculated for max of 256 kbit/s
-const MAX_PACKET_SIZE: usize = 640;
+use crate::dither::{self, Ditherer};
+use crate::layers::{LayeredSynthesizer, SoundFontStack};
+use crate::loudness;
+use crate::midi::{MidiSong, NoteFilter, Sequencer};
+use crate::overload;
+use crate::resampler::Resampler;
+use crate::sf3;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+// Sample rates the synthesizer accepts.
+const SAMPLE_RATES: RangeInclusive<u32> = 16000..=192000;
+// Block sizes and voice counts the synthesizer accepts.
+const BLOCK_SIZES: RangeInclusive<usize> = 8..=1024;
+const POLYPHONY: RangeInclusive<usize> = 8..=256;
+// Sample rates the Opus encoder accepts natively.
+const OPUS_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+// Ogg Opus granule positions always count 48kHz samples, whatever the input rate.
+const OPUS_GRANULE_RATE: u32 = 48000;
+// Loudness that R128_TRACK_GAIN normalizes to, in LUFS.
+const R128_REFERENCE: f64 = -23.0;
+// Samples per channel in each Opus frame: 20ms at 48kHz.
+const FRAME_SIZE: usize = 960;
+
+// How far ahead of a peak the limiter starts turning the render down.
+const LIMITER_LOOKAHEAD_SECONDS: f64 = 0.005;
+// Range of an exponential fade, as a ratio of its end levels (60 dB).
+const EXPONENTIAL_FADE_RANGE: f64 = 1000.0;
+
+// MIDI note of the `smpl` chunk, which a looped render plays at its recorded pitch.
+const SMPL_UNITY_NOTE: u32 = 60;
+
+// Longest tail a render may ask for, fixed or "silent", in seconds.
+pub const MAX_TAIL_SECONDS: f64 = 60.0;
+
+// Most times a looped render may play its loop.
+pub const MAX_LOOP_REPETITIONS: usize = 100;
+
+// Frames rendered between checks for the end of a "silent" tail.
+const CHUNK_SIZE: usize = 1024;
+
+// Output buffer for one encoded frame; libopus recommends 4000 bytes,
+// comfortably above the 1275 bytes a single 20ms frame can reach.
+const MAX_PACKET_SIZE: usize = 4000;
 
 #[derive(Debug, Error)]
 pub enum AudioError {
@@ -19,319 +69,3914 @@ pub enum AudioError {
     Opus(#[from] opus::Error),
     #[error("SoundFont error: {0}")]
     SoundFont(String),
+    #[error("Synthesizer error: {0}")]
+    Synthesizer(#[from] rustysynth::SynthesizerError),
     #[error("MIDI error: {0}")]
     Midi(String),
+    #[error("WAV error: {0}")]
+    Wav(String),
     #[error("WAV parsing error: {0}")]
     WavParsing(String),
+    #[error("FLAC error: {0}")]
+    Flac(String),
+    #[error("Vorbis error: {0}")]
+    Vorbis(#[from] vorbis_rs::VorbisError),
+    #[error("MP3 error: {0}")]
+    Mp3(String),
+    #[error("Invalid option: {0}")]
+    Options(String),
 }
 
-#[derive(Debug)]
+impl AudioError {
+    /// Whether the error comes from the SoundFont, MIDI file or options given, rather than
+    /// from rendering or encoding them.
+    pub fn is_invalid_input(&self) -> bool {
+        matches!(self, Self::SoundFont(_) | Self::Midi(_) | Self::Options(_))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum OpusBitrate {
     Auto,
     Max,
     Bits(i32),
 }
 
-#[derive(Debug)]
-struct WavHeader {
-    channels: u16,
-    sample_rate: u32,
-    bits_per_sample: u16,
-    data_start: usize,
+/// How a stereo render is folded into a single channel.
+#[derive(Debug, Clone, Copy)]
+pub enum Downmix {
+    /// Half of each channel, so a centred sound keeps its level.
+    Average,
+    /// Each channel at -3 dB, matching a constant-power pan law.
+    PanLaw,
+    Left,
+    Right,
 }
 
-fn parse_wav_header(data: &[u8]) -> Result<WavHeader, AudioError> {
-    if data.len() < 44 {
-        return Err(AudioError::WavParsing("WAV data too short".to_string()));
+impl Downmix {
+    fn mix(self, left: f32, right: f32) -> f32 {
+        match self {
+            Self::Average => (left + right) * 0.5,
+            Self::PanLaw => (left + right) * std::f32::consts::FRAC_1_SQRT_2,
+            Self::Left => left,
+            Self::Right => right,
+        }
     }
+}
 
-    if &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
-        return Err(AudioError::WavParsing("Invalid WAV file".to_string()));
+/// The channel layout of the encoded output.
+#[derive(Debug, Clone, Copy)]
+pub enum OutputChannels {
+    Stereo,
+    Mono(Downmix),
+}
+
+impl OutputChannels {
+    pub fn count(self) -> usize {
+        match self {
+            Self::Stereo => 2,
+            Self::Mono(_) => 1,
+        }
     }
+}
 
-    let channels = u16::from_le_bytes([data[22], data[23]]);
-    let sample_rate = u32::from_le_bytes([data[24], data[25], data[26], data[27]]);
-    let bits_per_sample = u16::from_le_bytes([data[34], data[35]]);
+/// The sample encoding of WAV and raw PCM output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    S16,
+    S24,
+    S32,
+    /// 32-bit float, which keeps peaks above full scale instead of clipping them.
+    F32,
+}
 
-    let mut data_start = 12;
-    while data_start + 8 < data.len() {
-        let chunk_type = &data[data_start..data_start + 4];
-        let chunk_size = u32::from_le_bytes([
-            data[data_start + 4],
-            data[data_start + 5],
-            data[data_start + 6],
-            data[data_start + 7],
-        ]) as usize;
+impl SampleFormat {
+    fn bytes_per_sample(self) -> usize {
+        match self {
+            Self::S16 => 2,
+            Self::S24 => 3,
+            Self::S32 | Self::F32 => 4,
+        }
+    }
 
-        if chunk_type == b"data" {
-            data_start += 8;
-            break;
+    fn write_sample(self, output: &mut Vec<u8>, sample: f32, ditherer: &mut Option<Ditherer>) {
+        match self {
+            Self::S16 => {
+                let sample = quantize(sample, 16, ditherer) as i16;
+                output.extend_from_slice(&sample.to_le_bytes())
+            }
+            Self::S24 => {
+                output.extend_from_slice(&quantize(sample, 24, ditherer).to_le_bytes()[..3])
+            }
+            Self::S32 => output.extend_from_slice(&quantize(sample, 32, ditherer).to_le_bytes()),
+            Self::F32 => output.extend_from_slice(&sample.to_le_bytes()),
         }
+    }
+}
 
-        data_start = data_start
-            .checked_add(8 + chunk_size)
-            .ok_or_else(|| AudioError::WavParsing("Invalid chunk size".to_string()))?;
+/// Noise added when samples are reduced to an integer format.
+#[derive(Debug, Clone, Copy)]
+pub enum Dither {
+    /// Truncate towards zero, as without dithering.
+    None,
+    /// Triangular noise of up to one step, so the quantization error no longer follows
+    /// the signal. Equal seeds give equal output.
+    Tpdf { seed: u64 },
+    /// TPDF noise shaped towards high frequencies, where it is least audible.
+    Shaped { seed: u64 },
+}
+
+impl Dither {
+    fn ditherer(self, channels: OutputChannels) -> Option<Ditherer> {
+        match self {
+            Self::None => None,
+            Self::Tpdf { seed } => Some(Ditherer::new(false, seed, channels.count())),
+            Self::Shaped { seed } => Some(Ditherer::new(true, seed, channels.count())),
+        }
     }
 
-    if data_start >= data.len() {
-        return Err(AudioError::WavParsing("No data chunk found".to_string()));
+    /// The same dither with a seed of its own for the stem `key`, so stems mixed back
+    /// together do not add up the same noise.
+    fn for_stem(self, key: usize) -> Self {
+        match self {
+            Self::None => Self::None,
+            Self::Tpdf { seed } => Self::Tpdf {
+                seed: dither::derive_seed(seed, key as u64),
+            },
+            Self::Shaped { seed } => Self::Shaped {
+                seed: dither::derive_seed(seed, key as u64),
+            },
+        }
     }
+}
 
-    Ok(WavHeader {
-        channels,
-        sample_rate,
-        bits_per_sample,
-        data_start,
-    })
+/// How long to keep rendering after the last MIDI event.
+#[derive(Debug, Clone, Copy)]
+pub enum Tail {
+    None,
+    Seconds(f64),
+    /// Render until a chunk peaks below `threshold` (linear), for at most `max_seconds`.
+    UntilSilent {
+        threshold: f32,
+        max_seconds: f64,
+    },
 }
 
-pub fn render_midi_to_wav(
-    soundfont_bytes: &[u8],
-    midi_bytes: &[u8],
-) -> Result<Vec<u8>, AudioError> {
-    let mut sf2 = Cursor::new(soundfont_bytes);
-    let sound_font =
-        Arc::new(SoundFont::new(&mut sf2).map_err(|e| AudioError::SoundFont(e.to_string()))?);
+#[derive(Debug)]
+pub struct RenderOptions {
+    pub sample_rate: u32,
+    pub tail: Tail,
+    // Section of the song to render, in seconds; `end: None` renders to the last event.
+    pub start: f64,
+    pub end: Option<f64>,
+    // Synthesizer overrides; `None` keeps the rustysynth default.
+    pub enable_reverb_and_chorus: Option<bool>,
+    pub block_size: Option<usize>,
+    pub maximum_polyphony: Option<usize>,
+    // Gain applied to whole-song renders to reach a loudness target.
+    pub loudness: Option<Loudness>,
+    // Handling of whole-song renders that peak beyond full scale.
+    pub overload: Overload,
+    // Noise added when reducing to integer samples; float and lossy output ignore it.
+    pub dither: Dither,
+    // Edits of whole-song renders, made before any gain: silence trimming, then fades.
+    pub trim: Option<Trim>,
+    pub fades: Option<Fades>,
+    // Repeats a section of the song in place of `start` and `end`.
+    pub looping: Option<Looping>,
+}
 
-    let mut mid = Cursor::new(midi_bytes);
-    let midi_file = Arc::new(MidiFile::new(&mut mid).map_err(|e| AudioError::Midi(e.to_string()))?);
+/// A section of the song played several times over, as game music loops.
+#[derive(Debug, Clone, Copy)]
+pub struct Looping {
+    /// Loop start in seconds; `None` takes the song's "loopStart" marker or CC111.
+    pub start: Option<f64>,
+    /// Loop end in seconds; `None` takes the song's "loopEnd" marker, else its end.
+    pub end: Option<f64>,
+    /// How many times the loop plays in all.
+    pub repetitions: usize,
+    /// Whether the render begins with the song before the loop.
+    pub intro: bool,
+}
 
-    let settings = SynthesizerSettings::new(SAMPLE_RATE as i32);
-    let synthesizer = Synthesizer::new(&sound_font, &settings)
-        .map_err(|e| AudioError::SoundFont(e.to_string()))?;
-    let mut sequencer = MidiFileSequencer::new(synthesizer);
+impl Looping {
+    /// Resolves the loop's start and end in the song, in seconds.
+    fn section(&self, song: &MidiSong) -> Result<(f64, f64), AudioError> {
+        let start = self.start.or(song.loop_start()).ok_or_else(|| {
+            AudioError::Midi(
+                "No loop start given and no loopStart marker or CC111 found".to_string(),
+            )
+        })?;
+        let end = self
+            .end
+            .or(song.loop_end())
+            .unwrap_or(song.length())
+            .min(song.length());
+        if end <= start {
+            return Err(AudioError::Midi(format!(
+                "Loop end ({end:.3}s) must be after its start ({start:.3}s)"
+            )));
+        }
+        Ok((start, end))
+    }
+}
 
-    sequencer.play(&midi_file, false);
+/// Where the last repetition of a looped render lies, in frames.
+///
+/// It is marked rather than the first, as it begins with the previous repetition still
+/// ringing, which is what a player hears when it jumps back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LoopPoints {
+    start: usize,
+    length: usize,
+}
 
-    let sample_count = (SAMPLE_RATE as f64 * midi_file.get_length()) as usize;
-    let mut left: Vec<f32> = Vec::with_capacity(sample_count);
-    let mut right: Vec<f32> = Vec::with_capacity(sample_count);
+impl LoopPoints {
+    fn locate(song: &MidiSong, options: &RenderOptions) -> Result<Option<LoopPoints>, AudioError> {
+        let Some(looping) = options.looping else {
+            return Ok(None);
+        };
+        let (start, end) = looping.section(song)?;
+        let rate = options.sample_rate as f64;
+        let intro = if looping.intro { start } else { 0.0 };
+        let repetitions = looping.repetitions.max(1) as f64;
+        let first = ((intro + (repetitions - 1.0) * (end - start)) * rate) as usize;
+        let last = ((intro + repetitions * (end - start)) * rate) as usize;
+        Ok(Some(LoopPoints {
+            start: first,
+            length: last - first,
+        }))
+    }
 
-    // Render audio in chunks to avoid excessive memory usage
-    const CHUNK_SIZE: usize = 1024;
-    let mut temp_left = vec![0.0; CHUNK_SIZE];
-    let mut temp_right = vec![0.0; CHUNK_SIZE];
+    /// LOOPSTART and LOOPLENGTH comments, in frames at `rate` from a render at `sample_rate`.
+    fn tags(self, sample_rate: u32, rate: u32) -> [(String, String); 2] {
+        let scale = |frames: usize| frames as u64 * rate as u64 / sample_rate as u64;
+        [
+            ("LOOPSTART".to_string(), scale(self.start).to_string()),
+            ("LOOPLENGTH".to_string(), scale(self.length).to_string()),
+        ]
+    }
+}
+
+/// Which ends of a render lose their silence.
+#[derive(Debug, Clone, Copy)]
+pub struct Trim {
+    pub start: bool,
+    pub end: bool,
+    /// The level, linear, below which frames count as silent.
+    pub threshold: f32,
+}
+
+/// Fades at the ends of a render, in seconds; a fade longer than the render is cut short.
+#[derive(Debug, Clone, Copy)]
+pub struct Fades {
+    pub fade_in: f64,
+    pub fade_out: f64,
+    pub curve: FadeCurve,
+}
+
+/// The shape of the gain over a fade.
+#[derive(Debug, Clone, Copy)]
+pub enum FadeCurve {
+    Linear,
+    /// Rising evenly in decibels over EXPONENTIAL_FADE_RANGE, so the fade sounds even.
+    Exponential,
+}
+
+/// A loudness normalization target, as in EBU R128.
+#[derive(Debug, Clone, Copy)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS, such as -23 for broadcast or -14 for streaming.
+    pub target: f64,
+    /// The highest true peak the gain may raise the render to, in dBTP.
+    pub true_peak: f64,
+}
 
-    while left.len() < sample_count {
-        let remaining = sample_count - left.len();
-        let current_chunk_size = std::cmp::min(CHUNK_SIZE, remaining);
-        temp_left.resize(current_chunk_size, 0.0);
-        temp_right.resize(current_chunk_size, 0.0);
+/// How samples beyond full scale are handled before encoding.
+#[derive(Debug, Clone, Copy)]
+pub enum Overload {
+    /// Clip when converting to integer samples, leaving float output as rendered.
+    Clip,
+    /// Saturate loud samples smoothly towards full scale.
+    Soft,
+    /// Turn the render down just ahead of its peaks so none passes `ceiling`.
+    Limit {
+        /// The highest sample level, linear.
+        ceiling: f32,
+        /// Time constant of the gain recovering after a peak, in seconds.
+        release: f64,
+    },
+}
 
-        sequencer.render(&mut temp_left, &mut temp_right);
+/// What overload handling did to a render, counting samples of the output channels.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OverloadReport {
+    /// Samples clipped, saturated or turned down. With `Overload::Clip` these are the
+    /// samples beyond full scale, which integer formats clip and float output keeps.
+    pub affected_samples: usize,
+    pub total_samples: usize,
+    /// The highest sample level before handling, linear.
+    pub peak: f32,
+}
 
-        left.extend_from_slice(&temp_left);
-        right.extend_from_slice(&temp_right);
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            tail: Tail::None,
+            start: 0.0,
+            end: None,
+            enable_reverb_and_chorus: None,
+            block_size: None,
+            maximum_polyphony: None,
+            loudness: None,
+            overload: Overload::Clip,
+            dither: Dither::None,
+            trim: None,
+            fades: None,
+            looping: None,
+        }
     }
+}
 
-    let mut wav_data = Vec::new();
+impl RenderOptions {
+    /// Checks the times and lengths that would otherwise empty the render or overflow its
+    /// length.
+    pub fn validate(&self) -> Result<(), AudioError> {
+        if !SAMPLE_RATES.contains(&self.sample_rate) {
+            return Err(AudioError::Options(format!(
+                "sample rate must be between {} and {} Hz, got {}",
+                SAMPLE_RATES.start(),
+                SAMPLE_RATES.end(),
+                self.sample_rate
+            )));
+        }
+        let synthesizer_settings = [
+            ("block size", self.block_size, BLOCK_SIZES),
+            ("maximum polyphony", self.maximum_polyphony, POLYPHONY),
+        ];
+        for (name, value, range) in synthesizer_settings {
+            if let Some(value) = value.filter(|value| !range.contains(value)) {
+                return Err(AudioError::Options(format!(
+                    "{name} must be between {} and {}, got {value}",
+                    range.start(),
+                    range.end()
+                )));
+            }
+        }
+        let (name, seconds) = match self.tail {
+            Tail::None => ("tail", 0.0),
+            Tail::Seconds(seconds) => ("tail", seconds),
+            Tail::UntilSilent { max_seconds, .. } => ("maximum tail", max_seconds),
+        };
+        if !(0.0..=MAX_TAIL_SECONDS).contains(&seconds) {
+            return Err(AudioError::Options(format!(
+                "{name} must be between 0 and {MAX_TAIL_SECONDS} seconds, got {seconds:?}"
+            )));
+        }
+        let times = [
+            ("start", Some(self.start)),
+            ("end", self.end),
+            ("loop start", self.looping.and_then(|looping| looping.start)),
+            ("loop end", self.looping.and_then(|looping| looping.end)),
+            ("fade in", self.fades.map(|fades| fades.fade_in)),
+            ("fade out", self.fades.map(|fades| fades.fade_out)),
+        ];
+        for (name, seconds) in times {
+            if let Some(seconds) = seconds.filter(|seconds| !seconds.is_finite() || *seconds < 0.0)
+            {
+                return Err(AudioError::Options(format!(
+                    "{name} must be a finite, non-negative time in seconds, got {seconds:?}"
+                )));
+            }
+        }
+        if let Some(trim) = self.trim {
+            // Zero would count every frame as sound, which comes from a threshold of -inf dB.
+            if !trim.threshold.is_finite() || trim.threshold <= 0.0 {
+                return Err(AudioError::Options(
+                    "trim threshold must be a finite level in dBFS".to_string(),
+                ));
+            }
+        }
+        if self.end.is_some_and(|end| end <= self.start) {
+            return Err(AudioError::Options(
+                "end must be greater than start".to_string(),
+            ));
+        }
+        if let Some(looping) = self.looping {
+            if !(1..=MAX_LOOP_REPETITIONS).contains(&looping.repetitions) {
+                return Err(AudioError::Options(format!(
+                    "loop repetitions must be between 1 and {MAX_LOOP_REPETITIONS}, got {}",
+                    looping.repetitions
+                )));
+            }
+        }
+        Ok(())
+    }
 
-    // Write WAV header
-    wav_data.extend_from_slice(b"RIFF");
-    write_u32(&mut wav_data, 36 + (sample_count * 4) as u32)?; // File size - 8
-    wav_data.extend_from_slice(b"WAVE");
+    fn synthesizer_settings(&self) -> SynthesizerSettings {
+        let mut settings = SynthesizerSettings::new(self.sample_rate as i32);
+        if let Some(enable_reverb_and_chorus) = self.enable_reverb_and_chorus {
+            settings.enable_reverb_and_chorus = enable_reverb_and_chorus;
+        }
+        if let Some(block_size) = self.block_size {
+            settings.block_size = block_size;
+        }
+        if let Some(maximum_polyphony) = self.maximum_polyphony {
+            settings.maximum_polyphony = maximum_polyphony;
+        }
+        settings
+    }
+}
 
-    // Write format chunk
-    wav_data.extend_from_slice(b"fmt ");
-    write_u32(&mut wav_data, 16)?; // Chunk size
-    wav_data.extend_from_slice(&1u16.to_le_bytes()); // Audio format (PCM)
-    wav_data.extend_from_slice(&2u16.to_le_bytes()); // Number of channels
-    write_u32(&mut wav_data, SAMPLE_RATE as u32)?; // Sample rate
-    write_u32(&mut wav_data, (SAMPLE_RATE as u32) * 4)?; // Byte rate
-    wav_data.extend_from_slice(&4u16.to_le_bytes()); // Block align
-    wav_data.extend_from_slice(&16u16.to_le_bytes()); // Bits per sample
+/// Settings for `render_midi_to_flac`.
+#[derive(Debug)]
+pub struct FlacOptions {
+    /// `S16` or `S24`; FLAC has no float samples and flacenc stops at 24 bits.
+    pub sample_format: SampleFormat,
+    /// 0 (fastest) to 8 (smallest), following the levels of the reference encoder.
+    pub compression_level: u8,
+    /// Vorbis comments, written in order.
+    pub tags: Vec<(String, String)>,
+}
 
-    // Write data chunk header
-    wav_data.extend_from_slice(b"data");
-    write_u32(&mut wav_data, (sample_count * 4) as u32)?; // Chunk size
+/// Sample order of `render_midi_to_pcm`.
+#[derive(Debug, Clone, Copy)]
+pub enum PcmLayout {
+    /// Frame by frame, with the channels alternating.
+    Interleaved,
+    /// Each channel in full, one after the other.
+    Planar,
+}
+
+/// Bitrate mode of `render_midi_to_mp3`.
+#[derive(Debug, Clone, Copy)]
+pub enum Mp3Bitrate {
+    /// Constant bitrate in kbit/s, one of the rates in `MP3_BITRATES`.
+    Cbr(u16),
+    /// Variable bitrate at a LAME `-V` level, 0 (best) to 9 (smallest).
+    Vbr(u8),
+}
+
+/// Constant bitrates LAME can encode, in kbit/s.
+pub const MP3_BITRATES: [u16; 16] = [
+    8, 16, 24, 32, 40, 48, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+
+// Tag names `render_midi_to_mp3` can store, each mapped to its ID3v2 frame.
+const MP3_TAGS: [&str; 5] = ["TITLE", "ARTIST", "ALBUM", "YEAR", "COMMENT"];
+
+// Longest tag value LAME copies into an ID3 frame, in bytes.
+const MAX_MP3_TAG_LENGTH: usize = 250;
 
-    // Convert f32 samples to i16 and write to WAV data
-    for (l, r) in left.iter().zip(right.iter()) {
-        let left_sample = (l.clamp(-1.0, 0.99999994) * 32768.0) as i16;
-        let right_sample = (r.clamp(-1.0, 0.99999994) * 32768.0) as i16;
-        wav_data.write_all(&left_sample.to_le_bytes())?;
-        wav_data.write_all(&right_sample.to_le_bytes())?;
+/// Settings for `render_midi_to_mp3`.
+#[derive(Debug)]
+pub struct Mp3Options {
+    pub bitrate: Mp3Bitrate,
+    /// Named after `MP3_TAGS`, in any case.
+    pub tags: Vec<(String, String)>,
+}
+
+impl Mp3Options {
+    /// Checks that every tag can be stored, so a render is not wasted on them.
+    pub fn validate(&self) -> Result<(), AudioError> {
+        self.id3_fields().map(|_| ())
     }
 
-    Ok(wav_data)
+    /// The tags as their `MP3_TAGS` name and Latin-1 text, which is all LAME writes.
+    fn id3_fields(&self) -> Result<Vec<(&'static str, Vec<u8>)>, AudioError> {
+        self.tags
+            .iter()
+            .map(|(name, value)| {
+                let upper = name.to_ascii_uppercase();
+                let field = MP3_TAGS.iter().find(|tag| **tag == upper).ok_or_else(|| {
+                    AudioError::Options(format!(
+                        "Unsupported MP3 tag '{}', expected one of {}",
+                        name,
+                        MP3_TAGS.join(", ")
+                    ))
+                })?;
+                let text = value
+                    .chars()
+                    .map(|c| u8::try_from(c).ok().filter(|&byte| byte != 0))
+                    .collect::<Option<Vec<u8>>>()
+                    .ok_or_else(|| {
+                        AudioError::Options(format!(
+                            "MP3 tag '{}' can only hold Latin-1 text without NUL characters",
+                            name
+                        ))
+                    })?;
+                if text.len() > MAX_MP3_TAG_LENGTH {
+                    return Err(AudioError::Options(format!(
+                        "MP3 tag '{}' is longer than {} characters",
+                        name, MAX_MP3_TAG_LENGTH
+                    )));
+                }
+                Ok((*field, text))
+            })
+            .collect()
+    }
 }
 
-pub fn wav_to_opus_ogg(
-    wav_data: &[u8],
-    stereo: bool,
-    bitrate: OpusBitrate,
-) -> Result<Vec<u8>, AudioError> {
-    let wav_header = parse_wav_header(wav_data)?;
+/// How `render_midi_stems` splits the song into separate renders.
+#[derive(Debug, Clone, Copy)]
+pub enum StemSplit {
+    Channel,
+    Track,
+}
+
+// Format tags of the `fmt ` chunk.
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+// FLAC metadata block type of a Vorbis comment.
+const FLAC_VORBIS_COMMENT: u8 = 4;
+// Chunk size of a stream whose length is unknown, read as "to the end of the file".
+const WAV_UNKNOWN_SIZE: u32 = u32::MAX;
+// The tail shared by the KSDATAFORMAT_SUBTYPE GUIDs, after the 2-byte format tag.
+const SUBTYPE_GUID_TAIL: [u8; 14] = [
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
+
+#[derive(Debug)]
+struct WavHeader {
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+    is_float: bool,
+    data_start: usize,
+    data_end: usize,
+}
 
-    if wav_header.sample_rate != SAMPLE_RATE as u32 {
-        return Err(AudioError::WavParsing(format!(
-            "Unsupported sample rate. Expected {}, got {}",
-            SAMPLE_RATE, wav_header.sample_rate
-        )));
+fn parse_wav_header(data: &[u8]) -> Result<WavHeader, AudioError> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(AudioError::WavParsing("Invalid WAV file".to_string()));
     }
 
-    let pcm_data = &wav_data[wav_header.data_start..];
-    let channel_count = wav_header.channels as usize;
+    let mut format = None;
+    let mut chunk_start = 12;
+    while chunk_start + 8 <= data.len() {
+        let chunk_type = &data[chunk_start..chunk_start + 4];
+        let chunk_size = u32::from_le_bytes([
+            data[chunk_start + 4],
+            data[chunk_start + 5],
+            data[chunk_start + 6],
+            data[chunk_start + 7],
+        ]) as usize;
+        let body_start = chunk_start + 8;
 
-    let channels = if stereo {
-        Channels::Stereo
-    } else {
-        Channels::Mono
-    };
-    let mut encoder = Encoder::new(SAMPLE_RATE as u32, channels, Application::Audio)?;
-
-    match bitrate {
-        OpusBitrate::Auto => encoder.set_bitrate(Bitrate::Auto)?,
-        OpusBitrate::Max => encoder.set_bitrate(Bitrate::Max)?,
-        OpusBitrate::Bits(bits) => encoder.set_bitrate(Bitrate::Bits(bits))?,
-    }
-    // Convert PCM data to Vec<i16>, handling both mono and stereo
-    let samples: Vec<i16> = match wav_header.bits_per_sample {
-        16 => pcm_data
-            .chunks_exact(2 * channel_count)
-            .flat_map(|chunk| {
-                chunk
-                    .chunks_exact(2)
-                    .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
-                    .take(if stereo { 2 } else { 1 })
+        if chunk_type == b"fmt " {
+            let body = data
+                .get(body_start..body_start + chunk_size)
+                .filter(|body| body.len() >= 16)
+                .ok_or_else(|| AudioError::WavParsing("Truncated fmt chunk".to_string()))?;
+            format = Some(parse_fmt_chunk(body)?);
+        } else if chunk_type == b"data" {
+            let (format_tag, channels, sample_rate, bits_per_sample) =
+                format.ok_or_else(|| AudioError::WavParsing("No fmt chunk found".to_string()))?;
+            return Ok(WavHeader {
+                channels,
+                sample_rate,
+                bits_per_sample,
+                is_float: format_tag == WAVE_FORMAT_IEEE_FLOAT,
+                data_start: body_start,
+                // Streamed files carry a placeholder size, so stop at the end of the data.
+                data_end: body_start.saturating_add(chunk_size).min(data.len()),
+            });
+        }
+
+        // Chunks are padded to an even size.
+        chunk_start = body_start
+            .checked_add(chunk_size + chunk_size % 2)
+            .ok_or_else(|| AudioError::WavParsing("Invalid chunk size".to_string()))?;
+    }
+
+    Err(AudioError::WavParsing("No data chunk found".to_string()))
+}
+
+/// Reads the format tag, channel count, sample rate and bit depth of a `fmt ` chunk,
+/// resolving WAVE_FORMAT_EXTENSIBLE to the format tag of its subtype.
+fn parse_fmt_chunk(body: &[u8]) -> Result<(u16, u16, u32, u16), AudioError> {
+    let mut format_tag = u16::from_le_bytes([body[0], body[1]]);
+    let channels = u16::from_le_bytes([body[2], body[3]]);
+    let sample_rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+    let bits_per_sample = u16::from_le_bytes([body[14], body[15]]);
+
+    if format_tag == WAVE_FORMAT_EXTENSIBLE {
+        let sub_format = body
+            .get(24..40)
+            .filter(|guid| guid[2..] == SUBTYPE_GUID_TAIL)
+            .ok_or_else(|| AudioError::WavParsing("Invalid extensible format".to_string()))?;
+        format_tag = u16::from_le_bytes([sub_format[0], sub_format[1]]);
+    }
+
+    match (format_tag, bits_per_sample) {
+        (WAVE_FORMAT_PCM, 8 | 16 | 24 | 32) | (WAVE_FORMAT_IEEE_FLOAT, 32) => {
+            Ok((format_tag, channels, sample_rate, bits_per_sample))
+        }
+        _ => Err(AudioError::WavParsing(format!(
+            "Unsupported sample format: tag {}, {} bits",
+            format_tag, bits_per_sample
+        ))),
+    }
+}
+
+/// Decodes the data chunk into interleaved samples in [-1.0, 1.0).
+fn decode_wav_samples(data: &[u8], header: &WavHeader) -> Vec<f32> {
+    let pcm_data = &data[header.data_start..header.data_end];
+    match (header.bits_per_sample, header.is_float) {
+        (8, _) => pcm_data
+            .iter()
+            .map(|&sample| (sample as f32 - 128.0) / 128.0)
+            .collect(),
+        (16, _) => pcm_data
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32768.0)
+            .collect(),
+        (24, _) => pcm_data
+            .chunks_exact(3)
+            .map(|sample| {
+                i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) as f32 / 2147483648.0
             })
             .collect(),
-        8 => pcm_data
-            .iter()
-            .map(|&sample| ((sample as i16 - 128) << 8))
+        (_, false) => pcm_data
+            .chunks_exact(4)
+            .map(|sample| {
+                i32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]) as f32
+                    / 2147483648.0
+            })
             .collect(),
-        _ => {
-            return Err(AudioError::WavParsing(format!(
-                "Unsupported bit depth: {}",
-                wav_header.bits_per_sample
-            )))
-        }
+        (_, true) => pcm_data
+            .chunks_exact(4)
+            .map(|sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]))
+            .collect(),
+    }
+}
+
+/// Parses an SF2 or SF3 file; SF3 samples are decoded from Ogg Vorbis here.
+pub fn load_soundfont(soundfont_bytes: &[u8]) -> Result<Arc<SoundFont>, AudioError> {
+    let soundfont_bytes = sf3::decompress(soundfont_bytes)?;
+    // rustysynth trusts the zone indices of presets and instruments, and panics on a file
+    // whose indices run past their lists; that is one more invalid SoundFont.
+    let sound_font =
+        std::panic::catch_unwind(|| SoundFont::new(&mut Cursor::new(&soundfont_bytes[..])))
+            .map_err(|_| AudioError::SoundFont("Zone indices out of range".to_string()))?
+            .map_err(|e| AudioError::SoundFont(e.to_string()))?;
+    Ok(Arc::new(sound_font))
+}
+
+pub fn render_midi_to_wav(
+    sound_fonts: &SoundFontStack,
+    midi_bytes: &[u8],
+    channels: OutputChannels,
+    sample_format: SampleFormat,
+    options: &RenderOptions,
+) -> Result<(Vec<u8>, OverloadReport), AudioError> {
+    let song = Arc::new(MidiSong::parse(midi_bytes)?);
+    let (mut left, mut right) = render_song(sound_fonts, &song, NoteFilter::All, options)?;
+    let report = master(&mut left, &mut right, channels, options);
+    let mut wav_data = encode_wav(
+        &left,
+        &right,
+        channels,
+        sample_format,
+        options.dither,
+        options.sample_rate,
+    )?;
+    if let Some(points) = LoopPoints::locate(&song, options)? {
+        append_smpl_chunk(&mut wav_data, points, options.sample_rate)?;
+    }
+    Ok((wav_data, report))
+}
+
+/// Returns the synthesizer's float samples without clipping or a container.
+pub fn render_midi_to_pcm(
+    sound_fonts: &SoundFontStack,
+    midi_bytes: &[u8],
+    channels: OutputChannels,
+    layout: PcmLayout,
+    options: &RenderOptions,
+) -> Result<(Vec<f32>, OverloadReport), AudioError> {
+    let song = Arc::new(MidiSong::parse(midi_bytes)?);
+    let (mut left, mut right) = render_song(sound_fonts, &song, NoteFilter::All, options)?;
+    let report = master(&mut left, &mut right, channels, options);
+    Ok((arrange_pcm(left, right, channels, layout), report))
+}
+
+pub fn render_midi_to_flac(
+    sound_fonts: &SoundFontStack,
+    midi_bytes: &[u8],
+    channels: OutputChannels,
+    flac_options: &FlacOptions,
+    options: &RenderOptions,
+) -> Result<(Vec<u8>, OverloadReport), AudioError> {
+    let song = Arc::new(MidiSong::parse(midi_bytes)?);
+    let (mut left, mut right) = render_song(sound_fonts, &song, NoteFilter::All, options)?;
+    let report = master(&mut left, &mut right, channels, options);
+    let mut tags = flac_options.tags.clone();
+    if let Some(points) = LoopPoints::locate(&song, options)? {
+        tags.extend(points.tags(options.sample_rate, options.sample_rate));
+    }
+    let flac_options = FlacOptions {
+        tags,
+        ..*flac_options
     };
+    Ok((
+        encode_flac(
+            &left,
+            &right,
+            channels,
+            &flac_options,
+            options.dither,
+            options.sample_rate,
+        )?,
+        report,
+    ))
+}
 
-    let mut ogg_output = Vec::new();
-    let mut granule_position = 0u64;
+/// Renders to Ogg Opus. TITLE and COPYRIGHT default to the song's name and copyright notice.
+pub fn render_midi_to_opus(
+    sound_fonts: &SoundFontStack,
+    midi_bytes: &[u8],
+    channels: OutputChannels,
+    bitrate: OpusBitrate,
+    tags: &[(String, String)],
+    options: &RenderOptions,
+) -> Result<(Vec<u8>, OverloadReport), AudioError> {
+    let song = Arc::new(MidiSong::parse(midi_bytes)?);
+    let (mut left, mut right) = render_song(sound_fonts, &song, NoteFilter::All, options)?;
+    trim_and_fade(&mut left, &mut right, channels, options);
+    let mut tags = with_song_tags(tags, &song);
+    if let Some(points) = LoopPoints::locate(&song, options)? {
+        tags.extend(points.tags(options.sample_rate, OPUS_GRANULE_RATE));
+    }
 
+    // The header output gain carries the normalization, which players apply when decoding.
+    // Only an attenuation keeping the samples below full scale is applied here.
+    let mut output_gain = 0;
+    if let Some((gain, measured)) = options
+        .loudness
+        .and_then(|target| loudness_gain(&left, &right, channels, options.sample_rate, target))
     {
-        let mut packet_writer = PacketWriter::new(Cursor::new(&mut ogg_output));
+        let sample_gain = (-20.0 * (peak(&left, &right) as f64).log10()).min(0.0);
+        apply_gain(&mut left, &mut right, sample_gain);
+        output_gain = to_q8(gain - sample_gain);
+        // Relative to the output gain, towards the -23 LUFS reference of RFC 7845.
+        let track_gain = to_q8(R128_REFERENCE - (measured + gain));
+        tags.push(("R128_TRACK_GAIN".to_string(), track_gain.to_string()));
+    }
+    let report = handle_overload(
+        &mut left,
+        &mut right,
+        channels,
+        options.sample_rate,
+        options.overload,
+    );
 
-        // Write Opus header
-        let opus_header = create_opus_header(channels, SAMPLE_RATE as u32);
-        packet_writer.write_packet(
-            opus_header,
-            1, // Serial number
-            PacketWriteEndInfo::EndPage,
-            0, // Granule position
-        )?;
+    let samples: Vec<i16> = interleave(&left, &right, channels)
+        .into_iter()
+        .map(to_i16)
+        .collect();
+    let stereo = matches!(channels, OutputChannels::Stereo);
+    let mut encoder =
+        OpusOggEncoder::new(options.sample_rate, stereo, bitrate, output_gain, &tags)?;
+    encoder.push(&samples)?;
+    Ok((encoder.finish()?, report))
+}
 
-        // Write Opus comment header
-        let opus_comment = create_opus_comment();
-        packet_writer.write_packet(
-            opus_comment,
-            1, // Serial number
-            PacketWriteEndInfo::EndPage,
-            0, // Granule position
-        )?;
+/// Adds TITLE and COPYRIGHT from the song's meta events unless `tags` already names them.
+fn with_song_tags(tags: &[(String, String)], song: &MidiSong) -> Vec<(String, String)> {
+    let mut tags = tags.to_vec();
+    for (name, value) in [("TITLE", song.title()), ("COPYRIGHT", song.copyright())] {
+        if let Some(value) = value {
+            if !tags.iter().any(|(key, _)| key.eq_ignore_ascii_case(name)) {
+                tags.push((name.to_string(), value.to_string()));
+            }
+        }
+    }
+    tags
+}
+
+/// Renders to Ogg Vorbis at `quality`, on the -1 (smallest) to 10 (best) scale of `oggenc`.
+pub fn render_midi_to_vorbis(
+    sound_fonts: &SoundFontStack,
+    midi_bytes: &[u8],
+    channels: OutputChannels,
+    quality: f32,
+    options: &RenderOptions,
+) -> Result<(Vec<u8>, OverloadReport), AudioError> {
+    let song = Arc::new(MidiSong::parse(midi_bytes)?);
+    let (mut left, mut right) = render_song(sound_fonts, &song, NoteFilter::All, options)?;
+    let report = master(&mut left, &mut right, channels, options);
+    let tags = LoopPoints::locate(&song, options)?
+        .map(|points| {
+            points
+                .tags(options.sample_rate, options.sample_rate)
+                .to_vec()
+        })
+        .unwrap_or_default();
+    Ok((
+        encode_vorbis(&left, &right, channels, quality, &tags, options.sample_rate)?,
+        report,
+    ))
+}
+
+pub fn render_midi_to_mp3(
+    sound_fonts: &SoundFontStack,
+    midi_bytes: &[u8],
+    channels: OutputChannels,
+    mp3_options: &Mp3Options,
+    options: &RenderOptions,
+) -> Result<(Vec<u8>, OverloadReport), AudioError> {
+    let song = Arc::new(MidiSong::parse(midi_bytes)?);
+    let (mut left, mut right) = render_song(sound_fonts, &song, NoteFilter::All, options)?;
+    let report = master(&mut left, &mut right, channels, options);
+    Ok((
+        encode_mp3(&left, &right, channels, mp3_options, options.sample_rate)?,
+        report,
+    ))
+}
+
+/// Renders every channel or track that plays notes on its own, keyed by its index.
+///
+/// All stems share the song's timeline, so they line up when mixed back together.
+pub fn render_midi_stems(
+    sound_fonts: &SoundFontStack,
+    midi_bytes: &[u8],
+    split: StemSplit,
+    channels: OutputChannels,
+    sample_format: SampleFormat,
+    options: &RenderOptions,
+) -> Result<Vec<(usize, Vec<u8>)>, AudioError> {
+    let song = Arc::new(MidiSong::parse(midi_bytes)?);
+    let filters: Vec<(usize, NoteFilter)> = match split {
+        StemSplit::Channel => song
+            .channels_with_notes()
+            .into_iter()
+            .map(|channel| (channel as usize, NoteFilter::Channel(channel)))
+            .collect(),
+        StemSplit::Track => song
+            .tracks_with_notes()
+            .into_iter()
+            .map(|track| (track, NoteFilter::Track(track)))
+            .collect(),
+    };
 
-        // Encode audio data
-        for chunk in samples.chunks(FRAME_SIZE * channels as usize) {
-            let mut packet = vec![0u8; MAX_PACKET_SIZE];
-            // Underlying C implementation of OPUS encoder
-            // cannot deal with frames shorter then 10ms.
-            // Additionally, the input should always be frame-sized???
-            //
-            // Since we take the data in frame-sized chunks,
-            // the only case when this would happen is the last chunk.
-            // We pad the last chunk up to the frame size.
-            let chunk = &(pad_chunk(chunk, channels as usize));
-            let _packet_len = encoder.encode(chunk, &mut packet)?;
-            packet.truncate(MAX_PACKET_SIZE);
-
-            granule_position = granule_position.saturating_add(FRAME_SIZE as u64);
-
-            packet_writer.write_packet(
-                packet,
-                1, // Serial number
-                PacketWriteEndInfo::NormalPacket,
-                granule_position,
+    let loop_points = LoopPoints::locate(&song, options)?;
+    filters
+        .into_iter()
+        .map(|(key, filter)| {
+            let (left, right) = render_song(sound_fonts, &song, filter, options)?;
+            let mut wav_data = encode_wav(
+                &left,
+                &right,
+                channels,
+                sample_format,
+                options.dither.for_stem(key),
+                options.sample_rate,
             )?;
-        }
+            if let Some(points) = loop_points {
+                append_smpl_chunk(&mut wav_data, points, options.sample_rate)?;
+            }
+            Ok((key, wav_data))
+        })
+        .collect()
+}
 
-        // Write end of stream
-        packet_writer.write_packet(
-            Vec::new(),
-            1, // Serial number
-            PacketWriteEndInfo::EndStream,
-            granule_position,
-        )?;
+fn render_song(
+    sound_fonts: &SoundFontStack,
+    song: &Arc<MidiSong>,
+    filter: NoteFilter,
+    options: &RenderOptions,
+) -> Result<(Vec<f32>, Vec<f32>), AudioError> {
+    let mut renderer = SongRenderer::new(sound_fonts, song, filter, options)?;
+    let capacity = renderer.known_length().unwrap_or(0);
+    let mut left: Vec<f32> = Vec::with_capacity(capacity);
+    let mut right: Vec<f32> = Vec::with_capacity(capacity);
+
+    // Render audio in chunks to avoid excessive memory usage
+    let mut temp_left = vec![0.0; CHUNK_SIZE];
+    let mut temp_right = vec![0.0; CHUNK_SIZE];
+    loop {
+        let frames = renderer.render(&mut temp_left, &mut temp_right);
+        if frames == 0 {
+            break;
+        }
+        left.extend_from_slice(&temp_left[..frames]);
+        right.extend_from_slice(&temp_right[..frames]);
     }
 
-    Ok(ogg_output)
+    Ok((left, right))
 }
 
-fn pad_chunk(chunk: &[i16], channels: usize) -> Vec<i16> {
-    let min_length = FRAME_SIZE * channels;
-    let padding_size = (min_length as i16) - (chunk.len() as i16);
-    if padding_size < 1 {
-        return chunk.to_vec();
-    }
-    let mut padded = chunk.to_vec();
-    padded.extend(std::iter::repeat(0 as i16).take(padding_size as usize));
-    padded
+/// Renders a song chunk by chunk: the selected section, then its tail.
+struct SongRenderer {
+    sequencer: Sequencer,
+    tail: Tail,
+    // Frames up to the end of the selected section.
+    sample_count: usize,
+    max_sample_count: usize,
+    stops_early: bool,
+    rendered: usize,
+    finished: bool,
 }
 
-fn create_opus_header(channels: Channels, sample_rate: u32) -> Vec<u8> {
-    let mut header = vec![
-        b'O',
-        b'p',
-        b'u',
-        b's',
-        b'H',
-        b'e',
-        b'a',
-        b'd', // Magic signature
-        1,    // Version
-        channels as u8,
-        0,
-        0, // Pre-skip (3840 samples or 80ms)
-        sample_rate.to_le_bytes()[0],
-        sample_rate.to_le_bytes()[1],
-        sample_rate.to_le_bytes()[2],
-        sample_rate.to_le_bytes()[3],
-        0,
-        0, // Output gain
-        0, // Channel mapping family (0 for mono/stereo)
-    ];
+impl SongRenderer {
+    fn new(
+        sound_fonts: &SoundFontStack,
+        song: &Arc<MidiSong>,
+        filter: NoteFilter,
+        options: &RenderOptions,
+    ) -> Result<Self, AudioError> {
+        options.validate()?;
+        let sample_rate = options.sample_rate;
 
-    // Set pre-skip value (3840 samples or 80ms)
-    header[10] = 0x00;
-    header[11] = 0x0F;
+        let settings = options.synthesizer_settings();
+        let synthesizer = LayeredSynthesizer::new(sound_fonts, &settings)?;
+        let mut sequencer = Sequencer::new(synthesizer, Arc::clone(song), filter);
 
-    header
-}
+        let (start, song_end, duration) = match options.looping {
+            Some(looping) => {
+                let (loop_start, loop_end) = looping.section(song)?;
+                sequencer.set_loop(loop_start, loop_end, looping.repetitions);
+                let start = if looping.intro { 0.0 } else { loop_start };
+                let repetitions = looping.repetitions.max(1);
+                let duration = loop_start - start + repetitions as f64 * (loop_end - loop_start);
+                (start, loop_end, duration)
+            }
+            None => {
+                let song_end = options
+                    .end
+                    .map_or(song.length(), |end| end.min(song.length()));
+                let start = options.start.min(song_end);
+                (start, song_end, song_end - start)
+            }
+        };
+        if start > 0.0 {
+            sequencer.seek(start);
+        }
 
-fn create_opus_comment() -> Vec<u8> {
-    let vendor_string = b"midirenderer";
-    let mut comment = vec![
-        b'O',
-        b'p',
-        b'u',
-        b's',
-        b'T',
-        b'a',
-        b'g',
-        b's', // Magic signature
-        (vendor_string.len() as u32).to_le_bytes()[0],
-        (vendor_string.len() as u32).to_le_bytes()[1],
-        (vendor_string.len() as u32).to_le_bytes()[2],
-        (vendor_string.len() as u32).to_le_bytes()[3],
-    ];
-    comment.extend_from_slice(vendor_string);
-    comment.extend_from_slice(&[0, 0, 0, 0]); // User comment list length
+        let sample_count = (sample_rate as f64 * duration) as usize;
+        let tail_count = match options.tail {
+            Tail::None => 0,
+            Tail::Seconds(seconds) => (sample_rate as f64 * seconds) as usize,
+            Tail::UntilSilent { max_seconds, .. } => (sample_rate as f64 * max_seconds) as usize,
+        };
 
-    comment
+        Ok(Self {
+            sequencer,
+            tail: options.tail,
+            sample_count,
+            max_sample_count: sample_count + tail_count,
+            // Ending early releases the notes still sounding, so the tail can ring out.
+            stops_early: song_end < song.length(),
+            rendered: 0,
+            finished: false,
+        })
+    }
+
+    /// The total number of frames, unless it depends on when the tail dies away.
+    fn known_length(&self) -> Option<usize> {
+        match self.tail {
+            Tail::UntilSilent { .. } => None,
+            _ => Some(self.max_sample_count),
+        }
+    }
+
+    /// Fills the buffers with the next frames and returns how many were written.
+    /// Returns 0 once the song and its tail are finished.
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) -> usize {
+        let mut written = 0;
+        while !self.finished && written < left.len() {
+            if self.rendered >= self.max_sample_count {
+                self.finished = true;
+                break;
+            }
+            if self.stops_early && self.rendered == self.sample_count {
+                self.sequencer.stop();
+            }
+
+            // Keep chunks aligned to the end of the song so the tail is checked on its own.
+            let remaining = if self.rendered < self.sample_count {
+                self.sample_count - self.rendered
+            } else {
+                self.max_sample_count - self.rendered
+            };
+            let chunk_size = CHUNK_SIZE.min(remaining).min(left.len() - written);
+            let chunk_left = &mut left[written..written + chunk_size];
+            let chunk_right = &mut right[written..written + chunk_size];
+
+            self.sequencer.render(chunk_left, chunk_right);
+
+            if let Tail::UntilSilent { threshold, .. } = self.tail {
+                if self.rendered >= self.sample_count && peak(chunk_left, chunk_right) < threshold {
+                    self.finished = true;
+                    break;
+                }
+            }
+
+            self.rendered += chunk_size;
+            written += chunk_size;
+        }
+        written
+    }
+}
+
+/// The encoding of the chunks produced by a `RenderStream`.
+#[derive(Debug, Clone, Copy)]
+pub enum StreamFormat {
+    /// Raw interleaved little-endian samples.
+    Pcm {
+        sample_format: SampleFormat,
+    },
+    Wav {
+        sample_format: SampleFormat,
+    },
+    Opus {
+        bitrate: OpusBitrate,
+    },
+}
+
+/// Renders and encodes a MIDI file incrementally, so only one chunk is held in memory.
+pub struct RenderStream {
+    renderer: SongRenderer,
+    opus_encoder: Option<OpusOggEncoder>,
+    format: StreamFormat,
+    channels: OutputChannels,
+    ditherer: Option<Ditherer>,
+    // Sample bytes produced so far, to pad an odd-sized WAV data chunk.
+    data_size: usize,
+    left: Vec<f32>,
+    right: Vec<f32>,
+    // Bytes produced before the first chunk, such as the container header.
+    pending: Vec<u8>,
+    // Bytes produced after the last chunk, such as a WAV `smpl` chunk.
+    trailer: Vec<u8>,
+    finished: bool,
+}
+
+impl RenderStream {
+    pub fn new(
+        sound_fonts: &SoundFontStack,
+        midi_bytes: &[u8],
+        format: StreamFormat,
+        channels: OutputChannels,
+        chunk_size: usize,
+        options: &RenderOptions,
+    ) -> Result<Self, AudioError> {
+        let song = Arc::new(MidiSong::parse(midi_bytes)?);
+        let renderer = SongRenderer::new(sound_fonts, &song, NoteFilter::All, options)?;
+        let loop_points = LoopPoints::locate(&song, options)?;
+
+        let mut pending = Vec::new();
+        let mut trailer = Vec::new();
+        let mut opus_encoder = None;
+        match format {
+            StreamFormat::Pcm { .. } => {}
+            StreamFormat::Wav { sample_format } => {
+                write_wav_header(
+                    &mut pending,
+                    renderer.known_length(),
+                    channels,
+                    sample_format,
+                    options.sample_rate,
+                )?;
+                if let Some(points) = loop_points {
+                    // The `smpl` chunk follows the samples, so the header must count it.
+                    if renderer.known_length().is_none() {
+                        return Err(AudioError::Options(
+                            "a looped WAV stream needs a known length; use a fixed tail".into(),
+                        ));
+                    }
+                    write_smpl_chunk(&mut trailer, points, options.sample_rate)?;
+                    let riff_size = u32::from_le_bytes(pending[4..8].try_into().unwrap());
+                    let riff_size = wav_size(riff_size as u64 + trailer.len() as u64)?;
+                    pending[4..8].copy_from_slice(&riff_size.to_le_bytes());
+                }
+            }
+            StreamFormat::Opus { bitrate } => {
+                let stereo = matches!(channels, OutputChannels::Stereo);
+                let tags = loop_points
+                    .map(|points| points.tags(options.sample_rate, OPUS_GRANULE_RATE).to_vec())
+                    .unwrap_or_default();
+                let mut encoder =
+                    OpusOggEncoder::new(options.sample_rate, stereo, bitrate, 0, &tags)?;
+                pending = encoder.take_output();
+                opus_encoder = Some(encoder);
+            }
+        }
+
+        Ok(Self {
+            renderer,
+            opus_encoder,
+            format,
+            channels,
+            ditherer: options.dither.ditherer(channels),
+            data_size: 0,
+            left: vec![0.0; chunk_size.max(1)],
+            right: vec![0.0; chunk_size.max(1)],
+            pending,
+            trailer,
+            finished: false,
+        })
+    }
+
+    /// Returns the next encoded chunk, or `None` once the whole song has been produced.
+    pub fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, AudioError> {
+        let mut output = std::mem::take(&mut self.pending);
+        while output.is_empty() {
+            if self.finished {
+                return Ok(None);
+            }
+
+            let frames = self.renderer.render(&mut self.left, &mut self.right);
+            let (left, right) = (&self.left[..frames], &self.right[..frames]);
+            match (&mut self.opus_encoder, self.format) {
+                (Some(encoder), _) => {
+                    let samples = interleave(left, right, self.channels);
+                    encoder.push(&samples.iter().map(|&s| to_i16(s)).collect::<Vec<_>>())?;
+                    output = encoder.take_output();
+                }
+                (None, StreamFormat::Pcm { sample_format })
+                | (None, StreamFormat::Wav { sample_format }) => {
+                    write_pcm(
+                        &mut output,
+                        left,
+                        right,
+                        self.channels,
+                        sample_format,
+                        &mut self.ditherer,
+                    );
+                    self.data_size += output.len();
+                }
+                (None, StreamFormat::Opus { .. }) => unreachable!(),
+            }
+
+            if frames == 0 {
+                self.finished = true;
+                if let Some(encoder) = self.opus_encoder.take() {
+                    output.extend(encoder.finish()?);
+                }
+                if matches!(self.format, StreamFormat::Wav { .. }) && self.data_size % 2 == 1 {
+                    output.push(0);
+                }
+                output.append(&mut self.trailer);
+            }
+        }
+        Ok(Some(output))
+    }
+}
+
+fn encode_wav(
+    left: &[f32],
+    right: &[f32],
+    channels: OutputChannels,
+    sample_format: SampleFormat,
+    dither: Dither,
+    sample_rate: u32,
+) -> Result<Vec<u8>, AudioError> {
+    let mut wav_data = Vec::new();
+    write_wav_header(
+        &mut wav_data,
+        Some(left.len()),
+        channels,
+        sample_format,
+        sample_rate,
+    )?;
+    write_pcm(
+        &mut wav_data,
+        left,
+        right,
+        channels,
+        sample_format,
+        &mut dither.ditherer(channels),
+    );
+    if wav_data.len() % 2 == 1 {
+        wav_data.push(0); // Pad byte
+    }
+    Ok(wav_data)
+}
+
+/// Appends a `smpl` chunk marking one endlessly repeating forward loop, and updates the
+/// RIFF size to cover it.
+fn append_smpl_chunk(
+    wav_data: &mut Vec<u8>,
+    points: LoopPoints,
+    sample_rate: u32,
+) -> Result<(), AudioError> {
+    write_smpl_chunk(wav_data, points, sample_rate)?;
+    let riff_size = wav_size(wav_data.len() as u64 - 8)?;
+    wav_data[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(())
+}
+
+fn write_smpl_chunk(
+    wav_data: &mut Vec<u8>,
+    points: LoopPoints,
+    sample_rate: u32,
+) -> Result<(), AudioError> {
+    wav_data.extend_from_slice(b"smpl");
+    write_u32(wav_data, 36 + 24)?; // Chunk size, with one loop
+    write_u32(wav_data, 0)?; // Manufacturer
+    write_u32(wav_data, 0)?; // Product
+    write_u32(wav_data, 1_000_000_000 / sample_rate)?; // Sample period in nanoseconds
+    write_u32(wav_data, SMPL_UNITY_NOTE)?; // MIDI unity note
+    write_u32(wav_data, 0)?; // MIDI pitch fraction
+    write_u32(wav_data, 0)?; // SMPTE format
+    write_u32(wav_data, 0)?; // SMPTE offset
+    write_u32(wav_data, 1)?; // Number of loops
+    write_u32(wav_data, 0)?; // Sampler data size
+
+    write_u32(wav_data, 0)?; // Cue point ID
+    write_u32(wav_data, 0)?; // Type: forward
+    write_u32(wav_data, points.start as u32)?; // Start frame
+    write_u32(
+        wav_data,
+        (points.start + points.length).saturating_sub(1) as u32,
+    )?; // End frame, inclusive
+    write_u32(wav_data, 0)?; // Fraction
+    write_u32(wav_data, 0)?; // Play count: forever
+    Ok(())
+}
+
+/// Checks that a RIFF or chunk size fits the 32-bit size fields of WAV.
+fn wav_size(size: u64) -> Result<u32, AudioError> {
+    u32::try_from(size).map_err(|_| {
+        AudioError::Wav(format!(
+            "{size} bytes is past the 4 GiB limit of a WAV file; use FLAC or a shorter render"
+        ))
+    })
+}
+
+/// Writes a WAV header. 16-bit files use the plain PCM `fmt ` chunk; deeper and float
+/// formats use WAVE_FORMAT_EXTENSIBLE, which readers expect beyond 16 bits.
+///
+/// Streams of unknown length get the maximum chunk sizes, which readers treat as
+/// "until the end of the file". Known lengths must fit the 4 GiB limit of the format.
+fn write_wav_header(
+    wav_data: &mut Vec<u8>,
+    sample_count: Option<usize>,
+    channels: OutputChannels,
+    sample_format: SampleFormat,
+    sample_rate: u32,
+) -> Result<(), AudioError> {
+    let extensible = sample_format != SampleFormat::S16;
+    let fmt_size: u32 = if extensible { 40 } else { 16 };
+    let bits_per_sample = sample_format.bytes_per_sample() as u16 * 8;
+    let block_align = (channels.count() * sample_format.bytes_per_sample()) as u32;
+    let header_size = 4 + (8 + fmt_size) + 8; // Everything after "RIFF" and its size
+    let (riff_size, data_size) = match sample_count {
+        Some(count) => {
+            let data_size = count as u64 * block_align as u64;
+            let riff_size = wav_size(header_size as u64 + data_size + data_size % 2)?;
+            (riff_size, data_size as u32)
+        }
+        None => (WAV_UNKNOWN_SIZE, WAV_UNKNOWN_SIZE),
+    };
+
+    // Write WAV header
+    wav_data.extend_from_slice(b"RIFF");
+    write_u32(wav_data, riff_size)?; // File size - 8
+    wav_data.extend_from_slice(b"WAVE");
+
+    // Write format chunk
+    wav_data.extend_from_slice(b"fmt ");
+    write_u32(wav_data, fmt_size)?; // Chunk size
+    let format_tag = if extensible {
+        WAVE_FORMAT_EXTENSIBLE
+    } else {
+        WAVE_FORMAT_PCM
+    };
+    wav_data.extend_from_slice(&format_tag.to_le_bytes()); // Audio format
+    wav_data.extend_from_slice(&(channels.count() as u16).to_le_bytes()); // Number of channels
+    write_u32(wav_data, sample_rate)?; // Sample rate
+    write_u32(wav_data, sample_rate * block_align)?; // Byte rate
+    wav_data.extend_from_slice(&(block_align as u16).to_le_bytes()); // Block align
+    wav_data.extend_from_slice(&bits_per_sample.to_le_bytes()); // Bits per sample
+
+    if extensible {
+        wav_data.extend_from_slice(&22u16.to_le_bytes()); // Extension size
+        wav_data.extend_from_slice(&bits_per_sample.to_le_bytes()); // Valid bits per sample
+        let channel_mask: u32 = match channels {
+            OutputChannels::Stereo => 0x3,  // Front left and right
+            OutputChannels::Mono(_) => 0x4, // Front center
+        };
+        write_u32(wav_data, channel_mask)?;
+        let sub_format = if sample_format == SampleFormat::F32 {
+            WAVE_FORMAT_IEEE_FLOAT
+        } else {
+            WAVE_FORMAT_PCM
+        };
+        wav_data.extend_from_slice(&sub_format.to_le_bytes());
+        wav_data.extend_from_slice(&SUBTYPE_GUID_TAIL);
+    }
+
+    // Write data chunk header
+    wav_data.extend_from_slice(b"data");
+    write_u32(wav_data, data_size)?; // Chunk size
+    Ok(())
+}
+
+fn write_pcm(
+    output: &mut Vec<u8>,
+    left: &[f32],
+    right: &[f32],
+    channels: OutputChannels,
+    sample_format: SampleFormat,
+    ditherer: &mut Option<Ditherer>,
+) {
+    for sample in interleave(left, right, channels) {
+        sample_format.write_sample(output, sample, ditherer);
+    }
+}
+
+/// Processes a whole-song render before it is encoded.
+fn master(
+    left: &mut Vec<f32>,
+    right: &mut Vec<f32>,
+    channels: OutputChannels,
+    options: &RenderOptions,
+) -> OverloadReport {
+    trim_and_fade(left, right, channels, options);
+    if let Some((gain, _)) = options
+        .loudness
+        .and_then(|target| loudness_gain(left, right, channels, options.sample_rate, target))
+    {
+        apply_gain(left, right, gain);
+    }
+    handle_overload(left, right, channels, options.sample_rate, options.overload)
+}
+
+/// Trims silence from the ends of a render, judging by the output channels, then fades it.
+fn trim_and_fade(
+    left: &mut Vec<f32>,
+    right: &mut Vec<f32>,
+    channels: OutputChannels,
+    options: &RenderOptions,
+) {
+    if let Some(trim) = options.trim {
+        let levels = frame_levels(left, right, channels);
+        let is_sound = |level: &f32| *level >= trim.threshold;
+        let mut start = 0;
+        let mut end = levels.len();
+        if trim.start {
+            start = levels.iter().position(is_sound).unwrap_or(end);
+        }
+        if trim.end {
+            end = levels
+                .iter()
+                .rposition(is_sound)
+                .map_or(start, |last| last + 1);
+        }
+        for channel in [&mut *left, &mut *right] {
+            channel.truncate(end.max(start));
+            channel.drain(..start);
+        }
+    }
+
+    if let Some(fades) = options.fades {
+        let frames = left.len();
+        let fade_in = ((fades.fade_in * options.sample_rate as f64) as usize).min(frames);
+        let fade_out = ((fades.fade_out * options.sample_rate as f64) as usize).min(frames);
+        for index in 0..fade_in {
+            let gain = fade_gain(fades.curve, index as f64 / fade_in as f64);
+            left[index] *= gain;
+            right[index] *= gain;
+        }
+        for index in frames - fade_out..frames {
+            let gain = fade_gain(fades.curve, (frames - 1 - index) as f64 / fade_out as f64);
+            left[index] *= gain;
+            right[index] *= gain;
+        }
+    }
+}
+
+/// Returns the gain `progress` of the way into a fade from silence.
+fn fade_gain(curve: FadeCurve, progress: f64) -> f32 {
+    match curve {
+        FadeCurve::Linear => progress as f32,
+        FadeCurve::Exponential => {
+            ((EXPONENTIAL_FADE_RANGE.powf(progress) - 1.0) / (EXPONENTIAL_FADE_RANGE - 1.0)) as f32
+        }
+    }
+}
+
+/// Returns the highest absolute sample of every frame in the output channels.
+fn frame_levels(left: &[f32], right: &[f32], channels: OutputChannels) -> Vec<f32> {
+    let frames = left.iter().zip(right);
+    match channels {
+        OutputChannels::Stereo => frames.map(|(l, r)| l.abs().max(r.abs())).collect(),
+        OutputChannels::Mono(downmix) => frames.map(|(l, r)| downmix.mix(*l, *r).abs()).collect(),
+    }
+}
+
+/// Keeps a render within full scale as `overload` asks, judging by the output channels.
+///
+/// A mono downmix scales with both sides, so turning both down by a frame's gain turns
+/// the mix down by the same amount.
+fn handle_overload(
+    left: &mut [f32],
+    right: &mut [f32],
+    channels: OutputChannels,
+    sample_rate: u32,
+    overload: Overload,
+) -> OverloadReport {
+    let levels = frame_levels(left, right, channels);
+    let mut report = OverloadReport {
+        affected_samples: 0,
+        total_samples: left.len() * channels.count(),
+        peak: levels.iter().fold(0.0, |peak, &level| peak.max(level)),
+    };
+
+    match (overload, channels) {
+        (Overload::Clip, _) => {
+            report.affected_samples = interleave(left, right, channels)
+                .iter()
+                .filter(|sample| sample.abs() > 1.0)
+                .count();
+        }
+        (Overload::Soft, OutputChannels::Stereo) => {
+            for sample in left.iter_mut().chain(right.iter_mut()) {
+                let shaped = overload::soft_clip(*sample);
+                if shaped != *sample {
+                    *sample = shaped;
+                    report.affected_samples += 1;
+                }
+            }
+        }
+        (Overload::Soft, OutputChannels::Mono(downmix)) => {
+            for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+                let mixed = downmix.mix(*l, *r);
+                let shaped = overload::soft_clip(mixed);
+                if shaped != mixed {
+                    *l *= shaped / mixed;
+                    *r *= shaped / mixed;
+                    report.affected_samples += 1;
+                }
+            }
+        }
+        (Overload::Limit { ceiling, release }, _) => {
+            let lookahead = (sample_rate as f64 * LIMITER_LOOKAHEAD_SECONDS).round() as usize;
+            let gains = overload::limiter_gains(
+                &levels,
+                ceiling,
+                lookahead.max(1),
+                release * sample_rate as f64,
+            );
+            for ((l, r), gain) in left.iter_mut().zip(right.iter_mut()).zip(gains) {
+                if gain < 1.0 {
+                    *l *= gain;
+                    *r *= gain;
+                    report.affected_samples += channels.count();
+                }
+            }
+        }
+    }
+    report
+}
+
+/// Returns the gain in dB that brings the render to `target` without its true peak
+/// passing the ceiling, along with the loudness measured before the gain.
+///
+/// Loudness is measured on the output channels, so a mono downmix is measured as mono.
+/// Renders too short or too quiet to measure get no gain.
+fn loudness_gain(
+    left: &[f32],
+    right: &[f32],
+    channels: OutputChannels,
+    sample_rate: u32,
+    target: Loudness,
+) -> Option<(f64, f64)> {
+    let mono;
+    let planes: Vec<&[f32]> = match channels {
+        OutputChannels::Stereo => vec![left, right],
+        OutputChannels::Mono(_) => {
+            mono = interleave(left, right, channels);
+            vec![&mono]
+        }
+    };
+
+    let measured = loudness::integrated_loudness(&planes, sample_rate)?;
+    let true_peak = 20.0 * (loudness::true_peak(&planes, sample_rate) as f64).log10();
+    let gain = (target.target - measured).min(target.true_peak - true_peak);
+    Some((gain, measured))
+}
+
+fn apply_gain(left: &mut [f32], right: &mut [f32], gain_db: f64) {
+    let factor = 10f64.powf(gain_db / 20.0) as f32;
+    for sample in left.iter_mut().chain(right.iter_mut()) {
+        *sample *= factor;
+    }
+}
+
+/// Converts decibels to the Q7.8 fixed point of Opus gains.
+fn to_q8(db: f64) -> i16 {
+    (db * 256.0).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+/// Converts a stereo render into interleaved samples in the output layout.
+fn interleave(left: &[f32], right: &[f32], channels: OutputChannels) -> Vec<f32> {
+    let frames = left.iter().zip(right);
+    match channels {
+        OutputChannels::Stereo => frames.flat_map(|(l, r)| [*l, *r]).collect(),
+        OutputChannels::Mono(downmix) => frames.map(|(l, r)| downmix.mix(*l, *r)).collect(),
+    }
+}
+
+fn arrange_pcm(
+    mut left: Vec<f32>,
+    right: Vec<f32>,
+    channels: OutputChannels,
+    layout: PcmLayout,
+) -> Vec<f32> {
+    match (channels, layout) {
+        (OutputChannels::Stereo, PcmLayout::Planar) => {
+            left.extend_from_slice(&right);
+            left
+        }
+        // A single channel reads the same in either layout.
+        _ => interleave(&left, &right, channels),
+    }
+}
+
+pub fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 0.99999994) * 32768.0) as i16
+}
+
+/// Converts a sample to a signed integer of `bits` bits, clamped to full scale.
+fn to_int(sample: f32, bits: u32) -> i32 {
+    let scale = 2f64.powi(bits as i32 - 1);
+    (sample as f64 * scale).clamp(-scale, scale - 1.0) as i32
+}
+
+/// Converts a sample like `to_int`, rounding with dither when there is a ditherer.
+fn quantize(sample: f32, bits: u32, ditherer: &mut Option<Ditherer>) -> i32 {
+    let Some(ditherer) = ditherer else {
+        return to_int(sample, bits);
+    };
+    let scale = 2f64.powi(bits as i32 - 1);
+    ditherer.quantize(sample as f64 * scale, -scale, scale - 1.0) as i32
+}
+
+/// Encodes a stereo render losslessly as FLAC, with a Vorbis comment block for the tags.
+pub fn encode_flac(
+    left: &[f32],
+    right: &[f32],
+    channels: OutputChannels,
+    flac_options: &FlacOptions,
+    dither: Dither,
+    sample_rate: u32,
+) -> Result<Vec<u8>, AudioError> {
+    let bits = match flac_options.sample_format {
+        SampleFormat::S16 => 16,
+        SampleFormat::S24 => 24,
+        sample_format => {
+            return Err(AudioError::Flac(format!(
+                "Unsupported sample format: {:?}",
+                sample_format
+            )))
+        }
+    };
+    let mut ditherer = dither.ditherer(channels);
+    let samples: Vec<i32> = interleave(left, right, channels)
+        .into_iter()
+        .map(|sample| quantize(sample, bits, &mut ditherer))
+        .collect();
+
+    let channel_count = channels.count();
+    let frame_count = samples.len() / channel_count;
+    let mut config = flac_config(flac_options.compression_level)?;
+    // flacenc's predictors need more samples than a block shorter than MIN_BLOCK_SIZE can
+    // hold, so a render that short is stored verbatim, in one block of its real length.
+    if frame_count < MIN_BLOCK_SIZE {
+        config.subframe_coding.use_fixed = false;
+        config.subframe_coding.use_lpc = false;
+    }
+    let config = config
+        .into_verified()
+        .map_err(|(_, e)| AudioError::Flac(e.to_string()))?;
+    let mut stream_info = StreamInfo::new(sample_rate as usize, channel_count, bits as usize)
+        .map_err(|e| AudioError::Flac(e.to_string()))?;
+
+    // The MD5 covers the real samples at their stored width. flacenc's own `Context`
+    // also hashes the padding of a short last block, so it is computed here instead.
+    let mut md5 = Md5::new();
+    for sample in &samples {
+        md5.update(&sample.to_le_bytes()[..bits as usize / 8]);
+    }
+    stream_info.set_md5_digest(&md5.finalize().into());
+
+    let block_size = flac_block_size(config.block_size, frame_count);
+
+    // `flacenc::encode_with_fixed_block_size` pads the last block to full size with
+    // leftovers from the one before, so the blocks are encoded here and the last one
+    // is shrunk to the samples that remain.
+    let mut framebuf = FrameBuf::with_size(channel_count, block_size)
+        .map_err(|e| AudioError::Flac(e.to_string()))?;
+    let mut frames = Vec::new();
+    for (frame_number, block) in samples.chunks(block_size * channel_count).enumerate() {
+        framebuf.resize(block.len() / channel_count);
+        framebuf
+            .fill_interleaved(block)
+            .map_err(|e| AudioError::Flac(e.to_string()))?;
+        let frame =
+            flacenc::encode_fixed_size_frame(&config, &framebuf, frame_number, &stream_info)
+                .map_err(|e| AudioError::Flac(format!("{:?}", e)))?;
+        frames.push(frame);
+    }
+
+    let mut stream = Stream::with_stream_info(stream_info);
+    for frame in frames {
+        stream.add_frame(frame);
+    }
+
+    let comment = vorbis_comment(&flac_options.tags);
+    let comment_block = MetadataBlockData::new_unknown(FLAC_VORBIS_COMMENT, &comment)
+        .map_err(|e| AudioError::Flac(e.to_string()))?;
+    stream.add_metadata_block(comment_block);
+
+    let mut sink = ByteSink::new();
+    stream
+        .write(&mut sink)
+        .map_err(|e| AudioError::Flac(e.to_string()))?;
+    let mut flac_data = sink.into_inner();
+    // A render shorter than MIN_BLOCK_SIZE is one short last block, which the block
+    // sizes leave out; like an empty one, it gets the size its blocks would have had, as
+    // the reference encoder writes.
+    let stream_block_size = if frame_count < MIN_BLOCK_SIZE {
+        block_size
+    } else {
+        block_size.min(frame_count)
+    };
+    patch_stream_info(&mut flac_data, stream_block_size);
+    Ok(flac_data)
+}
+
+/// Corrects the STREAMINFO block sizes flacenc derives from the frames it was given,
+/// which must leave out the shorter last block and cover an empty stream.
+fn patch_stream_info(flac_data: &mut [u8], block_size: usize) {
+    // "fLaC", then the 4-byte metadata block header.
+    let stream_info = &mut flac_data[8..];
+    // The minimum, then the maximum block size.
+    stream_info[0..2].copy_from_slice(&(block_size as u16).to_be_bytes());
+    stream_info[2..4].copy_from_slice(&(block_size as u16).to_be_bytes());
+}
+
+/// Picks the block size nearest to `preferred` that leaves a last block of at least
+/// MIN_BLOCK_SIZE samples, or none at all.
+fn flac_block_size(preferred: usize, frame_count: usize) -> usize {
+    (MIN_BLOCK_SIZE..=preferred)
+        .rev()
+        .find(|block_size| {
+            let last_block = frame_count % block_size;
+            last_block == 0 || last_block >= MIN_BLOCK_SIZE
+        })
+        .unwrap_or(preferred)
+}
+
+/// Maps the reference encoder's compression levels onto flacenc's settings: larger
+/// blocks, wider stereo decorrelation and higher prediction orders as the level rises.
+fn flac_config(compression_level: u8) -> Result<flacenc::config::Encoder, AudioError> {
+    // Block size, stereo modes (mid-side only, or all), LPC order (0 for fixed only).
+    let (block_size, stereo, lpc_order) = match compression_level {
+        0 => (1152, None, 0),
+        1 => (1152, Some(false), 0),
+        2 => (1152, Some(true), 0),
+        3 => (4096, None, 6),
+        4 => (4096, Some(false), 8),
+        5 => (4096, Some(true), 8),
+        6 => (4096, Some(true), 10),
+        7 => (4096, Some(true), 12),
+        8 => (4096, Some(true), 16),
+        _ => {
+            return Err(AudioError::Flac(format!(
+                "Invalid compression level: {}",
+                compression_level
+            )))
+        }
+    };
+
+    let mut config = flacenc::config::Encoder::default();
+    config.block_size = block_size;
+    config.stereo_coding.use_midside = stereo.is_some();
+    config.stereo_coding.use_leftside = stereo == Some(true);
+    config.stereo_coding.use_rightside = stereo == Some(true);
+    config.subframe_coding.use_lpc = lpc_order > 0;
+    if lpc_order > 0 {
+        config.subframe_coding.qlpc.lpc_order = lpc_order;
+    } else {
+        config.subframe_coding.fixed.max_order = if stereo == Some(true) { 4 } else { 2 };
+    }
+    Ok(config)
+}
+
+/// Encodes a stereo render as Ogg Vorbis in quality-based VBR mode.
+pub fn encode_vorbis(
+    left: &[f32],
+    right: &[f32],
+    channels: OutputChannels,
+    quality: f32,
+    tags: &[(String, String)],
+    sample_rate: u32,
+) -> Result<Vec<u8>, AudioError> {
+    let planes: Vec<Vec<f32>> = match channels {
+        OutputChannels::Stereo => vec![left.to_vec(), right.to_vec()],
+        OutputChannels::Mono(_) => vec![interleave(left, right, channels)],
+    };
+
+    let sample_rate = NonZeroU32::new(sample_rate)
+        .ok_or_else(|| AudioError::Options("Vorbis needs a sample rate above 0".to_string()))?;
+    let mut builder = VorbisEncoderBuilder::new_with_serial(
+        sample_rate,
+        NonZeroU8::new(planes.len() as u8).unwrap(),
+        Vec::new(),
+        1, // Serial number
+    );
+    builder.bitrate_management_strategy(VorbisBitrateManagementStrategy::QualityVbr {
+        // libvorbis takes the `oggenc` scale divided by ten.
+        target_quality: quality / 10.0,
+    });
+    builder.comment_tags(
+        tags.iter()
+            .map(|(key, value)| (key.as_str(), value.as_str())),
+    )?;
+    let mut encoder = builder.build()?;
+
+    for start in (0..left.len()).step_by(CHUNK_SIZE) {
+        let end = (start + CHUNK_SIZE).min(left.len());
+        let block: Vec<&[f32]> = planes.iter().map(|plane| &plane[start..end]).collect();
+        encoder.encode_audio_block(&block)?;
+    }
+
+    Ok(encoder.finish()?)
+}
+
+/// Encodes a stereo render as MP3 from the same 16-bit samples as an `S16` WAV.
+///
+/// Tags go into an ID3v2 tag ahead of the audio, and the LAME tag records the exact
+/// length so decoders can drop the encoder delay and padding.
+pub fn encode_mp3(
+    left: &[f32],
+    right: &[f32],
+    channels: OutputChannels,
+    mp3_options: &Mp3Options,
+    sample_rate: u32,
+) -> Result<Vec<u8>, AudioError> {
+    let samples: Vec<i16> = interleave(left, right, channels)
+        .into_iter()
+        .map(to_i16)
+        .collect();
+    let channel_count = channels.count();
+
+    let mut builder = mp3lame_encoder::Builder::new()
+        .ok_or_else(|| AudioError::Mp3("Failed to allocate the encoder".to_string()))?;
+    builder.set_sample_rate(sample_rate).map_err(mp3_error)?;
+    builder
+        .set_num_channels(channel_count as u8)
+        .map_err(mp3_error)?;
+    builder
+        .set_mode(match channels {
+            OutputChannels::Stereo => mp3lame_encoder::Mode::JointStereo,
+            OutputChannels::Mono(_) => mp3lame_encoder::Mode::Mono,
+        })
+        .map_err(mp3_error)?;
+    match mp3_options.bitrate {
+        Mp3Bitrate::Cbr(kbps) => builder.set_brate(mp3_bitrate(kbps)?).map_err(mp3_error)?,
+        Mp3Bitrate::Vbr(level) => {
+            builder.set_vbr_mode(VbrMode::Mtrh).map_err(mp3_error)?;
+            builder
+                .set_vbr_quality(mp3_quality(level)?)
+                .map_err(mp3_error)?;
+        }
+    }
+
+    let mut id3_tag = Id3Tag {
+        title: &[],
+        artist: &[],
+        album: &[],
+        album_art: &[],
+        year: &[],
+        comment: &[],
+    };
+    let id3_fields = mp3_options.id3_fields()?;
+    for (name, text) in &id3_fields {
+        let field = match *name {
+            "TITLE" => &mut id3_tag.title,
+            "ARTIST" => &mut id3_tag.artist,
+            "ALBUM" => &mut id3_tag.album,
+            "YEAR" => &mut id3_tag.year,
+            "COMMENT" => &mut id3_tag.comment,
+            _ => unreachable!("ID3 fields are named after MP3_TAGS"),
+        };
+        *field = text;
+    }
+    builder
+        .set_id3_tag(id3_tag)
+        .map_err(|e| AudioError::Mp3(format!("{:?}", e)))?;
+
+    let mut encoder = builder.build().map_err(mp3_error)?;
+    let mut mp3_data = Vec::new();
+    for chunk in samples.chunks(CHUNK_SIZE * channel_count) {
+        mp3_data.reserve(mp3lame_encoder::max_required_buffer_size(chunk.len()));
+        match channels {
+            OutputChannels::Stereo => encoder.encode_to_vec(InterleavedPcm(chunk), &mut mp3_data),
+            OutputChannels::Mono(_) => encoder.encode_to_vec(MonoPcm(chunk), &mut mp3_data),
+        }
+        .map_err(mp3_error)?;
+    }
+    mp3_data.reserve(mp3lame_encoder::max_required_buffer_size(0));
+    encoder
+        .flush_to_vec::<FlushGap>(&mut mp3_data)
+        .map_err(mp3_error)?;
+
+    // The first frame after the ID3v2 tag was a placeholder for the LAME tag.
+    if encoder.is_lame_tag_written() {
+        let mut lame_tag = Vec::with_capacity(encoder.lame_tag_size());
+        if encoder.lame_tag_encode_to_vec(&mut lame_tag).is_some() {
+            let start = encoder.id3v2_tag_size();
+            mp3_data[start..start + lame_tag.len()].copy_from_slice(&lame_tag);
+        }
+    }
+
+    Ok(mp3_data)
+}
+
+fn mp3_error(error: impl std::fmt::Display) -> AudioError {
+    AudioError::Mp3(error.to_string())
+}
+
+fn mp3_bitrate(kbps: u16) -> Result<LameBitrate, AudioError> {
+    Ok(match kbps {
+        8 => LameBitrate::Kbps8,
+        16 => LameBitrate::Kbps16,
+        24 => LameBitrate::Kbps24,
+        32 => LameBitrate::Kbps32,
+        40 => LameBitrate::Kbps40,
+        48 => LameBitrate::Kbps48,
+        64 => LameBitrate::Kbps64,
+        80 => LameBitrate::Kbps80,
+        96 => LameBitrate::Kbps96,
+        112 => LameBitrate::Kbps112,
+        128 => LameBitrate::Kbps128,
+        160 => LameBitrate::Kbps160,
+        192 => LameBitrate::Kbps192,
+        224 => LameBitrate::Kbps224,
+        256 => LameBitrate::Kbps256,
+        320 => LameBitrate::Kbps320,
+        _ => return Err(AudioError::Mp3(format!("Unsupported bitrate: {}", kbps))),
+    })
+}
+
+fn mp3_quality(level: u8) -> Result<Quality, AudioError> {
+    Ok(match level {
+        0 => Quality::Best,
+        1 => Quality::SecondBest,
+        2 => Quality::NearBest,
+        3 => Quality::VeryNice,
+        4 => Quality::Nice,
+        5 => Quality::Good,
+        6 => Quality::Decent,
+        7 => Quality::Ok,
+        8 => Quality::SecondWorst,
+        9 => Quality::Worst,
+        _ => return Err(AudioError::Mp3(format!("Unsupported VBR level: {}", level))),
+    })
+}
+
+pub fn wav_to_opus_ogg(
+    wav_data: &[u8],
+    channels: OutputChannels,
+    bitrate: OpusBitrate,
+    tags: &[(String, String)],
+) -> Result<Vec<u8>, AudioError> {
+    let wav_header = parse_wav_header(wav_data)?;
+    let samples = decode_wav_samples(wav_data, &wav_header);
+
+    let samples: Vec<i16> = match (wav_header.channels, channels) {
+        (1, OutputChannels::Stereo) => samples.iter().flat_map(|&s| [s, s]).map(to_i16).collect(),
+        (1, OutputChannels::Mono(_)) | (2, OutputChannels::Stereo) => {
+            samples.into_iter().map(to_i16).collect()
+        }
+        (2, OutputChannels::Mono(downmix)) => samples
+            .chunks_exact(2)
+            .map(|frame| to_i16(downmix.mix(frame[0], frame[1])))
+            .collect(),
+        (count, _) => {
+            return Err(AudioError::WavParsing(format!(
+                "Unsupported channel count: {}",
+                count
+            )))
+        }
+    };
+
+    let stereo = matches!(channels, OutputChannels::Stereo);
+    let mut encoder = OpusOggEncoder::new(wav_header.sample_rate, stereo, bitrate, 0, tags)?;
+    encoder.push(&samples)?;
+    encoder.finish()
+}
+
+/// Encodes interleaved 16-bit samples into an Ogg Opus stream, a frame at a time.
+pub struct OpusOggEncoder {
+    encoder: Encoder,
+    packet_writer: PacketWriter<'static, Vec<u8>>,
+    // Rates Opus cannot take directly are resampled to its native 48kHz.
+    resampler: Option<Resampler>,
+    channels: usize,
+    opus_rate: u32,
+    frame_size: usize,
+    // Samples that do not fill a whole frame yet.
+    pending: Vec<i16>,
+    // Encoder delay at the Opus rate, which decoders discard as pre-skip.
+    lookahead: usize,
+    // Frames of real audio handed to the encoder, at the Opus rate.
+    input_frames: u64,
+    granule_position: u64,
+}
+
+impl OpusOggEncoder {
+    pub fn new(
+        sample_rate: u32,
+        stereo: bool,
+        bitrate: OpusBitrate,
+        output_gain: i16,
+        tags: &[(String, String)],
+    ) -> Result<Self, AudioError> {
+        let opus_rate = if OPUS_SAMPLE_RATES.contains(&sample_rate) {
+            sample_rate
+        } else {
+            OPUS_GRANULE_RATE
+        };
+
+        let channels = if stereo {
+            Channels::Stereo
+        } else {
+            Channels::Mono
+        };
+        let mut encoder = Encoder::new(opus_rate, channels, Application::Audio)?;
+
+        match bitrate {
+            OpusBitrate::Auto => encoder.set_bitrate(Bitrate::Auto)?,
+            OpusBitrate::Max => encoder.set_bitrate(Bitrate::Max)?,
+            OpusBitrate::Bits(bits) => encoder.set_bitrate(Bitrate::Bits(bits))?,
+        }
+
+        let lookahead = encoder.get_lookahead()? as usize;
+        let pre_skip = lookahead as u64 * granule_scale(opus_rate);
+
+        let mut packet_writer = PacketWriter::new(Vec::new());
+
+        // Write Opus header
+        let opus_header = create_opus_header(channels, sample_rate, pre_skip as u16, output_gain);
+        packet_writer.write_packet(
+            opus_header,
+            1, // Serial number
+            PacketWriteEndInfo::EndPage,
+            0, // Granule position
+        )?;
+
+        // Write Opus comment header
+        let opus_comment = create_opus_comment(tags);
+        packet_writer.write_packet(
+            opus_comment,
+            1, // Serial number
+            PacketWriteEndInfo::EndPage,
+            0, // Granule position
+        )?;
+
+        Ok(Self {
+            encoder,
+            packet_writer,
+            resampler: (opus_rate != sample_rate)
+                .then(|| Resampler::new(channels as usize, sample_rate, opus_rate)),
+            channels: channels as usize,
+            opus_rate,
+            frame_size: opus_rate as usize / 50, // 20ms
+            pending: Vec::new(),
+            lookahead,
+            input_frames: 0,
+            granule_position: 0,
+        })
+    }
+
+    /// Encodes every whole frame available, keeping the remainder for the next call.
+    pub fn push(&mut self, samples: &[i16]) -> Result<(), AudioError> {
+        match &mut self.resampler {
+            Some(resampler) => {
+                let float_samples: Vec<f32> = samples.iter().map(|&s| s as f32 / 32768.0).collect();
+                let resampled = resampler.process(&float_samples);
+                self.queue(&resampled);
+            }
+            None => {
+                self.input_frames += (samples.len() / self.channels) as u64;
+                self.pending.extend_from_slice(samples);
+            }
+        }
+
+        let frame_length = self.frame_size * self.channels;
+        let whole_frames = self.pending.len() / frame_length * frame_length;
+        let pending = std::mem::take(&mut self.pending);
+        for chunk in pending[..whole_frames].chunks(frame_length) {
+            self.encode_frame(chunk)?;
+        }
+        self.pending = pending[whole_frames..].to_vec();
+        Ok(())
+    }
+
+    /// Takes the Ogg pages completed so far.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(self.packet_writer.inner_mut())
+    }
+
+    /// Encodes the remaining samples and returns the rest of the stream.
+    pub fn finish(mut self) -> Result<Vec<u8>, AudioError> {
+        if let Some(resampler) = &mut self.resampler {
+            let resampled = resampler.finish();
+            self.queue(&resampled);
+        }
+
+        // The encoder holds back `lookahead` frames, so keep feeding it silence until
+        // the last real sample has come out the other end. The encoder also only takes
+        // whole frames, so the tail is rounded up to one.
+        let encoded_frames = self.granule_position / granule_scale(self.opus_rate);
+        let needed_frames = self.input_frames + self.lookahead as u64 - encoded_frames;
+        let frame_count = (needed_frames as usize).div_ceil(self.frame_size).max(1);
+        let pending = pad_chunk(
+            &std::mem::take(&mut self.pending),
+            frame_count * self.frame_size,
+            self.channels,
+        );
+
+        let frame_length = self.frame_size * self.channels;
+        for (index, chunk) in pending.chunks(frame_length).enumerate() {
+            if index + 1 < frame_count {
+                self.encode_frame(chunk)?;
+            } else {
+                // The last page's granule position tells decoders where the real audio
+                // ends, so they trim the padding.
+                let end_position =
+                    (self.lookahead as u64 + self.input_frames) * granule_scale(self.opus_rate);
+                self.write_frame(chunk, PacketWriteEndInfo::EndStream, end_position)?;
+            }
+        }
+
+        Ok(self.packet_writer.into_inner())
+    }
+
+    fn queue(&mut self, samples: &[f32]) {
+        self.input_frames += (samples.len() / self.channels) as u64;
+        self.pending.extend(samples.iter().map(|&s| to_i16(s)));
+    }
+
+    fn encode_frame(&mut self, frame: &[i16]) -> Result<(), AudioError> {
+        let granule_position = self.granule_position + FRAME_SIZE as u64;
+        self.write_frame(frame, PacketWriteEndInfo::NormalPacket, granule_position)
+    }
+
+    fn write_frame(
+        &mut self,
+        frame: &[i16],
+        end_info: PacketWriteEndInfo,
+        granule_position: u64,
+    ) -> Result<(), AudioError> {
+        let mut packet = vec![0u8; MAX_PACKET_SIZE];
+        let packet_len = self.encoder.encode(frame, &mut packet)?;
+        packet.truncate(packet_len);
+
+        self.granule_position = self.granule_position.saturating_add(FRAME_SIZE as u64);
+
+        self.packet_writer.write_packet(
+            packet,
+            1, // Serial number
+            end_info,
+            granule_position,
+        )?;
+        Ok(())
+    }
+}
+
+/// Number of 48kHz granule positions per sample at the given Opus rate.
+fn granule_scale(opus_rate: u32) -> u64 {
+    (OPUS_GRANULE_RATE / opus_rate) as u64
+}
+
+fn pad_chunk(chunk: &[i16], frame_size: usize, channels: usize) -> Vec<i16> {
+    let min_length = frame_size * channels;
+    let padding_size = min_length.saturating_sub(chunk.len());
+    if padding_size < 1 {
+        return chunk.to_vec();
+    }
+    let mut padded = chunk.to_vec();
+    padded.extend(std::iter::repeat_n(0i16, padding_size));
+    padded
+}
+
+fn create_opus_header(
+    channels: Channels,
+    sample_rate: u32,
+    pre_skip: u16,
+    output_gain: i16,
+) -> Vec<u8> {
+    vec![
+        b'O',
+        b'p',
+        b'u',
+        b's',
+        b'H',
+        b'e',
+        b'a',
+        b'd', // Magic signature
+        1,    // Version
+        channels as u8,
+        pre_skip.to_le_bytes()[0],
+        pre_skip.to_le_bytes()[1], // Pre-skip, in 48kHz samples
+        sample_rate.to_le_bytes()[0],
+        sample_rate.to_le_bytes()[1],
+        sample_rate.to_le_bytes()[2],
+        sample_rate.to_le_bytes()[3],
+        output_gain.to_le_bytes()[0],
+        output_gain.to_le_bytes()[1], // Output gain, in Q7.8 dB
+        0,                            // Channel mapping family (0 for mono/stereo)
+    ]
+}
+
+fn create_opus_comment(tags: &[(String, String)]) -> Vec<u8> {
+    let mut comment = b"OpusTags".to_vec(); // Magic signature
+    comment.extend(vorbis_comment(tags));
+    comment
+}
+
+/// Builds a Vorbis comment body, as used by both Opus and FLAC.
+fn vorbis_comment(tags: &[(String, String)]) -> Vec<u8> {
+    let vendor_string = b"midirenderer";
+    let mut comment = Vec::new();
+    comment.extend_from_slice(&(vendor_string.len() as u32).to_le_bytes());
+    comment.extend_from_slice(vendor_string);
+    comment.extend_from_slice(&(tags.len() as u32).to_le_bytes()); // User comment list length
+    for (key, value) in tags {
+        let field = format!("{}={}", key, value);
+        comment.extend_from_slice(&(field.len() as u32).to_le_bytes());
+        comment.extend_from_slice(field.as_bytes());
+    }
+    comment
+}
+
+fn peak(left: &[f32], right: &[f32]) -> f32 {
+    left.iter()
+        .chain(right.iter())
+        .fold(0.0, |peak, sample| peak.max(sample.abs()))
 }
 
 fn write_u32(output: &mut Vec<u8>, value: u32) -> Result<(), AudioError> {
     output.write_all(&value.to_le_bytes())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{midi_file, sine_sound_font, sound_font_file, MONO_SAMPLE};
+    use ogg::PacketReader;
+
+    const SECONDS: usize = 10;
+
+    /// Deterministic broadband noise, which keeps the encoder near its target bitrate.
+    fn noise(frames: usize) -> (Vec<f32>, Vec<f32>) {
+        let mut state = 0x1234_5678u32;
+        let mut next = || {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+        };
+        let left: Vec<f32> = (0..frames).map(|_| next()).collect();
+        let right: Vec<f32> = (0..frames).map(|_| next()).collect();
+        (left, right)
+    }
+
+    fn noise_wav() -> Vec<u8> {
+        let (left, right) = noise(DEFAULT_SAMPLE_RATE as usize * SECONDS);
+        encode_wav(
+            &left,
+            &right,
+            OutputChannels::Stereo,
+            SampleFormat::S16,
+            Dither::None,
+            DEFAULT_SAMPLE_RATE,
+        )
+        .unwrap()
+    }
+
+    fn read_packets(ogg_data: &[u8]) -> Vec<ogg::Packet> {
+        let mut reader = PacketReader::new(Cursor::new(ogg_data));
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    /// Audio packets of an Ogg Opus stream, without the two header packets.
+    fn audio_packets(ogg_data: &[u8]) -> Vec<Vec<u8>> {
+        read_packets(ogg_data)
+            .into_iter()
+            .skip(2)
+            .map(|packet| packet.data)
+            .collect()
+    }
+
+    fn pre_skip_of(ogg_data: &[u8]) -> u64 {
+        let header = &read_packets(ogg_data)[0].data;
+        u16::from_le_bytes([header[10], header[11]]) as u64
+    }
+
+    /// Decodes a stereo Ogg Opus stream at 48kHz the way a player would, dropping the
+    /// pre-skip and trimming to the final granule position.
+    fn decode_stereo(ogg_data: &[u8]) -> Vec<i16> {
+        let packets = read_packets(ogg_data);
+        let pre_skip = pre_skip_of(ogg_data) as usize;
+        let end_position = packets.last().unwrap().absgp_page() as usize;
+
+        let mut decoder = opus::Decoder::new(OPUS_GRANULE_RATE, Channels::Stereo).unwrap();
+        let mut decoded = Vec::new();
+        let mut pcm = vec![0i16; FRAME_SIZE * 2];
+        for packet in &packets[2..] {
+            let frames = decoder.decode(&packet.data, &mut pcm, false).unwrap();
+            decoded.extend_from_slice(&pcm[..frames * 2]);
+        }
+        decoded.truncate(end_position * 2);
+        decoded.split_off(pre_skip * 2)
+    }
+
+    fn bitrate_of(ogg_data: &[u8]) -> f64 {
+        let bytes: usize = audio_packets(ogg_data).iter().map(Vec::len).sum();
+        bytes as f64 * 8.0 / SECONDS as f64
+    }
+
+    #[test]
+    fn opus_bitrate_follows_requested_bitrate() {
+        let wav_data = noise_wav();
+        for requested in [64_000, 128_000, 192_000] {
+            let ogg_data = wav_to_opus_ogg(
+                &wav_data,
+                OutputChannels::Stereo,
+                OpusBitrate::Bits(requested),
+                &[],
+            )
+            .unwrap();
+            let actual = bitrate_of(&ogg_data);
+            let error = (actual - requested as f64).abs() / requested as f64;
+            assert!(
+                error < 0.1,
+                "requested {requested} bit/s, got {actual:.0} bit/s"
+            );
+        }
+    }
+
+    #[test]
+    fn opus_packets_carry_only_encoded_bytes() {
+        let ogg_data = wav_to_opus_ogg(
+            &noise_wav(),
+            OutputChannels::Stereo,
+            OpusBitrate::Bits(64_000),
+            &[],
+        )
+        .unwrap();
+        let mut decoder = opus::Decoder::new(DEFAULT_SAMPLE_RATE, Channels::Stereo).unwrap();
+        let mut pcm = vec![0i16; FRAME_SIZE * 2];
+        for packet in audio_packets(&ogg_data) {
+            // A CBR-sized buffer would leave the packets padded to a fixed length.
+            assert!(packet.len() < 640);
+            assert_eq!(
+                decoder.decode(&packet, &mut pcm, false).unwrap(),
+                FRAME_SIZE
+            );
+        }
+    }
+
+    #[test]
+    fn opus_pre_skip_is_encoder_lookahead() {
+        for sample_rate in [16000, 44100, 48000] {
+            let silence = vec![0.0; 4800];
+            let wav_data = encode_wav(
+                &silence,
+                &silence,
+                OutputChannels::Stereo,
+                SampleFormat::S16,
+                Dither::None,
+                sample_rate,
+            )
+            .unwrap();
+            let ogg_data =
+                wav_to_opus_ogg(&wav_data, OutputChannels::Stereo, OpusBitrate::Auto, &[]).unwrap();
+
+            let opus_rate = if sample_rate == 44100 {
+                48000
+            } else {
+                sample_rate
+            };
+            let mut encoder =
+                Encoder::new(opus_rate, Channels::Stereo, Application::Audio).unwrap();
+            let lookahead = encoder.get_lookahead().unwrap() as u64;
+            assert_eq!(pre_skip_of(&ogg_data), lookahead * granule_scale(opus_rate));
+        }
+    }
+
+    #[test]
+    fn opus_end_position_counts_only_rendered_samples() {
+        // Lengths that do not fill the last frame, at native and resampled rates.
+        for (sample_rate, frames, expected) in [
+            (48000, 48_123, 48_123),
+            (16000, 16_001, 48_003),
+            (44100, 44_100, 48_000),
+            (48000, 10, 10),
+        ] {
+            let silence = vec![0.0; frames];
+            let wav_data = encode_wav(
+                &silence,
+                &silence,
+                OutputChannels::Stereo,
+                SampleFormat::S16,
+                Dither::None,
+                sample_rate,
+            )
+            .unwrap();
+            let ogg_data =
+                wav_to_opus_ogg(&wav_data, OutputChannels::Stereo, OpusBitrate::Auto, &[]).unwrap();
+
+            let end_position = read_packets(&ogg_data).last().unwrap().absgp_page();
+            assert_eq!(end_position - pre_skip_of(&ogg_data), expected);
+        }
+    }
+
+    #[test]
+    fn opus_decodes_in_step_with_wav() {
+        // Half a second of silence, then a tone through to the last sample.
+        let frames = 30_017;
+        let onset = 24_000;
+        let tone: Vec<f32> = (0..frames)
+            .map(|i| {
+                if i < onset {
+                    0.0
+                } else {
+                    0.5 * (2.0 * std::f32::consts::PI * 440.0 * i as f32 / 48000.0).sin()
+                }
+            })
+            .collect();
+        let wav_data = encode_wav(
+            &tone,
+            &tone,
+            OutputChannels::Stereo,
+            SampleFormat::S16,
+            Dither::None,
+            DEFAULT_SAMPLE_RATE,
+        )
+        .unwrap();
+        let ogg_data =
+            wav_to_opus_ogg(&wav_data, OutputChannels::Stereo, OpusBitrate::Max, &[]).unwrap();
+
+        let decoded = decode_stereo(&ogg_data);
+        assert_eq!(decoded.len(), frames * 2);
+
+        let first_loud = |samples: &[i16]| samples.iter().position(|s| s.abs() > 8192).unwrap();
+        let expected = tone.iter().position(|s| s.abs() > 0.25).unwrap();
+        let actual = first_loud(&decoded) / 2;
+        assert!(
+            actual.abs_diff(expected) <= 4,
+            "tone starts at {expected} in the WAV and {actual} in the Opus"
+        );
+        // The tone is still playing at the very end, so nothing was cut off.
+        assert!(decoded[decoded.len() - 200..]
+            .iter()
+            .any(|s| s.abs() > 8192));
+    }
+
+    #[test]
+    fn mono_wav_applies_downmix() {
+        let left = [0.5, 0.0];
+        let right = [0.0, 0.25];
+        for (downmix, expected) in [
+            (Downmix::Average, [0.25, 0.125]),
+            (Downmix::PanLaw, [0.5 * 0.70710677, 0.25 * 0.70710677]),
+            (Downmix::Left, [0.5, 0.0]),
+            (Downmix::Right, [0.0, 0.25]),
+        ] {
+            let wav_data = encode_wav(
+                &left,
+                &right,
+                OutputChannels::Mono(downmix),
+                SampleFormat::S16,
+                Dither::None,
+                48000,
+            )
+            .unwrap();
+            let header = parse_wav_header(&wav_data).unwrap();
+            assert_eq!(header.channels, 1);
+            let samples: Vec<i16> = wav_data[header.data_start..]
+                .chunks_exact(2)
+                .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+                .collect();
+            assert_eq!(samples, expected.map(to_i16));
+        }
+    }
+
+    #[test]
+    fn mono_opus_keeps_right_channel() {
+        let frames = 48000;
+        let silence = vec![0.0; frames];
+        let tone: Vec<f32> = (0..frames)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 440.0 * i as f32 / 48000.0).sin())
+            .collect();
+        let wav_data = encode_wav(
+            &silence,
+            &tone,
+            OutputChannels::Stereo,
+            SampleFormat::S16,
+            Dither::None,
+            48000,
+        )
+        .unwrap();
+
+        let level = |downmix| {
+            let channels = OutputChannels::Mono(downmix);
+            let ogg_data = wav_to_opus_ogg(&wav_data, channels, OpusBitrate::Auto, &[]).unwrap();
+            let mut decoder = opus::Decoder::new(OPUS_GRANULE_RATE, Channels::Mono).unwrap();
+            let mut pcm = vec![0i16; FRAME_SIZE];
+            audio_packets(&ogg_data)
+                .iter()
+                .map(|packet| {
+                    let frames = decoder.decode(packet, &mut pcm, false).unwrap();
+                    pcm[..frames]
+                        .iter()
+                        .map(|s| s.unsigned_abs())
+                        .max()
+                        .unwrap()
+                })
+                .max()
+                .unwrap()
+        };
+        assert!(level(Downmix::Average) > 6000);
+        assert!(level(Downmix::Right) > 12000);
+        assert!(level(Downmix::Left) < 100);
+    }
+
+    #[test]
+    fn wav_sample_formats_round_trip() {
+        let left = [0.5, -0.25, 1.5];
+        let right = [-1.0, 0.125, -0.75];
+        for (sample_format, bits, tolerance) in [
+            (SampleFormat::S16, 16, 1.0 / 32768.0),
+            (SampleFormat::S24, 24, 1.0 / 8388608.0),
+            (SampleFormat::S32, 32, 1.0 / 2147483648.0),
+            (SampleFormat::F32, 32, 0.0),
+        ] {
+            let wav_data = encode_wav(
+                &left,
+                &right,
+                OutputChannels::Stereo,
+                sample_format,
+                Dither::None,
+                44100,
+            )
+            .unwrap();
+            let header = parse_wav_header(&wav_data).unwrap();
+            assert_eq!(header.channels, 2);
+            assert_eq!(header.sample_rate, 44100);
+            assert_eq!(header.bits_per_sample, bits);
+            assert_eq!(header.is_float, sample_format == SampleFormat::F32);
+
+            let riff_size = u32::from_le_bytes(wav_data[4..8].try_into().unwrap()) as usize;
+            assert_eq!(riff_size, wav_data.len() - 8);
+
+            let decoded = decode_wav_samples(&wav_data, &header);
+            let expected = interleave(&left, &right, OutputChannels::Stereo);
+            for (index, (actual, expected)) in decoded.iter().zip(&expected).enumerate() {
+                // Only the float format keeps the sample above full scale.
+                let expected = if sample_format == SampleFormat::F32 {
+                    *expected
+                } else {
+                    expected.min(1.0)
+                };
+                assert!(
+                    (actual - expected).abs() <= tolerance,
+                    "{sample_format:?} sample {index}: {actual} != {expected}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn wav_odd_data_chunk_is_padded() {
+        let samples = [0.0; 3];
+        let channels = OutputChannels::Mono(Downmix::Average);
+        let wav_data = encode_wav(
+            &samples,
+            &samples,
+            channels,
+            SampleFormat::S24,
+            Dither::None,
+            48000,
+        )
+        .unwrap();
+        assert_eq!(wav_data.len() % 2, 0);
+        let header = parse_wav_header(&wav_data).unwrap();
+        assert_eq!(header.data_end - header.data_start, 9);
+    }
+
+    #[test]
+    fn wav_header_skips_unknown_chunks() {
+        let samples = [0.25, -0.25];
+        let wav_data = encode_wav(
+            &samples,
+            &samples,
+            OutputChannels::Stereo,
+            SampleFormat::S16,
+            Dither::None,
+            48000,
+        )
+        .unwrap();
+
+        // Put an odd-sized LIST chunk between `fmt ` and `data`.
+        let mut with_list = wav_data[..36].to_vec();
+        with_list.extend_from_slice(b"LIST");
+        with_list.extend_from_slice(&3u32.to_le_bytes());
+        with_list.extend_from_slice(b"abc\0");
+        with_list.extend_from_slice(&wav_data[36..]);
+
+        let header = parse_wav_header(&with_list).unwrap();
+        assert_eq!(
+            decode_wav_samples(&with_list, &header),
+            decode_wav_samples(&wav_data, &parse_wav_header(&wav_data).unwrap())
+        );
+    }
+
+    #[test]
+    fn opus_accepts_float_wav() {
+        let wav_data = noise_wav();
+        let header = parse_wav_header(&wav_data).unwrap();
+        let samples = decode_wav_samples(&wav_data, &header);
+        let left: Vec<f32> = samples.iter().step_by(2).copied().collect();
+        let right: Vec<f32> = samples.iter().skip(1).step_by(2).copied().collect();
+        let float_wav = encode_wav(
+            &left,
+            &right,
+            OutputChannels::Stereo,
+            SampleFormat::F32,
+            Dither::None,
+            DEFAULT_SAMPLE_RATE,
+        )
+        .unwrap();
+
+        let bitrate = OpusBitrate::Bits(64_000);
+        assert_eq!(
+            wav_to_opus_ogg(&float_wav, OutputChannels::Stereo, bitrate, &[]).unwrap(),
+            wav_to_opus_ogg(&wav_data, OutputChannels::Stereo, bitrate, &[]).unwrap()
+        );
+    }
+
+    fn decode_flac(flac_data: &[u8]) -> (claxon::FlacReader<Cursor<&[u8]>>, Vec<i32>) {
+        let mut reader = claxon::FlacReader::new(Cursor::new(flac_data)).unwrap();
+        let samples = reader.samples().map(Result::unwrap).collect();
+        (reader, samples)
+    }
+
+    #[test]
+    fn flac_is_lossless_at_every_level() {
+        // None are multiples of a block size, so the last block is a short one,
+        // down to fewer samples than flacenc accepts as a block size.
+        for (frames, sample_format, bits) in [
+            (10_000, SampleFormat::S16, 16),
+            (10_000, SampleFormat::S24, 24),
+            (4_106, SampleFormat::S16, 16),
+        ] {
+            let (left, right) = noise(frames);
+            let expected: Vec<i32> = interleave(&left, &right, OutputChannels::Stereo)
+                .into_iter()
+                .map(|sample| to_int(sample, bits))
+                .collect();
+            for compression_level in 0..=8 {
+                let flac_options = FlacOptions {
+                    sample_format,
+                    compression_level,
+                    tags: Vec::new(),
+                };
+                let flac_data = encode_flac(
+                    &left,
+                    &right,
+                    OutputChannels::Stereo,
+                    &flac_options,
+                    Dither::None,
+                    DEFAULT_SAMPLE_RATE,
+                )
+                .unwrap();
+
+                let (reader, decoded) = decode_flac(&flac_data);
+                let info = reader.streaminfo();
+                assert_eq!(info.bits_per_sample, bits);
+                assert_eq!(info.channels, 2);
+                assert_eq!(info.sample_rate, DEFAULT_SAMPLE_RATE);
+                assert_eq!(info.samples, Some(frames as u64));
+                assert_eq!(info.min_block_size, info.max_block_size);
+                assert!(
+                    decoded == expected,
+                    "level {compression_level} is not lossless"
+                );
+
+                let mut md5 = Md5::new();
+                for sample in &expected {
+                    md5.update(&sample.to_le_bytes()[..bits as usize / 8]);
+                }
+                assert_eq!(info.md5sum, <[u8; 16]>::from(md5.finalize()));
+            }
+        }
+    }
+
+    #[test]
+    fn flac_carries_tags() {
+        let samples = [0.0; 4800];
+        let flac_options = FlacOptions {
+            sample_format: SampleFormat::S16,
+            compression_level: 5,
+            tags: vec![
+                ("TITLE".to_string(), "Für Elise".to_string()),
+                ("ARTIST".to_string(), "Beethoven".to_string()),
+            ],
+        };
+        let channels = OutputChannels::Mono(Downmix::Average);
+        let flac_data = encode_flac(
+            &samples,
+            &samples,
+            channels,
+            &flac_options,
+            Dither::None,
+            44100,
+        )
+        .unwrap();
+
+        let (reader, decoded) = decode_flac(&flac_data);
+        assert_eq!(reader.vendor(), Some("midirenderer"));
+        let tags: Vec<(&str, &str)> = reader.tags().collect();
+        assert_eq!(tags, [("TITLE", "Für Elise"), ("ARTIST", "Beethoven")]);
+        assert_eq!(reader.streaminfo().channels, 1);
+        assert_eq!(decoded.len(), 4800);
+    }
+
+    #[test]
+    fn flac_rejects_unsupported_settings() {
+        let samples = [0.0; 16];
+        for (sample_format, compression_level) in [(SampleFormat::F32, 5), (SampleFormat::S16, 9)] {
+            let flac_options = FlacOptions {
+                sample_format,
+                compression_level,
+                tags: Vec::new(),
+            };
+            let result = encode_flac(
+                &samples,
+                &samples,
+                OutputChannels::Stereo,
+                &flac_options,
+                Dither::None,
+                48000,
+            );
+            assert!(matches!(result, Err(AudioError::Flac(_))));
+        }
+    }
+
+    #[test]
+    fn dither_is_seeded_and_shared_by_wav_and_flac() {
+        // A few steps of 16-bit audio, where truncation distorts the most.
+        let (left, right) = noise(4800);
+        let left: Vec<f32> = left.iter().map(|sample| sample / 4096.0).collect();
+        let right: Vec<f32> = right.iter().map(|sample| sample / 4096.0).collect();
+        let channels = OutputChannels::Stereo;
+        let wav =
+            |dither| encode_wav(&left, &right, channels, SampleFormat::S16, dither, 48000).unwrap();
+
+        let tpdf = wav(Dither::Tpdf { seed: 1 });
+        assert_eq!(tpdf, wav(Dither::Tpdf { seed: 1 }));
+        assert_ne!(tpdf, wav(Dither::Tpdf { seed: 2 }));
+        assert_ne!(tpdf, wav(Dither::Shaped { seed: 1 }));
+        assert_ne!(tpdf, wav(Dither::None));
+
+        let flac_options = FlacOptions {
+            sample_format: SampleFormat::S16,
+            compression_level: 5,
+            tags: Vec::new(),
+        };
+        let dither = Dither::Shaped { seed: 1 };
+        let flac_data = encode_flac(&left, &right, channels, &flac_options, dither, 48000).unwrap();
+        let wav_data = wav(dither);
+        let header = parse_wav_header(&wav_data).unwrap();
+        let expected: Vec<i32> = decode_wav_samples(&wav_data, &header)
+            .iter()
+            .map(|sample| (sample * 32768.0) as i32)
+            .collect();
+        assert_eq!(decode_flac(&flac_data).1, expected);
+    }
+
+    #[test]
+    fn flac_keeps_length_of_very_short_renders() {
+        let (left, right) = noise(10);
+        let flac_options = FlacOptions {
+            sample_format: SampleFormat::S16,
+            compression_level: 5,
+            tags: Vec::new(),
+        };
+        let flac_data = encode_flac(
+            &left,
+            &right,
+            OutputChannels::Stereo,
+            &flac_options,
+            Dither::None,
+            48000,
+        )
+        .unwrap();
+
+        let (reader, decoded) = decode_flac(&flac_data);
+        let info = reader.streaminfo();
+        assert_eq!(info.samples, Some(10));
+        assert_eq!(info.min_block_size, info.max_block_size);
+        let expected: Vec<i32> = interleave(&left, &right, OutputChannels::Stereo)
+            .into_iter()
+            .map(|sample| to_int(sample, 16))
+            .collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn flac_of_a_render_of_a_few_frames_decodes_to_its_length() {
+        for frame_count in [1, 3, 10, MIN_BLOCK_SIZE - 1, MIN_BLOCK_SIZE] {
+            let (left, right) = noise(frame_count);
+            let expected: Vec<i32> = interleave(&left, &right, OutputChannels::Stereo)
+                .into_iter()
+                .map(|sample| to_int(sample, 16))
+                .collect();
+            for compression_level in 0..=8 {
+                let flac_options = FlacOptions {
+                    sample_format: SampleFormat::S16,
+                    compression_level,
+                    tags: Vec::new(),
+                };
+                let flac_data = encode_flac(
+                    &left,
+                    &right,
+                    OutputChannels::Stereo,
+                    &flac_options,
+                    Dither::None,
+                    48000,
+                )
+                .unwrap();
+
+                let (reader, decoded) = decode_flac(&flac_data);
+                let info = reader.streaminfo();
+                assert_eq!(info.samples, Some(frame_count as u64));
+                assert_eq!(
+                    decoded, expected,
+                    "{frame_count} frames at {compression_level}"
+                );
+                let mut md5 = Md5::new();
+                for sample in &expected {
+                    md5.update((*sample as i16).to_le_bytes());
+                }
+                assert_eq!(info.md5sum, <[u8; 16]>::from(md5.finalize()));
+            }
+        }
+    }
+
+    #[test]
+    fn flac_of_an_empty_render_has_valid_block_sizes() {
+        let flac_options = FlacOptions {
+            sample_format: SampleFormat::S16,
+            compression_level: 5,
+            tags: Vec::new(),
+        };
+        let flac_data = encode_flac(
+            &[],
+            &[],
+            OutputChannels::Stereo,
+            &flac_options,
+            Dither::None,
+            48000,
+        )
+        .unwrap();
+
+        let (reader, decoded) = decode_flac(&flac_data);
+        let info = reader.streaminfo();
+        assert!(decoded.is_empty());
+        assert_eq!(info.samples.unwrap_or(0), 0);
+        assert_eq!((info.min_block_size, info.max_block_size), (4096, 4096));
+    }
+
+    #[test]
+    fn vorbis_keeps_length_and_quality_sets_size() {
+        let (left, right) = noise(48000);
+        let encode = |channels, quality| {
+            encode_vorbis(&left, &right, channels, quality, &[], 44100).unwrap()
+        };
+
+        for (channels, count) in [
+            (OutputChannels::Stereo, 2),
+            (OutputChannels::Mono(Downmix::Average), 1),
+        ] {
+            let vorbis_data = encode(channels, 5.0);
+            let mut decoder = vorbis_rs::VorbisDecoder::new(Cursor::new(vorbis_data)).unwrap();
+            assert_eq!(decoder.channels().get(), count);
+            assert_eq!(decoder.sampling_frequency().get(), 44100);
+            let mut frames = 0;
+            while let Some(block) = decoder.decode_audio_block().unwrap() {
+                frames += block.samples()[0].len();
+            }
+            assert_eq!(frames, left.len());
+        }
+
+        let small = encode(OutputChannels::Stereo, -1.0).len();
+        let large = encode(OutputChannels::Stereo, 10.0).len();
+        assert!(small * 2 < large, "{small} vs {large} bytes");
+    }
+
+    /// Returns the ID3v2 tag and the frame count, encoder delay and padding of the LAME tag.
+    fn mp3_layout(mp3_data: &[u8]) -> (&[u8], u32, usize, usize) {
+        assert_eq!(&mp3_data[..3], b"ID3");
+        // The tag size is stored as four 7-bit bytes.
+        let tag_size = mp3_data[6..10]
+            .iter()
+            .fold(0, |size, &byte| size << 7 | byte as usize);
+        let (id3_tag, frames) = mp3_data.split_at(10 + tag_size);
+
+        let info = frames
+            .windows(4)
+            .position(|w| w == b"Info" || w == b"Xing")
+            .unwrap();
+        let frame_count = u32::from_be_bytes(frames[info + 8..info + 12].try_into().unwrap());
+        let lame = &frames[info + 120..];
+        assert_eq!(&lame[..4], b"LAME");
+        let delay = (lame[21] as usize) << 4 | (lame[22] as usize) >> 4;
+        let padding = ((lame[22] & 0x0F) as usize) << 8 | lame[23] as usize;
+        (id3_tag, frame_count, delay, padding)
+    }
+
+    #[test]
+    fn mp3_lame_tag_records_exact_length() {
+        let (left, right) = noise(10_000);
+        for (bitrate, channels) in [
+            (Mp3Bitrate::Cbr(128), OutputChannels::Stereo),
+            (Mp3Bitrate::Vbr(2), OutputChannels::Stereo),
+            (Mp3Bitrate::Cbr(64), OutputChannels::Mono(Downmix::Average)),
+        ] {
+            let mp3_options = Mp3Options {
+                bitrate,
+                tags: vec![
+                    ("title".to_string(), "Prelude".to_string()),
+                    ("ARTIST".to_string(), "Composer".to_string()),
+                ],
+            };
+            let mp3_data = encode_mp3(&left, &right, channels, &mp3_options, 48000).unwrap();
+
+            let (id3_tag, frame_count, delay, padding) = mp3_layout(&mp3_data);
+            assert!(id3_tag.windows(4).any(|w| w == b"TIT2"));
+            assert!(id3_tag.windows(7).any(|w| w == b"Prelude"));
+            assert!(id3_tag.windows(8).any(|w| w == b"Composer"));
+            // MPEG-1 layer III frames hold 1152 samples.
+            assert_eq!(frame_count as usize * 1152 - delay - padding, left.len());
+        }
+    }
+
+    #[test]
+    fn vorbis_rejects_a_zero_sample_rate() {
+        let (left, right) = noise(1000);
+        let vorbis = |sample_rate| {
+            encode_vorbis(&left, &right, OutputChannels::Stereo, 5.0, &[], sample_rate)
+        };
+        assert!(vorbis(48000).is_ok());
+        assert!(matches!(vorbis(0), Err(AudioError::Options(_))));
+    }
+
+    #[test]
+    fn mp3_rejects_unsupported_settings() {
+        let (left, right) = noise(1000);
+        for mp3_options in [
+            Mp3Options {
+                bitrate: Mp3Bitrate::Cbr(100),
+                tags: Vec::new(),
+            },
+            Mp3Options {
+                bitrate: Mp3Bitrate::Vbr(10),
+                tags: Vec::new(),
+            },
+        ] {
+            let result = encode_mp3(&left, &right, OutputChannels::Stereo, &mp3_options, 48000);
+            assert!(matches!(result, Err(AudioError::Mp3(_))));
+        }
+    }
+
+    #[test]
+    fn mp3_tags_are_written_as_latin_1() {
+        let (left, right) = noise(1000);
+        let with_tag = |name: &str, value: &str| Mp3Options {
+            bitrate: Mp3Bitrate::Cbr(128),
+            tags: vec![(name.to_string(), value.to_string())],
+        };
+
+        let mp3_options = with_tag("title", "Caf\u{e9} Ol\u{e9}");
+        assert!(mp3_options.validate().is_ok());
+        let mp3_data =
+            encode_mp3(&left, &right, OutputChannels::Stereo, &mp3_options, 48000).unwrap();
+        let (id3_tag, ..) = mp3_layout(&mp3_data);
+        assert!(id3_tag.windows(8).any(|w| w == b"Caf\xE9 Ol\xE9"));
+
+        for mp3_options in [
+            with_tag("GENRE", "Classical"),
+            with_tag("TITLE", "\u{6708}\u{5149}"),
+            with_tag("ARTIST", "A\0B"),
+            with_tag("COMMENT", &"x".repeat(MAX_MP3_TAG_LENGTH + 1)),
+        ] {
+            assert!(matches!(
+                mp3_options.validate(),
+                Err(AudioError::Options(_))
+            ));
+            let result = encode_mp3(&left, &right, OutputChannels::Stereo, &mp3_options, 48000);
+            assert!(matches!(result, Err(AudioError::Options(_))));
+        }
+    }
+
+    #[test]
+    fn pcm_layouts_keep_samples_unclipped() {
+        let left = vec![0.5, 1.5, -2.0];
+        let right = vec![-0.25, 0.0, 0.75];
+        let pcm = |channels, layout| arrange_pcm(left.clone(), right.clone(), channels, layout);
+
+        assert_eq!(
+            pcm(OutputChannels::Stereo, PcmLayout::Interleaved),
+            [0.5, -0.25, 1.5, 0.0, -2.0, 0.75]
+        );
+        assert_eq!(
+            pcm(OutputChannels::Stereo, PcmLayout::Planar),
+            [0.5, 1.5, -2.0, -0.25, 0.0, 0.75]
+        );
+        for layout in [PcmLayout::Interleaved, PcmLayout::Planar] {
+            assert_eq!(
+                pcm(OutputChannels::Mono(Downmix::Left), layout),
+                [0.5, 1.5, -2.0]
+            );
+        }
+    }
+
+    /// Joins every chunk of a stereo stream rendered `chunk_size` frames at a time.
+    fn stream(
+        sound_fonts: &SoundFontStack,
+        midi: &[u8],
+        format: StreamFormat,
+        chunk_size: usize,
+        options: &RenderOptions,
+    ) -> Result<Vec<u8>, AudioError> {
+        let mut stream = RenderStream::new(
+            sound_fonts,
+            midi,
+            format,
+            OutputChannels::Stereo,
+            chunk_size,
+            options,
+        )?;
+        let mut output = Vec::new();
+        while let Some(chunk) = stream.next_chunk()? {
+            output.extend(chunk);
+        }
+        Ok(output)
+    }
+
+    #[test]
+    fn streams_match_whole_renders_at_any_chunk_size() {
+        let midi = midi_file(&[b"\x00\x90\x45\x60\x83\x60\x80\x45\x00"]);
+        let sound_fonts = SoundFontStack::from(sine_sound_font());
+        let options = RenderOptions {
+            tail: Tail::Seconds(0.25),
+            ..RenderOptions::default()
+        };
+        let sample_format = SampleFormat::S16;
+        let (wav_data, _) = render_midi_to_wav(
+            &sound_fonts,
+            &midi,
+            OutputChannels::Stereo,
+            sample_format,
+            &options,
+        )
+        .unwrap();
+        let (ogg_data, _) = render_midi_to_opus(
+            &sound_fonts,
+            &midi,
+            OutputChannels::Stereo,
+            OpusBitrate::Auto,
+            &[],
+            &options,
+        )
+        .unwrap();
+        let samples = &wav_data[parse_wav_header(&wav_data).unwrap().data_start..];
+        assert_eq!(samples.len(), DEFAULT_SAMPLE_RATE as usize * 3 / 4 * 4);
+
+        // Less than an Opus frame, a prime number of frames and more than the whole song.
+        for chunk_size in [500, 4099, 100_000] {
+            let streamed = |format| stream(&sound_fonts, &midi, format, chunk_size, &options);
+            assert_eq!(
+                streamed(StreamFormat::Pcm { sample_format }).unwrap(),
+                samples,
+                "PCM in chunks of {chunk_size}"
+            );
+            assert_eq!(
+                streamed(StreamFormat::Wav { sample_format }).unwrap(),
+                wav_data,
+                "WAV in chunks of {chunk_size}"
+            );
+            let opus = streamed(StreamFormat::Opus {
+                bitrate: OpusBitrate::Auto,
+            })
+            .unwrap();
+            assert_eq!(opus, ogg_data, "Opus in chunks of {chunk_size}");
+        }
+    }
+
+    #[test]
+    fn streamed_wav_of_unknown_length_marks_its_sizes() {
+        // Half a second of A4, then a tail that runs until the release dies away.
+        let midi = midi_file(&[b"\x00\x90\x45\x60\x83\x60\x80\x45\x00"]);
+        let sound_fonts = SoundFontStack::from(sine_sound_font());
+        let options = RenderOptions {
+            tail: Tail::UntilSilent {
+                threshold: 0.001,
+                max_seconds: 5.0,
+            },
+            ..RenderOptions::default()
+        };
+        let format = StreamFormat::Wav {
+            sample_format: SampleFormat::S16,
+        };
+        let wav_data = stream(&sound_fonts, &midi, format, 4096, &options).unwrap();
+
+        let field =
+            |offset: usize| u32::from_le_bytes(wav_data[offset..offset + 4].try_into().unwrap());
+        assert_eq!(&wav_data[36..40], b"data");
+        assert_eq!((field(4), field(40)), (WAV_UNKNOWN_SIZE, WAV_UNKNOWN_SIZE));
+        let streamed = &wav_data[parse_wav_header(&wav_data).unwrap().data_start..];
+        let frames = streamed.len() / 4;
+        let rate = DEFAULT_SAMPLE_RATE as usize;
+        assert!((rate / 2..rate).contains(&frames), "{frames}");
+
+        let (whole, _) = render_midi_to_wav(
+            &sound_fonts,
+            &midi,
+            OutputChannels::Stereo,
+            SampleFormat::S16,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            streamed,
+            &whole[parse_wav_header(&whole).unwrap().data_start..]
+        );
+
+        // Known lengths past 4 GiB cannot be written.
+        let mut header = Vec::new();
+        let header_for = |header: &mut Vec<u8>, frames| {
+            write_wav_header(
+                header,
+                Some(frames),
+                OutputChannels::Stereo,
+                SampleFormat::S16,
+                DEFAULT_SAMPLE_RATE,
+            )
+        };
+        assert!(header_for(&mut header, 1 << 28).is_ok());
+        assert!(matches!(
+            header_for(&mut header, 1 << 30),
+            Err(AudioError::Wav(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_input_is_told_apart_from_render_failures() {
+        let sf2 = sound_font_file(2, &[0; 200], &[[0, 50, 0, 50]], MONO_SAMPLE);
+        let midi = midi_file(&[b"\x00\x90\x45\x60\x83\x60\x80\x45\x00"]);
+        let sound_fonts = SoundFontStack::from(load_soundfont(&sf2).unwrap());
+        let render = |midi: &[u8], options: &RenderOptions| {
+            render_midi_to_wav(
+                &sound_fonts,
+                midi,
+                OutputChannels::Stereo,
+                SampleFormat::S16,
+                options,
+            )
+            .map(|_| ())
+        };
+        let nan_start = RenderOptions {
+            start: f64::NAN,
+            ..RenderOptions::default()
+        };
+
+        for error in [
+            load_soundfont(b"not a SoundFont").unwrap_err(),
+            load_soundfont(&sf2[..sf2.len() - 10]).unwrap_err(),
+            render(&midi[..20], &RenderOptions::default()).unwrap_err(),
+            render(&midi, &nan_start).unwrap_err(),
+        ] {
+            assert!(error.is_invalid_input(), "{error}");
+        }
+        let mut header = Vec::new();
+        let oversized = write_wav_header(
+            &mut header,
+            Some(1 << 30),
+            OutputChannels::Stereo,
+            SampleFormat::S16,
+            DEFAULT_SAMPLE_RATE,
+        );
+        assert!(!oversized.unwrap_err().is_invalid_input());
+    }
+
+    #[test]
+    fn sample_rate_must_be_supported_by_the_synthesizer() {
+        let at_rate = |sample_rate| RenderOptions {
+            sample_rate,
+            ..RenderOptions::default()
+        };
+        for sample_rate in [16000, 44100, 192000] {
+            assert!(at_rate(sample_rate).validate().is_ok(), "{sample_rate}");
+        }
+        for sample_rate in [0, 8000, 192001, u32::MAX] {
+            assert!(
+                matches!(at_rate(sample_rate).validate(), Err(AudioError::Options(_))),
+                "{sample_rate}"
+            );
+        }
+
+        let midi = midi_file(&[b""]);
+        let rendered = render_midi_to_pcm(
+            &SoundFontStack::from(sine_sound_font()),
+            &midi,
+            OutputChannels::Stereo,
+            PcmLayout::Interleaved,
+            &at_rate(8000),
+        );
+        assert!(rendered.unwrap_err().is_invalid_input());
+    }
+
+    #[test]
+    fn synthesizer_settings_are_bounded_and_applied() {
+        let with_settings = |block_size, maximum_polyphony| RenderOptions {
+            enable_reverb_and_chorus: Some(false),
+            block_size,
+            maximum_polyphony,
+            ..RenderOptions::default()
+        };
+        for (block_size, maximum_polyphony) in [(Some(8), Some(256)), (Some(1024), Some(8))] {
+            assert!(with_settings(block_size, maximum_polyphony)
+                .validate()
+                .is_ok());
+        }
+        for (block_size, maximum_polyphony) in [
+            (Some(0), None),
+            (Some(7), None),
+            (Some(1025), None),
+            (None, Some(1)),
+            (None, Some(257)),
+        ] {
+            assert!(matches!(
+                with_settings(block_size, maximum_polyphony).validate(),
+                Err(AudioError::Options(_))
+            ));
+        }
+
+        // Nine notes on nine channels, held to 1s: the first from the start and the other
+        // eight from 0.5s, where a cap of eight voices takes the first note's voice.
+        let track = |first: bool| {
+            let mut track = Vec::new();
+            if first {
+                track.extend_from_slice(b"\x00\x90\x3C\x60");
+            }
+            for channel in 1..9 {
+                let delta: &[u8] = if channel == 1 { b"\x83\x60" } else { b"\x00" };
+                track.extend_from_slice(delta);
+                track.extend_from_slice(&[0x90 | channel, 0x3C + channel, 0x60]);
+            }
+            track.extend_from_slice(b"\x83\x60\xB0\x7B\x00");
+            for channel in 1..9 {
+                track.extend_from_slice(&[0x00, 0xB0 | channel, 0x7B, 0x00]);
+            }
+            midi_file(&[&track])
+        };
+        let sound_fonts = SoundFontStack::from(sine_sound_font());
+        let render = |midi: &[u8], block_size, maximum_polyphony| {
+            render_midi_to_pcm(
+                &sound_fonts,
+                midi,
+                OutputChannels::Mono(Downmix::Left),
+                PcmLayout::Interleaved,
+                &with_settings(block_size, maximum_polyphony),
+            )
+            .unwrap()
+            .0
+        };
+        let rate = DEFAULT_SAMPLE_RATE as usize;
+        // Whether two renders match once the later notes have sounded for a moment.
+        let match_late = |a: &[f32], b: &[f32]| {
+            a[rate * 6 / 10..rate * 9 / 10]
+                .iter()
+                .zip(&b[rate * 6 / 10..rate * 9 / 10])
+                .all(|(a, b)| (a - b).abs() < 1e-4)
+        };
+        let later_notes = render(&track(false), None, Some(8));
+        assert!(match_late(
+            &render(&track(true), None, Some(8)),
+            &later_notes
+        ));
+        assert!(!match_late(&render(&track(true), None, None), &later_notes));
+
+        // The synthesizer starts notes at block boundaries, so a note at 0.5s sounds from
+        // the first block that begins at or after it: 64 frames apart by default.
+        let midi = midi_file(&[b"\x83\x60\x90\x45\x60\x83\x60\x80\x45\x00"]);
+        let onset = |block_size| {
+            render(&midi, block_size, None)
+                .iter()
+                .position(|sample| sample.abs() > 1e-3)
+                .unwrap()
+        };
+        assert!((rate / 2..rate / 2 + 64).contains(&onset(None)));
+        assert!((rate / 2).next_multiple_of(1024) <= onset(Some(1024)));
+    }
+
+    #[test]
+    fn tails_must_be_finite_and_bounded() {
+        let with_tail = |tail| RenderOptions {
+            tail,
+            ..RenderOptions::default()
+        };
+        let until_silent = |max_seconds| Tail::UntilSilent {
+            threshold: 0.001,
+            max_seconds,
+        };
+        for tail in [
+            Tail::None,
+            Tail::Seconds(0.0),
+            Tail::Seconds(MAX_TAIL_SECONDS),
+            until_silent(10.0),
+        ] {
+            assert!(with_tail(tail).validate().is_ok(), "{tail:?}");
+        }
+        for seconds in [-1.0, f64::NAN, f64::INFINITY, MAX_TAIL_SECONDS + 1.0, 1e300] {
+            for tail in [Tail::Seconds(seconds), until_silent(seconds)] {
+                assert!(
+                    matches!(with_tail(tail).validate(), Err(AudioError::Options(_))),
+                    "{tail:?}"
+                );
+            }
+        }
+
+        // Rendering checks them too, before sizing any buffer.
+        let midi = midi_file(&[b""]);
+        let sound_fonts = SoundFontStack::from(sine_sound_font());
+        let rendered = render_midi_to_pcm(
+            &sound_fonts,
+            &midi,
+            OutputChannels::Stereo,
+            PcmLayout::Interleaved,
+            &with_tail(Tail::Seconds(f64::INFINITY)),
+        );
+        assert!(matches!(rendered, Err(AudioError::Options(_))));
+    }
+
+    #[test]
+    fn start_and_end_play_the_song_as_it_stands_there() {
+        // Held from the start to 1s: A4 on channel 0, and C5 on channel 1, which is turned
+        // down to silence at 0.25s. Channel 2 switches to the "Low" preset at 0.25s, then
+        // channels 2 and 3 play from 0.75s to 1s.
+        let midi = midi_file(&[b"\x00\x90\x45\x60\x00\x91\x48\x60\x81\x70\xB1\x07\x00\
+            \x00\xC2\x01\x83\x60\x92\x4A\x60\x00\x93\x4C\x60\x81\x70\x80\x45\x00\
+            \x00\x81\x48\x00\x00\x82\x4A\x00\x00\x83\x4C\x00"]);
+        let sound_fonts = SoundFontStack::from(sine_sound_font());
+        let song = Arc::new(MidiSong::parse(&midi).unwrap());
+        let rate = DEFAULT_SAMPLE_RATE as f64;
+        let render = |filter, start, end, tail| {
+            let options = RenderOptions {
+                enable_reverb_and_chorus: Some(false),
+                start,
+                end,
+                tail,
+                ..RenderOptions::default()
+            };
+            render_song(&sound_fonts, &song, filter, &options)
+                .unwrap()
+                .0
+        };
+        let sounds = |samples: &[f32], from: f64, to: f64| {
+            let window = &samples[(from * rate) as usize..(to * rate) as usize];
+            window.iter().any(|sample| sample.abs() > 0.01)
+        };
+
+        // From 0.5s, held notes are struck again, and the controllers and programs sent
+        // before then apply.
+        for (channel, sounding) in [(0, true), (1, false), (2, false), (3, true)] {
+            let samples = render(NoteFilter::Channel(channel), 0.5, None, Tail::None);
+            assert_eq!(samples.len(), (rate * 0.5) as usize);
+            assert_eq!(
+                sounds(&samples, 0.3, 0.45),
+                sounding,
+                "channel {channel} from 0.5s"
+            );
+        }
+        let samples = render(NoteFilter::Channel(0), 0.5, None, Tail::None);
+        assert!(sounds(&samples, 0.0, 0.05));
+
+        // Up to 0.5s, the held notes are released there and later notes never start, even
+        // within the tail.
+        let samples = render(NoteFilter::All, 0.0, Some(0.5), Tail::Seconds(0.5));
+        assert_eq!(samples.len(), rate as usize);
+        assert!(sounds(&samples, 0.4, 0.5));
+        assert!(!sounds(&samples, 0.55, 1.0));
+
+        for (start, end) in [
+            (f64::NAN, None),
+            (-1.0, None),
+            (0.0, Some(f64::NAN)),
+            (0.5, Some(0.5)),
+        ] {
+            let options = RenderOptions {
+                start,
+                end,
+                ..RenderOptions::default()
+            };
+            assert!(
+                matches!(options.validate(), Err(AudioError::Options(_))),
+                "{start} to {end:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn loops_must_be_finite_and_bounded() {
+        let looping = |start, end, repetitions| RenderOptions {
+            looping: Some(Looping {
+                start,
+                end,
+                repetitions,
+                intro: true,
+            }),
+            ..RenderOptions::default()
+        };
+        assert!(looping(Some(0.5), Some(1.5), 3).validate().is_ok());
+        assert!(looping(None, None, MAX_LOOP_REPETITIONS).validate().is_ok());
+        for options in [
+            looping(None, None, 0),
+            looping(None, None, MAX_LOOP_REPETITIONS + 1),
+            looping(None, None, usize::MAX),
+            looping(Some(f64::NAN), None, 2),
+            looping(Some(-0.5), None, 2),
+            looping(None, Some(f64::INFINITY), 2),
+            looping(None, Some(f64::NAN), 2),
+        ] {
+            assert!(
+                matches!(options.validate(), Err(AudioError::Options(_))),
+                "{:?}",
+                options.looping
+            );
+        }
+
+        // A NaN loop start is an error rather than an empty render.
+        let midi = midi_file(&[b"\x00\x90\x45\x60\x83\x60\x80\x45\x00"]);
+        let rendered = render_midi_to_pcm(
+            &SoundFontStack::from(sine_sound_font()),
+            &midi,
+            OutputChannels::Stereo,
+            PcmLayout::Interleaved,
+            &looping(Some(f64::NAN), None, 2),
+        );
+        assert!(matches!(rendered, Err(AudioError::Options(_))));
+    }
+
+    #[test]
+    fn trim_and_fades_must_be_finite() {
+        let with_fades = |fade_in, fade_out| RenderOptions {
+            fades: Some(Fades {
+                fade_in,
+                fade_out,
+                curve: FadeCurve::Linear,
+            }),
+            ..RenderOptions::default()
+        };
+        let with_trim = |threshold| RenderOptions {
+            trim: Some(Trim {
+                start: true,
+                end: true,
+                threshold,
+            }),
+            ..RenderOptions::default()
+        };
+        assert!(with_fades(0.5, 0.0).validate().is_ok());
+        assert!(with_trim(0.001).validate().is_ok());
+        for seconds in [-1.0, f64::NAN, f64::INFINITY] {
+            for options in [with_fades(seconds, 0.0), with_fades(0.0, seconds)] {
+                assert!(
+                    matches!(options.validate(), Err(AudioError::Options(_))),
+                    "{:?}",
+                    options.fades
+                );
+            }
+        }
+        for threshold in [f32::NAN, f32::INFINITY, 0.0, -0.001] {
+            assert!(
+                matches!(with_trim(threshold).validate(), Err(AudioError::Options(_))),
+                "{threshold:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn opus_tags_default_to_song_meta_events() {
+        let midi = midi_file(&[
+            b"\x00\xFF\x03\x07Prelude\x00\xFF\x02\x0C(c) Composer",
+            // A sequence name in a later track names only that track.
+            b"\x00\xFF\x03\x05Piano\x00\x90\x3C\x40\x60\x80\x3C\x00",
+        ]);
+        let song = MidiSong::parse(&midi).unwrap();
+        assert_eq!(song.title(), Some("Prelude"));
+        assert_eq!(song.copyright(), Some("(c) Composer"));
+
+        let tag = |name: &str, value: &str| (name.to_string(), value.to_string());
+        assert_eq!(
+            with_song_tags(&[tag("ARTIST", "Someone")], &song),
+            [
+                tag("ARTIST", "Someone"),
+                tag("TITLE", "Prelude"),
+                tag("COPYRIGHT", "(c) Composer")
+            ]
+        );
+        assert_eq!(
+            with_song_tags(&[tag("title", "Fugue")], &song),
+            [tag("title", "Fugue"), tag("COPYRIGHT", "(c) Composer")]
+        );
+        let untitled = MidiSong::parse(&midi_file(&[b""])).unwrap();
+        assert_eq!(with_song_tags(&[], &untitled), []);
+
+        let tags = [tag("TITLE", "Prelude"), tag("ALBUM", "Suites")];
+        let ogg_data = wav_to_opus_ogg(
+            &noise_wav(),
+            OutputChannels::Stereo,
+            OpusBitrate::Auto,
+            &tags,
+        )
+        .unwrap();
+        let comment = read_packets(&ogg_data).swap_remove(1).data;
+        let mut expected = b"OpusTags".to_vec();
+        expected.extend(vorbis_comment(&tags));
+        assert_eq!(comment, expected);
+    }
+
+    #[test]
+    fn loop_points_come_from_markers_and_reach_the_metadata() {
+        // At 120 BPM and 480 ticks per beat: loop from 0.5s to 1.5s of a 2s note.
+        let midi = midi_file(&[
+            b"\x83\x60\xFF\x06\x09loopStart\x87\x40\xFF\x06\x07LOOPEND",
+            b"\x00\x90\x3C\x40\x8F\x00\x80\x3C\x00",
+        ]);
+        let song = MidiSong::parse(&midi).unwrap();
+        assert_eq!((song.loop_start(), song.loop_end()), (Some(0.5), Some(1.5)));
+        // CC111 marks the start when there is no marker; the loop then runs to the end, 2.5s.
+        let controller = MidiSong::parse(&midi_file(&[
+            b"\x87\x40\xB0\x6F\x00\x87\x40\x90\x3C\x40\x83\x60\x80\x3C\x00",
+        ]))
+        .unwrap();
+        assert_eq!(
+            (controller.loop_start(), controller.loop_end()),
+            (Some(1.0), None)
+        );
+
+        let locate = |song: &MidiSong, start, end, intro| {
+            let options = RenderOptions {
+                looping: Some(Looping {
+                    start,
+                    end,
+                    repetitions: 3,
+                    intro,
+                }),
+                ..RenderOptions::default()
+            };
+            LoopPoints::locate(song, &options)
+        };
+        let rate = DEFAULT_SAMPLE_RATE as usize;
+        let marked = locate(&song, None, None, true).unwrap().unwrap();
+        assert_eq!(
+            marked,
+            LoopPoints {
+                start: rate * 5 / 2,
+                length: rate,
+            }
+        );
+        assert_eq!(
+            locate(&song, None, None, false).unwrap(),
+            Some(LoopPoints {
+                start: rate * 2,
+                length: rate,
+            })
+        );
+        assert_eq!(
+            locate(&controller, None, None, false).unwrap(),
+            Some(LoopPoints {
+                start: rate * 3,
+                length: rate * 3 / 2,
+            })
+        );
+        let untagged = MidiSong::parse(&midi_file(&[b""])).unwrap();
+        assert!(locate(&untagged, None, None, true).is_err());
+        assert!(locate(&song, Some(1.5), Some(0.5), true).is_err());
+
+        let tag = |name: &str, value: usize| (name.to_string(), value.to_string());
+        assert_eq!(
+            marked.tags(44100, OPUS_GRANULE_RATE),
+            [
+                tag("LOOPSTART", rate * 5 / 2 * 48000 / 44100),
+                tag("LOOPLENGTH", rate * 48000 / 44100)
+            ]
+        );
+
+        let mut wav_data = noise_wav();
+        let data_end = wav_data.len();
+        append_smpl_chunk(&mut wav_data, marked, DEFAULT_SAMPLE_RATE).unwrap();
+        let riff_size = u32::from_le_bytes(wav_data[4..8].try_into().unwrap());
+        assert_eq!(riff_size as usize, wav_data.len() - 8);
+        assert!(parse_wav_header(&wav_data).is_ok());
+        let smpl = &wav_data[data_end..];
+        let field =
+            |offset: usize| u32::from_le_bytes(smpl[offset..offset + 4].try_into().unwrap());
+        assert_eq!(&smpl[..4], b"smpl");
+        assert_eq!((field(4) as usize, field(36)), (smpl.len() - 8, 1));
+        assert_eq!(
+            (field(52) as usize, field(56) as usize),
+            (rate * 5 / 2, rate * 7 / 2 - 1)
+        );
+    }
+
+    #[test]
+    fn looped_renders_repeat_the_body_and_strike_held_notes_again() {
+        // At 120 BPM: A4 for the intro's first quarter second, C5 held from 0.375s across
+        // the loop start at 0.5s until 1s, then D5 from 1.125s to 1.25s; the loop ends at 1.5s.
+        let midi = midi_file(&[b"\x00\x90\x45\x60\x81\x70\x80\x45\x00\x78\x90\x48\x60\
+            \x78\xFF\x06\x09loopStart\x83\x60\x80\x48\x00\x78\x90\x4A\x60\x78\x80\x4A\x00\
+            \x81\x70\xFF\x06\x07loopEnd"]);
+        let song = Arc::new(MidiSong::parse(&midi).unwrap());
+        let sound_fonts = SoundFontStack::from(sine_sound_font());
+        let rate = DEFAULT_SAMPLE_RATE as f64;
+
+        for (intro, sounding) in [
+            (
+                true,
+                &[
+                    (0.0, 0.25),
+                    (0.375, 1.0),
+                    (1.125, 1.25),
+                    (1.5, 2.0),
+                    (2.125, 2.25),
+                    (2.5, 3.0),
+                    (3.125, 3.25),
+                ][..],
+            ),
+            (
+                false,
+                &[
+                    (0.0, 0.5),
+                    (0.625, 0.75),
+                    (1.0, 1.5),
+                    (1.625, 1.75),
+                    (2.0, 2.5),
+                    (2.625, 2.75),
+                ][..],
+            ),
+        ] {
+            let options = RenderOptions {
+                enable_reverb_and_chorus: Some(false),
+                looping: Some(Looping {
+                    start: None,
+                    end: None,
+                    repetitions: 3,
+                    intro,
+                }),
+                ..RenderOptions::default()
+            };
+            let (left, _) = render_song(&sound_fonts, &song, NoteFilter::All, &options).unwrap();
+            let seconds = if intro { 3.5 } else { 3.0 };
+            assert_eq!(left.len(), (rate * seconds) as usize);
+
+            // Sample the middle of every 1/16s step, clear of attacks and releases.
+            for step in 0..(seconds * 16.0) as usize {
+                let time = (step as f64 + 0.5) / 16.0;
+                let window =
+                    &left[((time - 0.02) * rate) as usize..((time + 0.02) * rate) as usize];
+                let rms = (window.iter().map(|s| s * s).sum::<f32>() / window.len() as f32).sqrt();
+                let expected = sounding
+                    .iter()
+                    .any(|&(from, to)| (from..to).contains(&time));
+                assert_eq!(
+                    rms > 0.01,
+                    expected,
+                    "intro {intro}: level {rms} at {time}s"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stems_play_only_their_own_notes() {
+        // Track 1 plays channel 0 for the first half second, track 2 channel 1 for the next.
+        let midi = midi_file(&[
+            b"",
+            b"\x00\x90\x45\x60\x83\x60\x80\x45\x00",
+            b"\x83\x60\x91\x48\x60\x83\x60\x81\x48\x00",
+        ]);
+        let sound_fonts = SoundFontStack::from(sine_sound_font());
+        let options = RenderOptions {
+            enable_reverb_and_chorus: Some(false),
+            ..RenderOptions::default()
+        };
+        let rate = DEFAULT_SAMPLE_RATE as usize;
+        // The level of the first and second half seconds.
+        let levels = |wav_data: &[u8]| {
+            let data = &wav_data[parse_wav_header(wav_data).unwrap().data_start..];
+            let samples: Vec<i16> = data
+                .chunks_exact(4)
+                .map(|frame| i16::from_le_bytes([frame[0], frame[1]]))
+                .collect();
+            assert_eq!(samples.len(), rate);
+            [
+                &samples[rate / 20..rate * 9 / 20],
+                &samples[rate * 11 / 20..rate * 19 / 20],
+            ]
+            .map(|half| half.iter().map(|&s| s.unsigned_abs()).max().unwrap() > 1000)
+        };
+
+        for (split, keys) in [(StemSplit::Track, [1, 2]), (StemSplit::Channel, [0, 1])] {
+            let stems = render_midi_stems(
+                &sound_fonts,
+                &midi,
+                split,
+                OutputChannels::Stereo,
+                SampleFormat::S16,
+                &options,
+            )
+            .unwrap();
+            assert_eq!(stems.iter().map(|(key, _)| *key).collect::<Vec<_>>(), keys);
+            assert_eq!(levels(&stems[0].1), [true, false]);
+            assert_eq!(levels(&stems[1].1), [false, true]);
+        }
+    }
+
+    #[test]
+    fn stems_get_dither_seeds_of_their_own() {
+        // The same note on two tracks, so only the dither tells the stems apart.
+        let note: &[u8] = b"\x00\x90\x45\x60\x83\x60\x80\x45\x00";
+        let midi = midi_file(&[note, note]);
+        let sound_fonts = SoundFontStack::from(sine_sound_font());
+        let render = |dither| {
+            let options = RenderOptions {
+                dither,
+                ..RenderOptions::default()
+            };
+            render_midi_stems(
+                &sound_fonts,
+                &midi,
+                StemSplit::Track,
+                OutputChannels::Stereo,
+                SampleFormat::S16,
+                &options,
+            )
+            .unwrap()
+        };
+
+        let undithered = render(Dither::None);
+        assert_eq!(undithered[0].1, undithered[1].1);
+        let dithered = render(Dither::Tpdf { seed: 1 });
+        assert_ne!(dithered[0].1, dithered[1].1);
+        assert_eq!(dithered, render(Dither::Tpdf { seed: 1 }));
+    }
+
+    #[test]
+    fn streamed_wav_carries_loop_points() {
+        let midi = midi_file(&[
+            b"\x83\x60\xFF\x06\x09loopStart\x87\x40\xFF\x06\x07loopEnd",
+            b"\x00\x90\x3C\x40\x8F\x00\x80\x3C\x00",
+        ]);
+        let sound_fonts = SoundFontStack::from(sine_sound_font());
+        let format = StreamFormat::Wav {
+            sample_format: SampleFormat::S16,
+        };
+        let mut options = RenderOptions {
+            tail: Tail::Seconds(0.5),
+            looping: Some(Looping {
+                start: None,
+                end: None,
+                repetitions: 2,
+                intro: true,
+            }),
+            ..RenderOptions::default()
+        };
+        let streamed = stream(&sound_fonts, &midi, format, 4096, &options).unwrap();
+        let (whole, _) = render_midi_to_wav(
+            &sound_fonts,
+            &midi,
+            OutputChannels::Stereo,
+            SampleFormat::S16,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(&whole[whole.len() - 68..whole.len() - 64], b"smpl");
+        assert_eq!(streamed, whole);
+
+        // The `smpl` chunk cannot follow samples of unknown length.
+        options.tail = Tail::UntilSilent {
+            threshold: 0.001,
+            max_seconds: 5.0,
+        };
+        assert!(matches!(
+            stream(&sound_fonts, &midi, format, 4096, &options),
+            Err(AudioError::Options(_))
+        ));
+    }
+
+    #[test]
+    fn loudness_target_is_met_within_the_true_peak_ceiling() {
+        let rate = DEFAULT_SAMPLE_RATE;
+        let (left, right) = noise(rate as usize * 2);
+        let measure = |left: &[f32], right: &[f32], channels| {
+            let planes = match channels {
+                OutputChannels::Stereo => vec![left.to_vec(), right.to_vec()],
+                OutputChannels::Mono(_) => vec![interleave(left, right, channels)],
+            };
+            let planes: Vec<&[f32]> = planes.iter().map(Vec::as_slice).collect();
+            (
+                loudness::integrated_loudness(&planes, rate).unwrap(),
+                20.0 * (loudness::true_peak(&planes, rate) as f64).log10(),
+            )
+        };
+
+        for channels in [
+            OutputChannels::Stereo,
+            OutputChannels::Mono(Downmix::Average),
+        ] {
+            let options = |target, true_peak| RenderOptions {
+                loudness: Some(Loudness { target, true_peak }),
+                ..RenderOptions::default()
+            };
+
+            let (mut quiet_left, mut quiet_right) = (left.clone(), right.clone());
+            master(
+                &mut quiet_left,
+                &mut quiet_right,
+                channels,
+                &options(-30.0, -1.0),
+            );
+            let (loudness, _) = measure(&quiet_left, &quiet_right, channels);
+            assert!((loudness + 30.0).abs() < 0.01, "{loudness}");
+
+            // Reaching -5 LUFS would take the peaks past -8 dBTP, so the ceiling wins.
+            let (mut loud_left, mut loud_right) = (left.clone(), right.clone());
+            master(
+                &mut loud_left,
+                &mut loud_right,
+                channels,
+                &options(-5.0, -8.0),
+            );
+            let (loudness, true_peak) = measure(&loud_left, &loud_right, channels);
+            assert!(loudness < -6.0, "{loudness}");
+            assert!((true_peak + 8.0).abs() < 0.01, "{true_peak}");
+        }
+    }
+
+    #[test]
+    fn trim_and_fades_edit_the_output_channels() {
+        // A count-in on the right only, a tone on the left, then a hum below -60 dB.
+        let mut left = vec![0.0f32; 1000];
+        left.extend(vec![0.5; 2000]);
+        left.extend(vec![0.0001; 1000]);
+        let mut right = vec![0.0f32; left.len()];
+        right[100] = 0.5;
+
+        let trim = Trim {
+            start: true,
+            end: true,
+            threshold: 0.001,
+        };
+        let edit = |channels, trim, fades| {
+            let options = RenderOptions {
+                trim,
+                fades,
+                ..RenderOptions::default()
+            };
+            let (mut left, mut right) = (left.clone(), right.clone());
+            trim_and_fade(&mut left, &mut right, channels, &options);
+            assert_eq!(left.len(), right.len());
+            left
+        };
+
+        assert_eq!(edit(OutputChannels::Stereo, Some(trim), None).len(), 2900);
+        let only_left = OutputChannels::Mono(Downmix::Left);
+        assert_eq!(edit(only_left, Some(trim), None), vec![0.5; 2000]);
+        let end_only = Trim {
+            start: false,
+            ..trim
+        };
+        assert_eq!(edit(only_left, Some(end_only), None).len(), 3000);
+        let silent = Trim {
+            threshold: 1.0,
+            ..trim
+        };
+        assert!(edit(only_left, Some(silent), None).is_empty());
+
+        // 10ms fades at 48kHz, so 480 frames at each end.
+        for (curve, halfway) in [
+            (FadeCurve::Linear, 0.25),
+            (FadeCurve::Exponential, 0.5 * (1000f32.sqrt() - 1.0) / 999.0),
+        ] {
+            let fades = Fades {
+                fade_in: 0.01,
+                fade_out: 0.01,
+                curve,
+            };
+            let faded = edit(only_left, Some(trim), Some(fades));
+            assert_eq!(faded.len(), 2000);
+            assert_eq!((faded[0], faded[1999]), (0.0, 0.0));
+            assert!(
+                (faded[240] - halfway).abs() < 1e-4,
+                "{curve:?}: {}",
+                faded[240]
+            );
+            assert!((faded[1999 - 240] - halfway).abs() < 1e-4);
+            assert_eq!(faded[480..1520], vec![0.5; 1040]);
+        }
+    }
+
+    #[test]
+    fn overload_modes_keep_output_within_full_scale() {
+        let rate = DEFAULT_SAMPLE_RATE;
+        let (left, right) = noise(rate as usize);
+        // Peaks near 2.0, with most samples still below full scale.
+        let left: Vec<f32> = left.iter().map(|sample| sample * 4.0).collect();
+        let right: Vec<f32> = right.iter().map(|sample| sample * 4.0).collect();
+        let ceiling = 10f32.powf(-1.0 / 20.0);
+
+        for channels in [
+            OutputChannels::Stereo,
+            OutputChannels::Mono(Downmix::PanLaw),
+        ] {
+            let over = interleave(&left, &right, channels)
+                .iter()
+                .filter(|sample| sample.abs() > 1.0)
+                .count();
+            for (overload, limit) in [
+                (Overload::Clip, None),
+                (Overload::Soft, Some(1.0)),
+                (
+                    Overload::Limit {
+                        ceiling,
+                        release: 0.05,
+                    },
+                    Some(ceiling),
+                ),
+            ] {
+                let (mut shaped_left, mut shaped_right) = (left.clone(), right.clone());
+                let report = handle_overload(
+                    &mut shaped_left,
+                    &mut shaped_right,
+                    channels,
+                    rate,
+                    overload,
+                );
+                let output = interleave(&shaped_left, &shaped_right, channels);
+                assert_eq!(report.total_samples, output.len());
+                assert!(report.peak > 1.5, "{:?}", report);
+
+                let changed = output
+                    .iter()
+                    .zip(interleave(&left, &right, channels))
+                    .filter(|(shaped, sample)| **shaped != *sample)
+                    .count();
+                match limit {
+                    None => {
+                        assert_eq!(changed, 0);
+                        assert_eq!(report.affected_samples, over);
+                    }
+                    Some(limit) => {
+                        let peak = output.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+                        assert!(peak <= limit * 1.00001, "{overload:?}: {peak}");
+                        assert!(report.affected_samples >= over, "{:?}", report);
+                        assert!(report.affected_samples >= changed, "{:?}", report);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn opus_header_carries_output_gain() {
+        let ogg_data = {
+            let mut encoder =
+                OpusOggEncoder::new(48000, true, OpusBitrate::Auto, -1234, &[]).unwrap();
+            encoder.push(&[0; FRAME_SIZE * 2]).unwrap();
+            encoder.finish().unwrap()
+        };
+        let header = &read_packets(&ogg_data)[0].data;
+        assert_eq!(i16::from_le_bytes([header[16], header[17]]), -1234);
+        assert_eq!(to_q8(-4.82), -1234);
+        assert_eq!(to_q8(200.0), i16::MAX);
+    }
+}
@@ -0,0 +1,149 @@
+// Largest error fed back by noise shaping: half a step of rounding plus the full reach
+// of the dither. Anything larger comes from clipping and would only be fed back again.
+const MAX_ERROR: f64 = 1.5;
+
+/// Adds triangular (TPDF) dither before rounding, optionally shaped by error feedback.
+///
+/// Samples arrive interleaved, one channel after another, and each channel keeps its own
+/// feedback. The noise comes from a seeded generator, so equal seeds give equal output.
+pub struct Ditherer {
+    noise_shaping: bool,
+    state: u64,
+    errors: Vec<f64>,
+    channel: usize,
+}
+
+impl Ditherer {
+    pub fn new(noise_shaping: bool, seed: u64, channels: usize) -> Self {
+        Self {
+            noise_shaping,
+            state: seed,
+            errors: vec![0.0; channels.max(1)],
+            channel: 0,
+        }
+    }
+
+    /// Rounds `value`, in steps of the output format, to a whole step between `min` and `max`.
+    ///
+    /// Noise shaping subtracts the previous total error of the channel first, which moves
+    /// the noise towards high frequencies where it is least audible.
+    pub fn quantize(&mut self, value: f64, min: f64, max: f64) -> f64 {
+        let channel = self.channel;
+        self.channel = (channel + 1) % self.errors.len();
+
+        let wanted = if self.noise_shaping {
+            value - self.errors[channel]
+        } else {
+            value
+        };
+        let quantized = (wanted + self.triangular()).round().clamp(min, max);
+        if self.noise_shaping {
+            self.errors[channel] = (quantized - wanted).clamp(-MAX_ERROR, MAX_ERROR);
+        }
+        quantized
+    }
+
+    /// Returns noise between -1 and 1 step with a triangular distribution.
+    fn triangular(&mut self) -> f64 {
+        self.uniform() - self.uniform()
+    }
+
+    /// Returns a uniform value in [0, 1) from a SplitMix64 sequence.
+    fn uniform(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+        (mix(self.state) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Step of the SplitMix64 sequence.
+const GOLDEN_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// Derives the seed of one of several streams from a shared seed.
+///
+/// Seeds a whole number of steps apart would give the same noise shifted by that many
+/// samples, so the stream index is mixed in rather than added.
+pub fn derive_seed(seed: u64, stream: u64) -> u64 {
+    mix(seed ^ mix(stream.wrapping_add(1).wrapping_mul(GOLDEN_GAMMA)))
+}
+
+/// The SplitMix64 output function, which scatters nearby states across all 64 bits.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quantize_all(ditherer: &mut Ditherer, values: &[f64]) -> Vec<f64> {
+        values
+            .iter()
+            .map(|&value| ditherer.quantize(value, -32768.0, 32767.0))
+            .collect()
+    }
+
+    #[test]
+    fn dither_keeps_detail_below_one_step() {
+        // A level of 0.3 steps rounds to silence without dither.
+        let values = vec![0.3; 100_000];
+        for noise_shaping in [false, true] {
+            let output = quantize_all(&mut Ditherer::new(noise_shaping, 1, 1), &values);
+            assert!(output.iter().all(|&sample| sample.fract() == 0.0));
+            assert!(output.iter().all(|&sample| (-2.0..=3.0).contains(&sample)));
+            let mean = output.iter().sum::<f64>() / output.len() as f64;
+            assert!((mean - 0.3).abs() < 0.01, "{noise_shaping}: {mean}");
+        }
+
+        let seeded = quantize_all(&mut Ditherer::new(false, 7, 1), &values);
+        assert_eq!(
+            seeded,
+            quantize_all(&mut Ditherer::new(false, 7, 1), &values)
+        );
+        assert_ne!(
+            seeded,
+            quantize_all(&mut Ditherer::new(false, 8, 1), &values)
+        );
+    }
+
+    #[test]
+    fn derived_seeds_give_unrelated_noise() {
+        let values = vec![0.5; 1000];
+        let noise = |stream| {
+            quantize_all(
+                &mut Ditherer::new(false, derive_seed(7, stream), 1),
+                &values,
+            )
+        };
+        assert_eq!(noise(1), noise(1));
+        // Not even a few samples apart, as seeds one step of the sequence apart would be.
+        let (first, second) = (noise(0), noise(1));
+        for shift in 0..8 {
+            assert_ne!(first[shift..shift + 900], second[..900], "{shift}");
+            assert_ne!(second[shift..shift + 900], first[..900], "{shift}");
+        }
+    }
+
+    #[test]
+    fn noise_shaping_moves_error_out_of_low_frequencies() {
+        // With first-order feedback the error of each sample cancels the one before, so the
+        // running error, which carries its low-frequency content, stays within MAX_ERROR.
+        let values: Vec<f64> = (0..48000)
+            .map(|n| 100.0 * (n as f64 * 0.01).sin())
+            .collect();
+        let running_error = |noise_shaping| {
+            let output = quantize_all(&mut Ditherer::new(noise_shaping, 3, 2), &values);
+            // Interleaved stereo, so each channel's error is every other sample.
+            let mut sums = [0.0f64; 2];
+            let mut largest = 0.0f64;
+            for (index, (quantized, value)) in output.iter().zip(&values).enumerate() {
+                sums[index % 2] += quantized - value;
+                largest = largest.max(sums[index % 2].abs());
+            }
+            largest
+        };
+        assert!(running_error(true) <= MAX_ERROR, "{}", running_error(true));
+        assert!(running_error(false) > 10.0, "{}", running_error(false));
+    }
+}
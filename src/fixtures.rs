@@ -0,0 +1,164 @@
+use rustysynth::SoundFont;
+use std::io::Cursor;
+use std::num::{NonZeroU32, NonZeroU8};
+use std::sync::Arc;
+use vorbis_rs::VorbisEncoderBuilder;
+
+use crate::sf3::write_chunk;
+
+// Sample type of a mono sample in the `shdr` chunk.
+pub const MONO_SAMPLE: u16 = 1;
+
+fn records(size: usize, records: &[&[u8]]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for record in records {
+        body.extend_from_slice(record);
+        body.resize(body.len().next_multiple_of(size), 0);
+    }
+    body
+}
+
+fn name(name: &str) -> Vec<u8> {
+    let mut bytes = name.as_bytes().to_vec();
+    bytes.resize(20, 0);
+    bytes
+}
+
+fn u16s(values: &[u16]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+/// A SoundFont named "Test" with two presets of one instrument that loops the first
+/// sample: "Sine" at bank 0 program 0 across the keyboard, and "Low" at program 1, which
+/// plays only key 0 and so is silent for any other note.
+///
+/// `samples` are (start, end, loop start, loop end) of each sample at 44.1kHz, all of
+/// `sample_type`; `version` is the major version of the file format, 2 or 3.
+pub fn sound_font_file(
+    version: u16,
+    smpl: &[u8],
+    samples: &[[u32; 4]],
+    sample_type: u16,
+) -> Vec<u8> {
+    let mut headers: Vec<Vec<u8>> = samples
+        .iter()
+        .map(|sample| {
+            let mut record = name("Sine");
+            for value in sample.iter().chain(&[44100]) {
+                record.extend_from_slice(&value.to_le_bytes());
+            }
+            // Original pitch, correction and link.
+            record.extend_from_slice(&[60, 0, 0, 0]);
+            record.extend_from_slice(&sample_type.to_le_bytes());
+            record
+        })
+        .collect();
+    headers.push(name("EOS"));
+
+    let mut info = b"INFO".to_vec();
+    write_chunk(&mut info, b"ifil", &u16s(&[version, 1]));
+    write_chunk(&mut info, b"INAM", b"Test\0\0");
+    write_chunk(&mut info, b"ICOP", b"Public domain\0");
+    let mut sample_data = b"sdta".to_vec();
+    write_chunk(&mut sample_data, b"smpl", smpl);
+    let mut parameters = b"pdta".to_vec();
+    let header_refs: Vec<&[u8]> = headers.iter().map(Vec::as_slice).collect();
+    for (id, body) in [
+        (
+            b"phdr",
+            records(
+                38,
+                &[
+                    &[name("Sine"), u16s(&[0, 0, 0])].concat(),
+                    &[name("Low"), u16s(&[1, 0, 1])].concat(),
+                    &[name("EOP"), u16s(&[0, 0, 2])].concat(),
+                ],
+            ),
+        ),
+        (b"pbag", u16s(&[0, 0, 1, 0, 3, 0])),
+        (b"pmod", vec![0; 10]),
+        // Instrument 0; key range 0-0 and instrument 0; then the terminator.
+        (b"pgen", u16s(&[41, 0, 43, 0, 41, 0, 0, 0])),
+        (
+            b"inst",
+            records(
+                22,
+                &[
+                    &[name("Sine"), u16s(&[0])].concat(),
+                    &[name("EOI"), u16s(&[1])].concat(),
+                ],
+            ),
+        ),
+        (b"ibag", u16s(&[0, 0, 2, 0])),
+        (b"imod", vec![0; 10]),
+        // Looping sample 0, then the terminator.
+        (b"igen", u16s(&[54, 1, 53, 0, 0, 0])),
+        (b"shdr", records(46, &header_refs)),
+    ] {
+        write_chunk(&mut parameters, id, &body);
+    }
+
+    let mut body = b"sfbk".to_vec();
+    for list in [info, sample_data, parameters] {
+        write_chunk(&mut body, b"LIST", &list);
+    }
+    let mut sound_font = Vec::new();
+    write_chunk(&mut sound_font, b"RIFF", &body);
+    sound_font
+}
+
+/// `frames` of a sine wave at half scale, repeating every `period` frames.
+pub fn sine(frames: usize, period: f32) -> Vec<f32> {
+    (0..frames)
+        .map(|n| 0.5 * (n as f32 * std::f32::consts::TAU / period).sin())
+        .collect()
+}
+
+/// A mono 44.1kHz Ogg Vorbis stream, as SF3 stores each sample.
+pub fn vorbis_sample(samples: &[f32]) -> Vec<u8> {
+    let mut builder = VorbisEncoderBuilder::new_with_serial(
+        NonZeroU32::new(44100).unwrap(),
+        NonZeroU8::new(1).unwrap(),
+        Vec::new(),
+        1,
+    );
+    let mut encoder = builder.build().unwrap();
+    encoder.encode_audio_block([samples]).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// An SF2 SoundFont whose one sample is a seamlessly looping 441Hz sine at half scale.
+pub fn sine_sound_font() -> Arc<SoundFont> {
+    // 100 periods of 100 samples, followed by the padding the format requires.
+    let mut smpl = Vec::new();
+    for n in 0..10_000 + 46 {
+        let sample = if n < 10_000 {
+            (16384.0 * (n as f32 * std::f32::consts::TAU / 100.0).sin()) as i16
+        } else {
+            0
+        };
+        smpl.extend_from_slice(&sample.to_le_bytes());
+    }
+    let sf2 = sound_font_file(2, &smpl, &[[0, 10_000, 0, 10_000]], MONO_SAMPLE);
+    Arc::new(SoundFont::new(&mut Cursor::new(&sf2)).unwrap())
+}
+
+/// A format 1 MIDI file at 480 ticks per quarter note, ending each track's events with an
+/// end-of-track marker.
+pub fn midi_file(tracks: &[&[u8]]) -> Vec<u8> {
+    let mut midi = b"MThd".to_vec();
+    midi.extend_from_slice(&6u32.to_be_bytes());
+    midi.extend_from_slice(&1u16.to_be_bytes());
+    midi.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+    midi.extend_from_slice(&480u16.to_be_bytes());
+    for track in tracks {
+        midi.extend_from_slice(b"MTrk");
+        midi.extend_from_slice(&(track.len() as u32 + 4).to_be_bytes());
+        midi.extend_from_slice(track);
+        midi.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+    }
+    midi
+}
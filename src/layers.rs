@@ -0,0 +1,308 @@
+use rustysynth::{SoundFont, Synthesizer, SynthesizerError, SynthesizerSettings};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+const CHANNEL_COUNT: usize = 16;
+// The channel rustysynth plays from the drum banks, as General MIDI does.
+const PERCUSSION_CHANNEL: usize = 9;
+// Drum kits sit in bank 128 of a SoundFont, above the banks a Bank Select can reach.
+const PERCUSSION_BANK: i32 = 128;
+
+/// A SoundFont in a `SoundFontStack`, with where its presets apply.
+#[derive(Clone)]
+pub struct SoundFontLayer {
+    pub sound_font: Arc<SoundFont>,
+    /// Added to the SoundFont's bank numbers, so a Bank Select of `bank_offset` reaches its
+    /// bank 0 and its presets can sit beside those of another SoundFont.
+    pub bank_offset: u8,
+    /// The MIDI channels that may play from this SoundFont; `None` allows all of them.
+    pub channels: Option<Vec<u8>>,
+}
+
+impl SoundFontLayer {
+    pub fn new(sound_font: Arc<SoundFont>) -> Self {
+        Self {
+            sound_font,
+            bank_offset: 0,
+            channels: None,
+        }
+    }
+}
+
+/// SoundFonts rendered together, in order of priority.
+///
+/// Each note plays from the first layer open to its channel that has the channel's preset.
+/// When none has it, the first layer with the General MIDI fallback preset (the same
+/// program in bank 0, or the standard drum kit) plays it, and failing that the last layer.
+#[derive(Clone)]
+pub struct SoundFontStack {
+    layers: Vec<SoundFontLayer>,
+}
+
+impl SoundFontStack {
+    pub fn new(layers: Vec<SoundFontLayer>) -> Self {
+        Self { layers }
+    }
+}
+
+impl From<Arc<SoundFont>> for SoundFontStack {
+    fn from(sound_font: Arc<SoundFont>) -> Self {
+        Self::new(vec![SoundFontLayer::new(sound_font)])
+    }
+}
+
+/// One synthesizer per layer of a `SoundFontStack`, mixed into a single output.
+///
+/// Every layer follows all the controllers and program changes, so each is ready for the
+/// notes routed to it; a note-on only reaches the layer chosen for it.
+pub struct LayeredSynthesizer {
+    synthesizers: Vec<Synthesizer>,
+    bank_offsets: Vec<i32>,
+    routing: Routing,
+    scratch_left: Vec<f32>,
+    scratch_right: Vec<f32>,
+}
+
+impl LayeredSynthesizer {
+    pub fn new(
+        stack: &SoundFontStack,
+        settings: &SynthesizerSettings,
+    ) -> Result<Self, SynthesizerError> {
+        let synthesizers = stack
+            .layers
+            .iter()
+            .map(|layer| Synthesizer::new(&layer.sound_font, settings))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            synthesizers,
+            bank_offsets: stack
+                .layers
+                .iter()
+                .map(|layer| layer.bank_offset as i32)
+                .collect(),
+            routing: Routing::new(stack.layers.iter().map(LayerRoute::of).collect()),
+            scratch_left: Vec::new(),
+            scratch_right: Vec::new(),
+        })
+    }
+
+    pub fn get_block_size(&self) -> usize {
+        self.synthesizers[0].get_block_size()
+    }
+
+    pub fn get_sample_rate(&self) -> i32 {
+        self.synthesizers[0].get_sample_rate()
+    }
+
+    pub fn process_midi_message(&mut self, channel: i32, command: i32, data1: i32, data2: i32) {
+        if command == 0x90 && data2 > 0 {
+            self.note_on(channel, data1, data2);
+            return;
+        }
+        self.routing.observe(channel, command, data1, data2);
+        let bank_select = command == 0xB0 && data1 == 0x00;
+        for (synthesizer, offset) in self.synthesizers.iter_mut().zip(&self.bank_offsets) {
+            // Each layer sees banks relative to its own offset.
+            let data2 = if bank_select {
+                (data2 - offset).max(0)
+            } else {
+                data2
+            };
+            synthesizer.process_midi_message(channel, command, data1, data2);
+        }
+    }
+
+    pub fn note_on(&mut self, channel: i32, key: i32, velocity: i32) {
+        if let Some(layer) = self.routing.layer_for(channel) {
+            self.synthesizers[layer].note_on(channel, key, velocity);
+        }
+    }
+
+    pub fn note_off_all(&mut self, immediate: bool) {
+        for synthesizer in &mut self.synthesizers {
+            synthesizer.note_off_all(immediate);
+        }
+    }
+
+    pub fn render(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let (first, rest) = self.synthesizers.split_first_mut().unwrap();
+        first.render(left, right);
+        if rest.is_empty() {
+            return;
+        }
+
+        self.scratch_left.resize(left.len(), 0.0);
+        self.scratch_right.resize(right.len(), 0.0);
+        for synthesizer in rest {
+            synthesizer.render(&mut self.scratch_left, &mut self.scratch_right);
+            for (sample, layer) in left.iter_mut().zip(&self.scratch_left) {
+                *sample += layer;
+            }
+            for (sample, layer) in right.iter_mut().zip(&self.scratch_right) {
+                *sample += layer;
+            }
+        }
+    }
+}
+
+/// What routing needs to know of a layer.
+struct LayerRoute {
+    // Preset IDs as rustysynth forms them: the bank in the upper 16 bits, then the program.
+    presets: HashSet<i32>,
+    bank_offset: i32,
+    channels: [bool; CHANNEL_COUNT],
+}
+
+impl LayerRoute {
+    fn of(layer: &SoundFontLayer) -> Self {
+        let mut channels = [layer.channels.is_none(); CHANNEL_COUNT];
+        for &channel in layer.channels.iter().flatten() {
+            if let Some(open) = channels.get_mut(channel as usize) {
+                *open = true;
+            }
+        }
+        Self {
+            presets: layer
+                .sound_font
+                .get_presets()
+                .iter()
+                .map(|preset| preset_id(preset.get_bank_number(), preset.get_patch_number()))
+                .collect(),
+            bank_offset: layer.bank_offset as i32,
+            channels,
+        }
+    }
+
+    /// Whether the layer has the preset at `bank`, counted with its offset.
+    fn has(&self, bank: i32, program: i32, percussion: bool) -> bool {
+        let bank = bank - self.bank_offset;
+        if bank < 0 {
+            return false;
+        }
+        let bank = if percussion {
+            bank + PERCUSSION_BANK
+        } else {
+            bank
+        };
+        self.presets.contains(&preset_id(bank, program))
+    }
+}
+
+/// Tracks the bank and program of each channel to choose the layer that plays its notes.
+struct Routing {
+    layers: Vec<LayerRoute>,
+    banks: [i32; CHANNEL_COUNT],
+    programs: [i32; CHANNEL_COUNT],
+}
+
+impl Routing {
+    fn new(layers: Vec<LayerRoute>) -> Self {
+        Self {
+            layers,
+            banks: [0; CHANNEL_COUNT],
+            programs: [0; CHANNEL_COUNT],
+        }
+    }
+
+    fn observe(&mut self, channel: i32, command: i32, data1: i32, data2: i32) {
+        let Some(channel) = usize::try_from(channel)
+            .ok()
+            .filter(|&channel| channel < CHANNEL_COUNT)
+        else {
+            return;
+        };
+        match command {
+            0xB0 if data1 == 0x00 => self.banks[channel] = data2,
+            0xC0 => self.programs[channel] = data1,
+            _ => {}
+        }
+    }
+
+    /// The layer that plays notes on `channel`, or `None` when no layer is open to it.
+    fn layer_for(&self, channel: i32) -> Option<usize> {
+        let channel = usize::try_from(channel)
+            .ok()
+            .filter(|&channel| channel < CHANNEL_COUNT)?;
+        let percussion = channel == PERCUSSION_CHANNEL;
+        let (bank, program) = (self.banks[channel], self.programs[channel]);
+        // The same fallback as rustysynth: the program in bank 0, or the standard drum kit.
+        let fallback = if percussion { (0, 0) } else { (0, program) };
+
+        let open: Vec<usize> = (0..self.layers.len())
+            .filter(|&layer| self.layers[layer].channels[channel])
+            .collect();
+        open.iter()
+            .find(|&&layer| self.layers[layer].has(bank, program, percussion))
+            .or_else(|| {
+                open.iter()
+                    .find(|&&layer| self.layers[layer].has(fallback.0, fallback.1, percussion))
+            })
+            .or(open.last())
+            .copied()
+    }
+}
+
+fn preset_id(bank: i32, program: i32) -> i32 {
+    (bank << 16) | program
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(presets: &[(i32, i32)], bank_offset: i32, channels: Option<&[usize]>) -> LayerRoute {
+        let mut open = [channels.is_none(); CHANNEL_COUNT];
+        for &channel in channels.unwrap_or(&[]) {
+            open[channel] = true;
+        }
+        LayerRoute {
+            presets: presets
+                .iter()
+                .map(|&(bank, program)| preset_id(bank, program))
+                .collect(),
+            bank_offset,
+            channels: open,
+        }
+    }
+
+    #[test]
+    fn notes_go_to_the_first_layer_with_the_preset() {
+        let piano = layer(&[(0, 0)], 0, None);
+        let general_midi = layer(&[(0, 0), (0, 5), (8, 5), (128, 0)], 0, None);
+        let effects = layer(&[(0, 5)], 100, None);
+        let mut routing = Routing::new(vec![piano, general_midi, effects]);
+
+        // Program 0 from the piano layer, everything else from General MIDI.
+        assert_eq!(routing.layer_for(0), Some(0));
+        routing.observe(0, 0xC0, 5, 0);
+        assert_eq!(routing.layer_for(0), Some(1));
+        routing.observe(0, 0xB0, 0x00, 8);
+        assert_eq!(routing.layer_for(0), Some(1));
+        // Bank 100 is the effects layer's bank 0.
+        routing.observe(0, 0xB0, 0x00, 100);
+        assert_eq!(routing.layer_for(0), Some(2));
+        // A missing bank falls back to the program in bank 0, before the last layer.
+        routing.observe(0, 0xB0, 0x00, 3);
+        assert_eq!(routing.layer_for(0), Some(1));
+        routing.observe(0, 0xC0, 42, 0);
+        assert_eq!(routing.layer_for(0), Some(2));
+
+        // Drum kits are looked up in bank 128 on the percussion channel.
+        routing.observe(9, 0xC0, 0, 0);
+        assert_eq!(routing.layer_for(9), Some(1));
+        assert_eq!(routing.layer_for(16), None);
+    }
+
+    #[test]
+    fn channel_assignments_limit_the_layers() {
+        let strings = layer(&[(0, 0)], 0, Some(&[1, 2]));
+        let piano = layer(&[(0, 0)], 0, Some(&[0]));
+        let mut routing = Routing::new(vec![strings, piano]);
+        assert_eq!(routing.layer_for(0), Some(1));
+        assert_eq!(routing.layer_for(2), Some(0));
+        assert_eq!(routing.layer_for(3), None);
+        // A preset neither has still plays from the last layer open to the channel.
+        routing.observe(2, 0xC0, 7, 0);
+        assert_eq!(routing.layer_for(2), Some(0));
+    }
+}
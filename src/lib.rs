@@ -1,49 +1,1179 @@
+// The #[pyfunction] expansion in pyo3 0.22 trips this lint on `PyResult` returns.
+#![allow(clippy::useless_conversion)]
+
+use numpy::{PyArray1, PyArrayMethods};
 use pyo3::prelude::*;
-use pyo3::types::PyBytes;
+use pyo3::types::{PyBytes, PyDict, PyList, PyTuple};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::Arc;
 
 mod audio_utils;
-use audio_utils::{render_midi_to_wav, wav_to_opus_ogg, OpusBitrate};
+mod dither;
+#[cfg(test)]
+mod fixtures;
+mod layers;
+mod loudness;
+mod midi;
+mod overload;
+mod resampler;
+mod sf3;
+use audio_utils::{
+    load_soundfont, render_midi_stems, render_midi_to_flac, render_midi_to_mp3,
+    render_midi_to_opus, render_midi_to_pcm, render_midi_to_vorbis, render_midi_to_wav,
+    wav_to_opus_ogg, AudioError, Dither, Downmix, FadeCurve, Fades, FlacOptions, Looping, Loudness,
+    Mp3Bitrate, Mp3Options, OpusBitrate, OutputChannels, Overload, OverloadReport, PcmLayout,
+    RenderOptions, RenderStream, SampleFormat, StemSplit, StreamFormat, Tail, Trim,
+    DEFAULT_SAMPLE_RATE, MP3_BITRATES,
+};
+use layers::{SoundFontLayer, SoundFontStack};
 
-#[pyfunction]
-fn render_wave_from<'py>(
-    py: Python<'py>,
-    soundfont_bytes: &[u8],
-    midi_bytes: &[u8],
-) -> PyResult<Bound<'py, PyBytes>> {
-    let wav_data = render_midi_to_wav(soundfont_bytes, midi_bytes)
-        .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))?;
-    Ok(PyBytes::new_bound(py, &wav_data))
+const DEFAULT_TAIL_THRESHOLD_DB: f32 = -60.0;
+const DEFAULT_MAX_TAIL_SECONDS: f64 = 10.0;
+const DEFAULT_TRUE_PEAK_DB: f64 = -1.0;
+const DEFAULT_LIMITER_CEILING_DB: f32 = -1.0;
+const DEFAULT_LIMITER_RELEASE_SECONDS: f64 = 0.05;
+const DEFAULT_TRIM_THRESHOLD_DB: f32 = -60.0;
+const DEFAULT_CHUNK_SIZE: usize = 16384;
+const DEFAULT_FLAC_COMPRESSION_LEVEL: u8 = 5;
+const DEFAULT_VORBIS_QUALITY: f32 = 5.0;
+
+/// A parsed SoundFont that can be reused across renders.
+#[pyclass(name = "SoundFont", module = "midirenderer", frozen)]
+struct PySoundFont {
+    inner: Arc<rustysynth::SoundFont>,
+}
+
+#[pymethods]
+impl PySoundFont {
+    #[new]
+    fn new(py: Python<'_>, soundfont_bytes: &[u8]) -> PyResult<Self> {
+        let inner = py
+            .allow_threads(|| load_soundfont(soundfont_bytes))
+            .map_err(to_py_error)?;
+        Ok(Self { inner })
+    }
+
+    /// The presets, ordered by bank and program.
+    #[getter]
+    fn presets(&self) -> Vec<PyPreset> {
+        let mut presets: Vec<PyPreset> = self
+            .inner
+            .get_presets()
+            .iter()
+            .map(|preset| PyPreset {
+                name: preset.get_name().to_string(),
+                bank: preset.get_bank_number(),
+                program: preset.get_patch_number(),
+            })
+            .collect();
+        presets.sort_by_key(|preset| (preset.bank, preset.program));
+        presets
+    }
+
+    /// The names of the instruments, in file order.
+    #[getter]
+    fn instruments(&self) -> Vec<String> {
+        self.inner
+            .get_instruments()
+            .iter()
+            .map(|instrument| instrument.get_name().to_string())
+            .collect()
+    }
+
+    #[getter]
+    fn sample_count(&self) -> usize {
+        self.inner.get_sample_headers().len()
+    }
+
+    /// Bytes of 16-bit sample data held in memory, after decoding SF3 samples.
+    #[getter]
+    fn sample_memory(&self) -> usize {
+        std::mem::size_of_val(self.inner.get_wave_data())
+    }
+
+    /// The INFO chunk, keyed by field. Fields the file leaves out are empty strings.
+    #[getter]
+    fn info<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let dict = PyDict::new_bound(py);
+        for (key, value) in info_fields(self.inner.get_info()) {
+            dict.set_item(key, value)?;
+        }
+        Ok(dict)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "SoundFont(bank_name={:?}, presets={}, samples={})",
+            self.inner.get_info().get_bank_name(),
+            self.inner.get_presets().len(),
+            self.sample_count()
+        )
+    }
+}
+
+/// The fields of `SoundFont.info`, in the order of the INFO chunk's sub-chunks.
+fn info_fields(info: &rustysynth::SoundFontInfo) -> [(&'static str, String); 11] {
+    let version = |version: &rustysynth::SoundFontVersion| {
+        format!("{}.{:02}", version.get_major(), version.get_minor())
+    };
+    [
+        ("version", version(info.get_version())),
+        ("sound_engine", info.get_target_sound_engine().to_string()),
+        ("bank_name", info.get_bank_name().to_string()),
+        ("rom_name", info.get_rom_name().to_string()),
+        ("rom_version", version(info.get_rom_version())),
+        ("creation_date", info.get_creation_date().to_string()),
+        ("author", info.get_author().to_string()),
+        ("product", info.get_target_product().to_string()),
+        ("copyright", info.get_copyright().to_string()),
+        ("comments", info.get_comments().to_string()),
+        ("tools", info.get_tools().to_string()),
+    ]
+}
+
+/// A preset of a `SoundFont`, as listed by `SoundFont.presets`.
+#[pyclass(name = "Preset", module = "midirenderer", frozen, get_all)]
+struct PyPreset {
+    name: String,
+    bank: i32,
+    program: i32,
+}
+
+#[pymethods]
+impl PyPreset {
+    fn __repr__(&self) -> String {
+        format!(
+            "Preset(name={:?}, bank={}, program={})",
+            self.name, self.bank, self.program
+        )
+    }
+}
+
+/// A SoundFont in a list of SoundFonts, moved to other banks or limited to some channels.
+#[pyclass(name = "SoundFontLayer", module = "midirenderer", frozen)]
+struct PySoundFontLayer {
+    inner: SoundFontLayer,
+}
+
+#[pymethods]
+impl PySoundFontLayer {
+    #[new]
+    #[pyo3(signature = (soundfont, bank_offset=0, channels=None))]
+    fn new(
+        py: Python<'_>,
+        soundfont: &Bound<'_, PyAny>,
+        bank_offset: u8,
+        channels: Option<Vec<u8>>,
+    ) -> PyResult<Self> {
+        if bank_offset > 127 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "bank_offset must be between 0 and 127",
+            ));
+        }
+        if channels.iter().flatten().any(|&channel| channel > 15) {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "channels must be between 0 and 15",
+            ));
+        }
+        let sound_font = match SoundFontSource::extract_single(soundfont)? {
+            SoundFontSource::Loaded(sound_font) => sound_font,
+            SoundFontSource::Bytes(soundfont_bytes) => py
+                .allow_threads(|| load_soundfont(&soundfont_bytes))
+                .map_err(to_py_error)?,
+            _ => {
+                return Err(PyErr::new::<pyo3::exceptions::PyTypeError, _>(
+                    "soundfont must be bytes or a SoundFont",
+                ))
+            }
+        };
+        Ok(Self {
+            inner: SoundFontLayer {
+                sound_font,
+                bank_offset,
+                channels,
+            },
+        })
+    }
+}
+
+/// What overload handling did to a render, returned alongside it when `report=True`.
+#[pyclass(name = "OverloadReport", module = "midirenderer", frozen, get_all)]
+struct PyOverloadReport {
+    affected_samples: usize,
+    total_samples: usize,
+    // In dBFS, unlike the linear peak of `OverloadReport`.
+    peak_db: f64,
+}
+
+#[pymethods]
+impl PyOverloadReport {
+    fn __repr__(&self) -> String {
+        format!(
+            "OverloadReport(affected_samples={}, total_samples={}, peak_db={:.2})",
+            self.affected_samples, self.total_samples, self.peak_db
+        )
+    }
 }
 
-#[pyfunction]
-#[pyo3(signature = (soundfont_bytes, midi_bytes, stereo=true, bitrate="auto"))]
-fn render_opus_from<'py>(
+impl From<OverloadReport> for PyOverloadReport {
+    fn from(report: OverloadReport) -> Self {
+        Self {
+            affected_samples: report.affected_samples,
+            total_samples: report.total_samples,
+            peak_db: 20.0 * (report.peak as f64).log10(),
+        }
+    }
+}
+
+/// Returns the rendered data, paired with its overload report when one was asked for.
+fn with_report<'py>(
     py: Python<'py>,
-    soundfont_bytes: &[u8],
-    midi_bytes: &[u8],
-    stereo: bool,
-    bitrate: &str,
-) -> PyResult<Bound<'py, PyBytes>> {
-    let wav_data = render_midi_to_wav(soundfont_bytes, midi_bytes)
-        .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))?;
-
-    let opus_bitrate = match bitrate {
-        "auto" => OpusBitrate::Auto,
-        "max" => OpusBitrate::Max,
-        _ => bitrate.parse::<i32>().map(OpusBitrate::Bits).map_err(|_| {
-            PyErr::new::<pyo3::exceptions::PyValueError, _>("Invalid bitrate value")
-        })?,
+    data: Bound<'py, PyAny>,
+    report: OverloadReport,
+    wanted: bool,
+) -> PyResult<Bound<'py, PyAny>> {
+    if !wanted {
+        return Ok(data);
+    }
+    let report = Bound::new(py, PyOverloadReport::from(report))?.into_any();
+    Ok(PyTuple::new_bound(py, [data, report]).into_any())
+}
+
+/// A SoundFont argument, detached from the GIL so it can be resolved while rendering.
+enum SoundFontSource {
+    Loaded(Arc<rustysynth::SoundFont>),
+    Bytes(Vec<u8>),
+    Layer(SoundFontLayer),
+    /// Several SoundFonts, in order of priority.
+    Stack(Vec<SoundFontSource>),
+}
+
+impl SoundFontSource {
+    /// Accepts a loaded `SoundFont`, a `SoundFontLayer`, the raw bytes of an .sf2 or .sf3
+    /// file, or a list of those.
+    fn extract(soundfont: &Bound<'_, PyAny>) -> PyResult<Self> {
+        if soundfont.is_instance_of::<PyList>() || soundfont.is_instance_of::<PyTuple>() {
+            let layers = soundfont
+                .iter()?
+                .map(|layer| Self::extract_single(&layer?))
+                .collect::<PyResult<Vec<_>>>()?;
+            if layers.is_empty() {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    "The list of SoundFonts is empty",
+                ));
+            }
+            return Ok(Self::Stack(layers));
+        }
+        Self::extract_single(soundfont)
+    }
+
+    fn extract_single(soundfont: &Bound<'_, PyAny>) -> PyResult<Self> {
+        if let Ok(loaded) = soundfont.downcast::<PySoundFont>() {
+            return Ok(Self::Loaded(Arc::clone(&loaded.get().inner)));
+        }
+        if let Ok(layer) = soundfont.downcast::<PySoundFontLayer>() {
+            return Ok(Self::Layer(layer.get().inner.clone()));
+        }
+        let soundfont_bytes: &[u8] = soundfont.extract()?;
+        Ok(Self::Bytes(soundfont_bytes.to_vec()))
+    }
+
+    fn load(&self) -> Result<SoundFontStack, AudioError> {
+        match self {
+            Self::Stack(layers) => Ok(SoundFontStack::new(
+                layers
+                    .iter()
+                    .map(Self::load_layer)
+                    .collect::<Result<_, _>>()?,
+            )),
+            _ => Ok(SoundFontStack::new(vec![self.load_layer()?])),
+        }
+    }
+
+    fn load_layer(&self) -> Result<SoundFontLayer, AudioError> {
+        match self {
+            Self::Loaded(sound_font) => Ok(SoundFontLayer::new(Arc::clone(sound_font))),
+            Self::Bytes(soundfont_bytes) => {
+                load_soundfont(soundfont_bytes).map(SoundFontLayer::new)
+            }
+            Self::Layer(layer) => Ok(layer.clone()),
+            Self::Stack(_) => unreachable!("lists of SoundFonts are not nested"),
+        }
+    }
+}
+
+/// The rendering options shared by every render function, as Python passed them.
+struct RenderArgs<'a> {
+    sample_rate: u32,
+    tail: Option<&'a Bound<'a, PyAny>>,
+    tail_threshold: f32,
+    max_tail: f64,
+    start: f64,
+    end: Option<f64>,
+    loop_count: usize,
+    loop_start: Option<f64>,
+    loop_end: Option<f64>,
+    loop_intro: bool,
+    enable_reverb_and_chorus: Option<bool>,
+    block_size: Option<usize>,
+    maximum_polyphony: Option<usize>,
+    target_lufs: Option<f64>,
+    true_peak: f64,
+    overload: &'a str,
+    limiter_ceiling: f32,
+    limiter_release: f64,
+    trim: Option<&'a str>,
+    trim_threshold: f32,
+    fade_in: f64,
+    fade_out: f64,
+    fade_curve: &'a str,
+    dither: &'a str,
+    dither_seed: Option<u64>,
+}
+
+/// Declares a render function taking a SoundFont and a MIDI file, then any parameters
+/// that may also be passed by position, then only keyword arguments: its own after the
+/// `*`, followed by the rendering options every render function shares. The body sees
+/// those options checked and collected in the `RenderOptions` named by `$options`.
+macro_rules! render_function {
+    (
+        fn $name:ident<$py:lifetime>(
+            $python:ident,
+            $soundfont:ident,
+            $midi:ident,
+            $options:ident
+            $(, $positional:ident: $positional_type:ty = $positional_default:tt)*
+            , *
+            $(, $param:ident: $type:ty = $default:tt)* $(,)?
+        ) -> $output:ty $body:block
+    ) => {
+        #[pyfunction]
+        #[pyo3(signature = (
+            $soundfont,
+            $midi,
+            $($positional = $positional_default,)*
+            *,
+            $($param = $default,)*
+            sample_rate = DEFAULT_SAMPLE_RATE,
+            tail = None,
+            tail_threshold = DEFAULT_TAIL_THRESHOLD_DB,
+            max_tail = DEFAULT_MAX_TAIL_SECONDS,
+            start = 0.0,
+            end = None,
+            loop_count = 0,
+            loop_start = None,
+            loop_end = None,
+            loop_intro = true,
+            enable_reverb_and_chorus = None,
+            block_size = None,
+            maximum_polyphony = None,
+            target_lufs = None,
+            true_peak = DEFAULT_TRUE_PEAK_DB,
+            overload = "clip",
+            limiter_ceiling = DEFAULT_LIMITER_CEILING_DB,
+            limiter_release = DEFAULT_LIMITER_RELEASE_SECONDS,
+            trim = None,
+            trim_threshold = DEFAULT_TRIM_THRESHOLD_DB,
+            fade_in = 0.0,
+            fade_out = 0.0,
+            fade_curve = "linear",
+            dither = "none",
+            dither_seed = None,
+        ))]
+        #[allow(clippy::too_many_arguments)]
+        fn $name<$py>(
+            $python: Python<$py>,
+            $soundfont: &Bound<$py, PyAny>,
+            $midi: &[u8],
+            $($positional: $positional_type,)*
+            $($param: $type,)*
+            sample_rate: u32,
+            tail: Option<&Bound<$py, PyAny>>,
+            tail_threshold: f32,
+            max_tail: f64,
+            start: f64,
+            end: Option<f64>,
+            loop_count: usize,
+            loop_start: Option<f64>,
+            loop_end: Option<f64>,
+            loop_intro: bool,
+            enable_reverb_and_chorus: Option<bool>,
+            block_size: Option<usize>,
+            maximum_polyphony: Option<usize>,
+            target_lufs: Option<f64>,
+            true_peak: f64,
+            overload: &str,
+            limiter_ceiling: f32,
+            limiter_release: f64,
+            trim: Option<&str>,
+            trim_threshold: f32,
+            fade_in: f64,
+            fade_out: f64,
+            fade_curve: &str,
+            dither: &str,
+            dither_seed: Option<u64>,
+        ) -> PyResult<$output> {
+            let $options = extract_render_options(RenderArgs {
+                sample_rate,
+                tail,
+                tail_threshold,
+                max_tail,
+                start,
+                end,
+                loop_count,
+                loop_start,
+                loop_end,
+                loop_intro,
+                enable_reverb_and_chorus,
+                block_size,
+                maximum_polyphony,
+                target_lufs,
+                true_peak,
+                overload,
+                limiter_ceiling,
+                limiter_release,
+                trim,
+                trim_threshold,
+                fade_in,
+                fade_out,
+                fade_curve,
+                dither,
+                dither_seed,
+            })?;
+            $body
+        }
+    };
+}
+
+/// Checks the rendering options shared by every render function.
+fn extract_render_options(args: RenderArgs<'_>) -> PyResult<RenderOptions> {
+    let mut render_options = RenderOptions {
+        sample_rate: args.sample_rate,
+        start: args.start,
+        end: args.end,
+        enable_reverb_and_chorus: args.enable_reverb_and_chorus,
+        block_size: args.block_size,
+        maximum_polyphony: args.maximum_polyphony,
+        ..RenderOptions::default()
     };
 
-    let opus_ogg_data = wav_to_opus_ogg(&wav_data, stereo, opus_bitrate)
-        .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))?;
+    if let Some(tail) = args.tail {
+        render_options.tail = extract_tail(tail, args.tail_threshold, args.max_tail)?;
+    }
+    if let Some(target) = args.target_lufs {
+        if !(-70.0..0.0).contains(&target) {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "target_lufs must be between -70 and 0",
+            ));
+        }
+        if args.true_peak.is_nan() || args.true_peak > 0.0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "true_peak must not be above 0",
+            ));
+        }
+        render_options.loudness = Some(Loudness {
+            target,
+            true_peak: args.true_peak,
+        });
+    }
+    render_options.overload =
+        parse_overload(args.overload, args.limiter_ceiling, args.limiter_release)?;
+    render_options.dither = parse_dither(args.dither, args.dither_seed)?;
+    if let Some(trim) = args.trim {
+        let (start, end) = match trim {
+            "start" => (true, false),
+            "end" => (false, true),
+            "both" => (true, true),
+            _ => {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    "Invalid trim value, expected 'start', 'end' or 'both'",
+                ))
+            }
+        };
+        render_options.trim = Some(Trim {
+            start,
+            end,
+            threshold: 10f32.powf(args.trim_threshold / 20.0),
+        });
+    }
+    let (fade_in, fade_out) = (args.fade_in, args.fade_out);
+    let curve = match args.fade_curve {
+        "linear" => FadeCurve::Linear,
+        "exponential" => FadeCurve::Exponential,
+        _ => {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "Invalid fade_curve, expected 'linear' or 'exponential'",
+            ))
+        }
+    };
+    // Anything but no fades at all, including NaN, is left for `validate` to check.
+    if fade_in != 0.0 || fade_out != 0.0 {
+        render_options.fades = Some(Fades {
+            fade_in,
+            fade_out,
+            curve,
+        });
+    }
+    if args.loop_count > 0 {
+        if render_options.start > 0.0 || render_options.end.is_some() {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "start and end cannot be combined with loop_count; use loop_start and loop_end",
+            ));
+        }
+        if render_options.trim.is_some() {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "trim cannot be combined with loop_count, as it would move the loop points",
+            ));
+        }
+        if render_options
+            .fades
+            .is_some_and(|fades| fades.fade_out > 0.0)
+        {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "fade_out cannot be combined with loop_count, as every loop would fade out",
+            ));
+        }
+        render_options.looping = Some(Looping {
+            start: args.loop_start,
+            end: args.loop_end,
+            repetitions: args.loop_count,
+            intro: args.loop_intro,
+        });
+    }
+    render_options.validate().map_err(to_py_error)?;
 
-    Ok(PyBytes::new_bound(py, &opus_ogg_data))
+    Ok(render_options)
+}
+
+/// Reads the `overload` mode; the limiter settings only apply to "limit".
+fn parse_overload(overload: &str, ceiling_db: f32, release: f64) -> PyResult<Overload> {
+    match overload {
+        "clip" => Ok(Overload::Clip),
+        "soft" => Ok(Overload::Soft),
+        "limit" => {
+            if ceiling_db.is_nan() || ceiling_db > 0.0 {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    "limiter_ceiling must not be above 0",
+                ));
+            }
+            if release.is_nan() || release <= 0.0 {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    "limiter_release must be positive",
+                ));
+            }
+            Ok(Overload::Limit {
+                ceiling: 10f32.powf(ceiling_db / 20.0),
+                release,
+            })
+        }
+        _ => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+            "Invalid overload, expected 'clip', 'soft' or 'limit'",
+        )),
+    }
+}
+
+/// Reads the `dither` mode. Without a seed every render gets different noise.
+fn parse_dither(dither: &str, seed: Option<u64>) -> PyResult<Dither> {
+    if dither == "none" && seed.is_some() {
+        return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+            "dither_seed needs dither set to 'tpdf' or 'shaped'",
+        ));
+    }
+    let seed = seed.unwrap_or_else(|| RandomState::new().hash_one(0));
+    match dither {
+        "none" => Ok(Dither::None),
+        "tpdf" => Ok(Dither::Tpdf { seed }),
+        "shaped" => Ok(Dither::Shaped { seed }),
+        _ => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+            "Invalid dither, expected 'none', 'tpdf' or 'shaped'",
+        )),
+    }
+}
+
+/// Accepts a tail length in seconds, or "silent" to render until the output dies away.
+fn extract_tail(tail: &Bound<'_, PyAny>, threshold_db: f32, max_seconds: f64) -> PyResult<Tail> {
+    if tail.is_none() {
+        return Ok(Tail::None);
+    }
+    if let Ok(mode) = tail.extract::<String>() {
+        if mode != "silent" {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "Invalid tail value",
+            ));
+        }
+        return Ok(Tail::UntilSilent {
+            threshold: 10f32.powf(threshold_db / 20.0),
+            max_seconds,
+        });
+    }
+
+    Ok(Tail::Seconds(tail.extract()?))
+}
+
+render_function! {
+    fn render_wave_from<'py>(
+        py,
+        soundfont_bytes,
+        midi_bytes,
+        render_options,
+        stereo: bool = true,
+        *,
+        downmix: &str = "average",
+        sample_format: &str = "s16",
+        report: bool = false,
+    ) -> Bound<'py, PyAny> {
+        let channels = output_channels(stereo, downmix)?;
+        let sample_format = parse_sample_format(sample_format)?;
+        let soundfont = SoundFontSource::extract(soundfont_bytes)?;
+        let midi_bytes = midi_bytes.to_vec();
+
+        let (wav_data, overload_report) = py
+            .allow_threads(|| {
+                render_midi_to_wav(
+                    &soundfont.load()?,
+                    &midi_bytes,
+                    channels,
+                    sample_format,
+                    &render_options,
+                )
+            })
+            .map_err(to_py_error)?;
+        let wav_data = PyBytes::new_bound(py, &wav_data).into_any();
+        with_report(py, wav_data, overload_report, report)
+    }
+}
+
+render_function! {
+    fn render_pcm<'py>(
+        py,
+        soundfont_bytes,
+        midi_bytes,
+        render_options,
+        *,
+        stereo: bool = true,
+        downmix: &str = "average",
+        layout: &str = "interleaved",
+        report: bool = false,
+    ) -> Bound<'py, PyAny> {
+        // numpy is an optional dependency; fail with its ImportError before rendering.
+        py.import_bound("numpy")?;
+        let channels = output_channels(stereo, downmix)?;
+        let layout = match layout {
+            "interleaved" => PcmLayout::Interleaved,
+            "planar" => PcmLayout::Planar,
+            _ => {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    "Invalid layout, expected 'interleaved' or 'planar'",
+                ))
+            }
+        };
+        let soundfont = SoundFontSource::extract(soundfont_bytes)?;
+        let midi_bytes = midi_bytes.to_vec();
+
+        let (samples, overload_report) = py
+            .allow_threads(|| {
+                render_midi_to_pcm(
+                    &soundfont.load()?,
+                    &midi_bytes,
+                    channels,
+                    layout,
+                    &render_options,
+                )
+            })
+            .map_err(to_py_error)?;
+
+        let channel_count = channels.count();
+        let frames = samples.len() / channel_count;
+        let shape = match layout {
+            PcmLayout::Interleaved => [frames, channel_count],
+            PcmLayout::Planar => [channel_count, frames],
+        };
+        // The array takes ownership of the samples, and reshaping a contiguous array is a view.
+        let samples = PyArray1::from_vec_bound(py, samples).reshape(shape)?;
+        with_report(py, samples.into_any(), overload_report, report)
+    }
+}
+
+render_function! {
+    fn render_flac_from<'py>(
+        py,
+        soundfont_bytes,
+        midi_bytes,
+        render_options,
+        *,
+        stereo: bool = true,
+        downmix: &str = "average",
+        sample_format: &str = "s16",
+        compression_level: u8 = DEFAULT_FLAC_COMPRESSION_LEVEL,
+        tags: Option<&Bound<'py, PyDict>> = None,
+        report: bool = false,
+    ) -> Bound<'py, PyAny> {
+        let channels = output_channels(stereo, downmix)?;
+        let sample_format = parse_sample_format(sample_format)?;
+        if !matches!(sample_format, SampleFormat::S16 | SampleFormat::S24) {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "Invalid FLAC sample format, expected 's16' or 's24'",
+            ));
+        }
+        if compression_level > 8 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "compression_level must be between 0 and 8",
+            ));
+        }
+        let flac_options = FlacOptions {
+            sample_format,
+            compression_level,
+            tags: extract_tags(tags)?,
+        };
+        let soundfont = SoundFontSource::extract(soundfont_bytes)?;
+        let midi_bytes = midi_bytes.to_vec();
+
+        let (flac_data, overload_report) = py
+            .allow_threads(|| {
+                render_midi_to_flac(
+                    &soundfont.load()?,
+                    &midi_bytes,
+                    channels,
+                    &flac_options,
+                    &render_options,
+                )
+            })
+            .map_err(to_py_error)?;
+        let flac_data = PyBytes::new_bound(py, &flac_data).into_any();
+        with_report(py, flac_data, overload_report, report)
+    }
+}
+
+render_function! {
+    fn render_opus_from<'py>(
+        py,
+        soundfont_bytes,
+        midi_bytes,
+        render_options,
+        stereo: bool = true,
+        bitrate: &str = "auto",
+        *,
+        downmix: &str = "average",
+        tags: Option<&Bound<'py, PyDict>> = None,
+        report: bool = false,
+    ) -> Bound<'py, PyAny> {
+        let opus_bitrate = parse_opus_bitrate(bitrate)?;
+        let channels = output_channels(stereo, downmix)?;
+        let tags = extract_tags(tags)?;
+
+        let soundfont = SoundFontSource::extract(soundfont_bytes)?;
+        let midi_bytes = midi_bytes.to_vec();
+
+        let (opus_ogg_data, overload_report) = py
+            .allow_threads(|| {
+                render_midi_to_opus(
+                    &soundfont.load()?,
+                    &midi_bytes,
+                    channels,
+                    opus_bitrate,
+                    &tags,
+                    &render_options,
+                )
+            })
+            .map_err(to_py_error)?;
+        let opus_ogg_data = PyBytes::new_bound(py, &opus_ogg_data).into_any();
+        with_report(py, opus_ogg_data, overload_report, report)
+    }
+}
+
+render_function! {
+    fn render_vorbis_from<'py>(
+        py,
+        soundfont_bytes,
+        midi_bytes,
+        render_options,
+        *,
+        stereo: bool = true,
+        quality: f32 = DEFAULT_VORBIS_QUALITY,
+        downmix: &str = "average",
+        report: bool = false,
+    ) -> Bound<'py, PyAny> {
+        if !(-1.0..=10.0).contains(&quality) {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "quality must be between -1 and 10",
+            ));
+        }
+        let channels = output_channels(stereo, downmix)?;
+        let soundfont = SoundFontSource::extract(soundfont_bytes)?;
+        let midi_bytes = midi_bytes.to_vec();
+
+        let (vorbis_data, overload_report) = py
+            .allow_threads(|| {
+                render_midi_to_vorbis(
+                    &soundfont.load()?,
+                    &midi_bytes,
+                    channels,
+                    quality,
+                    &render_options,
+                )
+            })
+            .map_err(to_py_error)?;
+        let vorbis_data = PyBytes::new_bound(py, &vorbis_data).into_any();
+        with_report(py, vorbis_data, overload_report, report)
+    }
+}
+
+render_function! {
+    fn render_mp3_from<'py>(
+        py,
+        soundfont_bytes,
+        midi_bytes,
+        render_options,
+        *,
+        stereo: bool = true,
+        bitrate: &str = "192",
+        downmix: &str = "average",
+        tags: Option<&Bound<'py, PyDict>> = None,
+        report: bool = false,
+    ) -> Bound<'py, PyAny> {
+        let channels = output_channels(stereo, downmix)?;
+        let mp3_options = Mp3Options {
+            bitrate: parse_mp3_bitrate(bitrate)?,
+            tags: extract_tags(tags)?,
+        };
+        mp3_options.validate().map_err(to_py_error)?;
+        let soundfont = SoundFontSource::extract(soundfont_bytes)?;
+        let midi_bytes = midi_bytes.to_vec();
+
+        let (mp3_data, overload_report) = py
+            .allow_threads(|| {
+                render_midi_to_mp3(
+                    &soundfont.load()?,
+                    &midi_bytes,
+                    channels,
+                    &mp3_options,
+                    &render_options,
+                )
+            })
+            .map_err(to_py_error)?;
+        let mp3_data = PyBytes::new_bound(py, &mp3_data).into_any();
+        with_report(py, mp3_data, overload_report, report)
+    }
+}
+
+render_function! {
+    fn render_stems<'py>(
+        py,
+        soundfont_bytes,
+        midi_bytes,
+        render_options,
+        *,
+        by: &str = "channel",
+        format: &str = "wav",
+        stereo: bool = true,
+        bitrate: &str = "auto",
+        downmix: &str = "average",
+        sample_format: &str = "s16",
+    ) -> Bound<'py, PyDict> {
+        if render_options.loudness.is_some() {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "target_lufs is not supported by render_stems, whose stems keep their mix levels",
+            ));
+        }
+        if !matches!(render_options.overload, Overload::Clip) {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "overload is not supported by render_stems, whose stems keep their mix levels",
+            ));
+        }
+        if render_options.trim.is_some() || render_options.fades.is_some() {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "trim and fades are not supported by render_stems, whose stems share one timeline",
+            ));
+        }
+        let channels = output_channels(stereo, downmix)?;
+        let sample_format = parse_sample_format(sample_format)?;
+        let split = match by {
+            "channel" => StemSplit::Channel,
+            "track" => StemSplit::Track,
+            _ => {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    "Invalid stem split, expected 'channel' or 'track'",
+                ))
+            }
+        };
+        let opus_bitrate = match format {
+            "wav" => None,
+            "opus" => Some(parse_opus_bitrate(bitrate)?),
+            _ => {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    "Invalid stem format, expected 'wav' or 'opus'",
+                ))
+            }
+        };
+
+        let soundfont = SoundFontSource::extract(soundfont_bytes)?;
+        let midi_bytes = midi_bytes.to_vec();
+
+        let stems = py
+            .allow_threads(|| {
+                let stems = render_midi_stems(
+                    &soundfont.load()?,
+                    &midi_bytes,
+                    split,
+                    channels,
+                    sample_format,
+                    &render_options,
+                )?;
+                match opus_bitrate {
+                    None => Ok(stems),
+                    Some(opus_bitrate) => stems
+                        .into_iter()
+                        .map(|(key, wav_data)| {
+                            Ok((
+                                key,
+                                wav_to_opus_ogg(&wav_data, channels, opus_bitrate, &[])?,
+                            ))
+                        })
+                        .collect(),
+                }
+            })
+            .map_err(to_py_error)?;
+
+        let result = PyDict::new_bound(py);
+        for (key, data) in stems {
+            result.set_item(key, PyBytes::new_bound(py, &data))?;
+        }
+        Ok(result)
+    }
+}
+
+/// Iterates over the encoded chunks of a render as the synthesizer produces them.
+#[pyclass(name = "RenderIterator", module = "midirenderer")]
+struct PyRenderIterator {
+    stream: RenderStream,
+}
+
+#[pymethods]
+impl PyRenderIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__<'py>(&mut self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        let chunk = py
+            .allow_threads(|| self.stream.next_chunk())
+            .map_err(to_py_error)?;
+        Ok(chunk.map(|chunk| PyBytes::new_bound(py, &chunk)))
+    }
+}
+
+render_function! {
+    fn iter_render<'py>(
+        py,
+        soundfont_bytes,
+        midi_bytes,
+        render_options,
+        *,
+        format: &str = "pcm",
+        chunk_size: usize = DEFAULT_CHUNK_SIZE,
+        stereo: bool = true,
+        bitrate: &str = "auto",
+        downmix: &str = "average",
+        sample_format: &str = "s16",
+    ) -> PyRenderIterator {
+        if render_options.loudness.is_some() {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "target_lufs needs the whole render and is not supported by iter_render",
+            ));
+        }
+        if !matches!(render_options.overload, Overload::Clip) {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "overload needs the whole render and is not supported by iter_render",
+            ));
+        }
+        if render_options.trim.is_some() || render_options.fades.is_some() {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "trim and fades need the whole render and are not supported by iter_render",
+            ));
+        }
+        let channels = output_channels(stereo, downmix)?;
+        let sample_format = parse_sample_format(sample_format)?;
+        let format = match format {
+            "pcm" => StreamFormat::Pcm { sample_format },
+            "wav" => StreamFormat::Wav { sample_format },
+            "opus" => StreamFormat::Opus {
+                bitrate: parse_opus_bitrate(bitrate)?,
+            },
+            _ => {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    "Invalid stream format, expected 'pcm', 'wav' or 'opus'",
+                ))
+            }
+        };
+
+        let soundfont = SoundFontSource::extract(soundfont_bytes)?;
+        let midi_bytes = midi_bytes.to_vec();
+
+        let stream = py
+            .allow_threads(|| {
+                RenderStream::new(
+                    &soundfont.load()?,
+                    &midi_bytes,
+                    format,
+                    channels,
+                    chunk_size,
+                    &render_options,
+                )
+            })
+            .map_err(to_py_error)?;
+        Ok(PyRenderIterator { stream })
+    }
+}
+
+/// Raises invalid SoundFonts, MIDI files and options as `ValueError`, wherever they are
+/// found, and failures while rendering or encoding as `RuntimeError`.
+fn to_py_error(error: AudioError) -> PyErr {
+    if error.is_invalid_input() {
+        PyErr::new::<pyo3::exceptions::PyValueError, _>(error.to_string())
+    } else {
+        PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(error.to_string())
+    }
+}
+
+/// Combines the `stereo` flag with the downmix used when it is off.
+fn output_channels(stereo: bool, downmix: &str) -> PyResult<OutputChannels> {
+    let downmix = match downmix {
+        "average" => Downmix::Average,
+        "pan_law" => Downmix::PanLaw,
+        "left" => Downmix::Left,
+        "right" => Downmix::Right,
+        _ => {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "Invalid downmix, expected 'average', 'pan_law', 'left' or 'right'",
+            ))
+        }
+    };
+    Ok(if stereo {
+        OutputChannels::Stereo
+    } else {
+        OutputChannels::Mono(downmix)
+    })
+}
+
+/// Reads a dict of Vorbis comments, keeping its order.
+fn extract_tags(tags: Option<&Bound<'_, PyDict>>) -> PyResult<Vec<(String, String)>> {
+    let Some(tags) = tags else {
+        return Ok(Vec::new());
+    };
+    tags.iter()
+        .map(|(key, value)| {
+            let key: String = key.extract()?;
+            // Field names are printable ASCII other than '='.
+            if key.is_empty() || !key.bytes().all(|b| (0x20..=0x7D).contains(&b) && b != b'=') {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                    "Invalid tag name '{}'",
+                    key
+                )));
+            }
+            Ok((key, value.extract()?))
+        })
+        .collect()
+}
+
+fn parse_sample_format(sample_format: &str) -> PyResult<SampleFormat> {
+    match sample_format {
+        "s16" => Ok(SampleFormat::S16),
+        "s24" => Ok(SampleFormat::S24),
+        "s32" => Ok(SampleFormat::S32),
+        "f32" => Ok(SampleFormat::F32),
+        _ => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+            "Invalid sample format, expected 's16', 's24', 's32' or 'f32'",
+        )),
+    }
+}
+
+fn parse_opus_bitrate(bitrate: &str) -> PyResult<OpusBitrate> {
+    match bitrate {
+        "auto" => Ok(OpusBitrate::Auto),
+        "max" => Ok(OpusBitrate::Max),
+        _ => bitrate
+            .parse::<i32>()
+            .map(OpusBitrate::Bits)
+            .map_err(|_| PyErr::new::<pyo3::exceptions::PyValueError, _>("Invalid bitrate value")),
+    }
+}
+
+/// Accepts a constant bitrate in kbit/s, or "V0" to "V9" for LAME's variable bitrate levels.
+fn parse_mp3_bitrate(bitrate: &str) -> PyResult<Mp3Bitrate> {
+    let invalid = || PyErr::new::<pyo3::exceptions::PyValueError, _>("Invalid MP3 bitrate value");
+    if let Some(level) = bitrate.strip_prefix(['V', 'v']) {
+        return match level.parse::<u8>() {
+            Ok(level) if level <= 9 => Ok(Mp3Bitrate::Vbr(level)),
+            _ => Err(invalid()),
+        };
+    }
+    match bitrate.parse::<u16>() {
+        Ok(kbps) if MP3_BITRATES.contains(&kbps) => Ok(Mp3Bitrate::Cbr(kbps)),
+        _ => Err(invalid()),
+    }
 }
 
 #[pymodule]
 fn midirenderer(_py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PySoundFont>()?;
+    m.add_class::<PyPreset>()?;
+    m.add_class::<PySoundFontLayer>()?;
+    m.add_class::<PyRenderIterator>()?;
+    m.add_class::<PyOverloadReport>()?;
     m.add_function(wrap_pyfunction!(render_wave_from, m)?)?;
     m.add_function(wrap_pyfunction!(render_opus_from, m)?)?;
+    m.add_function(wrap_pyfunction!(render_pcm, m)?)?;
+    m.add_function(wrap_pyfunction!(render_flac_from, m)?)?;
+    m.add_function(wrap_pyfunction!(render_vorbis_from, m)?)?;
+    m.add_function(wrap_pyfunction!(render_mp3_from, m)?)?;
+    m.add_function(wrap_pyfunction!(render_stems, m)?)?;
+    m.add_function(wrap_pyfunction!(iter_render, m)?)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{sine, sound_font_file, vorbis_sample, MONO_SAMPLE};
+    use crate::sf3::{SAMPLE_PADDING, VORBIS_COMPRESSED};
+
+    #[test]
+    fn sound_font_describes_its_contents() {
+        let smpl = vorbis_sample(&sine(1000, 100.0));
+        let sf3 = sound_font_file(
+            3,
+            &smpl,
+            &[[0, smpl.len() as u32, 0, 1000]],
+            MONO_SAMPLE | VORBIS_COMPRESSED,
+        );
+        let sound_font = PySoundFont {
+            inner: load_soundfont(&sf3).unwrap(),
+        };
+
+        let presets: Vec<_> = sound_font
+            .presets()
+            .into_iter()
+            .map(|preset| (preset.name, preset.bank, preset.program))
+            .collect();
+        assert_eq!(
+            presets,
+            [("Sine".to_string(), 0, 0), ("Low".to_string(), 0, 1)]
+        );
+        assert_eq!(sound_font.instruments(), ["Sine"]);
+        assert_eq!(sound_font.sample_count(), 1);
+        // The decoded 16-bit samples, with the padding that follows each one.
+        assert_eq!(sound_font.sample_memory(), (1000 + SAMPLE_PADDING) * 2);
+
+        let info = info_fields(sound_font.inner.get_info());
+        let field = |key: &str| &info.iter().find(|(name, _)| *name == key).unwrap().1;
+        assert_eq!(field("version"), "3.01");
+        assert_eq!(field("bank_name"), "Test");
+        assert_eq!(field("copyright"), "Public domain");
+        assert_eq!(field("author"), "");
+        assert_eq!(
+            sound_font.__repr__(),
+            "SoundFont(bank_name=\"Test\", presets=2, samples=1)"
+        );
+    }
+}
@@ -0,0 +1,251 @@
+use std::f64::consts::PI;
+
+// Gating block length and step, in seconds (400ms blocks overlapping by 75%).
+const BLOCK_SECONDS: f64 = 0.4;
+const STEPS_PER_BLOCK: usize = 4;
+const ABSOLUTE_GATE: f64 = -70.0;
+// Relative gate, below the loudness of the blocks above the absolute gate.
+const RELATIVE_GATE: f64 = -10.0;
+// Rates from which sample peaks are close enough to true peaks without oversampling.
+const TRUE_PEAK_NATIVE_RATE: u32 = 96000;
+
+// Interpolation filter of ITU-R BS.1770-4 Annex 2: four phases of a 48-tap FIR,
+// oversampling by four. Coefficients are in units of 2^-13.
+const TRUE_PEAK_PHASES: [[i16; 12]; 4] = [
+    [
+        14, 90, -161, 272, -487, 1125, 7964, -838, 390, -218, 122, -68,
+    ],
+    [
+        -239, 240, -424, 730, -1364, 3810, 6388, -1641, 832, -477, 271, -155,
+    ],
+    [
+        -155, 271, -477, 832, -1641, 6388, 3810, -1364, 730, -424, 240, -239,
+    ],
+    [
+        -68, 122, -218, 390, -838, 7964, 1125, -487, 272, -161, 90, 14,
+    ],
+];
+const TRUE_PEAK_SCALE: f32 = 8192.0;
+
+/// Measures integrated loudness in LUFS following ITU-R BS.1770-4, with every channel
+/// weighted equally as for mono and stereo.
+///
+/// Returns `None` when the audio is shorter than one gating block or entirely below
+/// the absolute gate.
+pub fn integrated_loudness(channels: &[&[f32]], sample_rate: u32) -> Option<f64> {
+    let step = (sample_rate as f64 * BLOCK_SECONDS / STEPS_PER_BLOCK as f64).round() as usize;
+    let frames = channels.first().map_or(0, |channel| channel.len());
+    let steps = frames / step;
+    if steps < STEPS_PER_BLOCK {
+        return None;
+    }
+
+    // Sum of K-weighted squares over each step, across all channels.
+    let mut step_energy = vec![0.0; steps];
+    for channel in channels {
+        let mut filter = KWeighting::new(sample_rate);
+        for (energy, samples) in step_energy.iter_mut().zip(channel.chunks_exact(step)) {
+            *energy += samples
+                .iter()
+                .map(|&sample| filter.process(sample as f64).powi(2))
+                .sum::<f64>();
+        }
+    }
+
+    let block_length = (step * STEPS_PER_BLOCK) as f64;
+    let blocks: Vec<f64> = step_energy
+        .windows(STEPS_PER_BLOCK)
+        .map(|block| block.iter().sum::<f64>() / block_length)
+        .collect();
+
+    let above_absolute: Vec<f64> = blocks
+        .into_iter()
+        .filter(|&power| to_lufs(power) > ABSOLUTE_GATE)
+        .collect();
+    if above_absolute.is_empty() {
+        return None;
+    }
+    let relative_gate = to_lufs(mean(&above_absolute)) + RELATIVE_GATE;
+    let gated: Vec<f64> = above_absolute
+        .into_iter()
+        .filter(|&power| to_lufs(power) > relative_gate)
+        .collect();
+    Some(to_lufs(mean(&gated)))
+}
+
+/// Measures the true peak, linear, by oversampling the audio fourfold below 96kHz.
+pub fn true_peak(channels: &[&[f32]], sample_rate: u32) -> f32 {
+    let sample_peak = channels
+        .iter()
+        .flat_map(|channel| channel.iter())
+        .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+    if sample_rate >= TRUE_PEAK_NATIVE_RATE {
+        return sample_peak;
+    }
+
+    let taps = TRUE_PEAK_PHASES[0].len();
+    let mut peak = sample_peak;
+    for channel in channels {
+        // Pad with silence so the filter also rings out past both ends.
+        let mut padded = vec![0.0; taps - 1];
+        padded.extend_from_slice(channel);
+        padded.resize(padded.len() + taps - 1, 0.0);
+        for window in padded.windows(taps) {
+            for phase in &TRUE_PEAK_PHASES {
+                let value: f32 = window
+                    .iter()
+                    .rev()
+                    .zip(phase)
+                    .map(|(sample, &coefficient)| sample * coefficient as f32)
+                    .sum::<f32>()
+                    / TRUE_PEAK_SCALE;
+                peak = peak.max(value.abs());
+            }
+        }
+    }
+    peak
+}
+
+fn to_lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// The K-weighting of BS.1770: a high shelf modelling the head, then a high-pass filter.
+///
+/// Both stages are derived from their analog prototypes, so any sample rate works.
+struct KWeighting {
+    stages: [Biquad; 2],
+}
+
+impl KWeighting {
+    fn new(sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+
+        let k = (PI * 1681.974450955533 / rate).tan();
+        let q = 0.7071752369554196;
+        let vh = 10f64.powf(3.999843853973347 / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let k = (PI * 38.13547087602444 / rate).tan();
+        let q = 0.5003270373238773;
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        Self {
+            stages: [shelf, high_pass],
+        }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.stages
+            .iter_mut()
+            .fold(sample, |sample, stage| stage.process(sample))
+    }
+}
+
+/// A second-order IIR filter in transposed direct form II.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            state: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.state[0];
+        self.state[0] = self.b[1] * input - self.a[0] * output + self.state[1];
+        self.state[1] = self.b[2] * input - self.a[1] * output;
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, amplitude: f64, phase: f64, frames: usize, rate: u32) -> Vec<f32> {
+        (0..frames)
+            .map(|n| {
+                let t = n as f64 / rate as f64;
+                (amplitude * (2.0 * PI * frequency * t + phase).sin()) as f32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sine_reads_at_its_calibrated_loudness() {
+        // BS.1770 calibrates a full-scale 997 Hz sine in one channel to -3.01 LUFS.
+        for rate in [44100, 48000, 96000] {
+            let tone = sine(997.0, 0.1, 0.0, rate as usize * 5, rate);
+            let mono = integrated_loudness(&[&tone], rate).unwrap();
+            assert!((mono + 23.01).abs() < 0.05, "{rate} Hz mono: {mono}");
+            let stereo = integrated_loudness(&[&tone, &tone], rate).unwrap();
+            assert!((stereo + 20.0).abs() < 0.05, "{rate} Hz stereo: {stereo}");
+        }
+    }
+
+    #[test]
+    fn gates_drop_silence_and_quiet_passages() {
+        let rate = 48000;
+        let tone = sine(997.0, 0.1, 0.0, rate as usize * 5, rate);
+        let mut with_silence = tone.clone();
+        with_silence.extend(vec![0.0; rate as usize * 5]);
+        let mut with_quiet = with_silence.clone();
+        // 40 dB down, so below the relative gate.
+        with_quiet.extend(sine(997.0, 0.001, 0.0, rate as usize * 5, rate));
+
+        let tone_only = integrated_loudness(&[&tone], rate).unwrap();
+        let silence = integrated_loudness(&[&with_silence], rate).unwrap();
+        let quiet = integrated_loudness(&[&with_quiet], rate).unwrap();
+        assert!((quiet - silence).abs() < 1e-9, "{quiet} vs {silence}");
+        // Only the blocks overlapping the end of the tone count towards the difference.
+        assert!(
+            (silence - tone_only).abs() < 0.2,
+            "{silence} vs {tone_only}"
+        );
+
+        assert_eq!(
+            integrated_loudness(&[&vec![0.0; rate as usize]], rate),
+            None
+        );
+        assert_eq!(
+            integrated_loudness(&[&tone[..rate as usize / 4]], rate),
+            None
+        );
+    }
+
+    #[test]
+    fn true_peak_finds_peaks_between_samples() {
+        // A quarter of the sample rate at 45 degrees never lands a sample on its crest.
+        let rate = 48000;
+        let tone = sine(12000.0, 1.0, PI / 4.0, 4800, rate);
+        let sample_peak = tone.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!((sample_peak - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.001);
+        let peak = true_peak(&[&tone], rate);
+        assert!((peak - 1.0).abs() < 0.05, "{peak}");
+    }
+}
@@ -0,0 +1,743 @@
+use std::sync::Arc;
+
+use crate::audio_utils::AudioError;
+use crate::layers::LayeredSynthesizer;
+
+const DEFAULT_TEMPO: u32 = 500_000; // Microseconds per quarter note (120 BPM)
+
+// Controller that RPG Maker and other game engines use to mark the loop start.
+const LOOP_START_CONTROLLER: u8 = 111;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelMessage {
+    pub channel: u8,
+    pub command: u8,
+    pub data1: u8,
+    pub data2: u8,
+}
+
+impl ChannelMessage {
+    pub fn is_note_on(&self) -> bool {
+        self.command == 0x90 && self.data2 > 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimedMessage {
+    pub time: f64,
+    pub track: usize,
+    pub message: ChannelMessage,
+}
+
+enum TrackEvent {
+    Channel(ChannelMessage),
+    Tempo(u32),
+    Copyright(String),
+    SequenceName(String),
+    Marker(String),
+    EndOfTrack,
+}
+
+/// A standard MIDI file flattened into a single time-ordered list of channel messages.
+///
+/// Unlike `rustysynth::MidiFile`, the messages remember which track they came from,
+/// so they can be filtered before they reach the synthesizer.
+#[derive(Debug)]
+pub struct MidiSong {
+    messages: Vec<TimedMessage>,
+    length: f64,
+    title: Option<String>,
+    copyright: Option<String>,
+    loop_start: Option<f64>,
+    loop_end: Option<f64>,
+}
+
+impl MidiSong {
+    pub fn parse(midi_bytes: &[u8]) -> Result<Self, AudioError> {
+        let mut reader = ByteReader::new(midi_bytes);
+
+        if reader.read_bytes(4)? != b"MThd" {
+            return Err(AudioError::Midi("Missing MThd chunk".to_string()));
+        }
+        let header_length = reader.read_u32()? as usize;
+        if header_length < 6 {
+            return Err(AudioError::Midi("Invalid MThd chunk".to_string()));
+        }
+        let format = reader.read_u16()?;
+        let track_count = reader.read_u16()? as usize;
+        let division = reader.read_u16()?;
+        reader.skip(header_length - 6)?;
+
+        if format > 1 {
+            return Err(AudioError::Midi(format!("Unsupported format: {}", format)));
+        }
+        if division & 0x8000 != 0 {
+            // SMPTE timing: the negated frame rate in the high byte, then ticks per frame.
+            let frames_per_second = -((division >> 8) as i8 as i16);
+            if ![24, 25, 29, 30].contains(&frames_per_second) || division & 0xFF == 0 {
+                return Err(AudioError::Midi(format!(
+                    "Invalid SMPTE division: {:#06X}",
+                    division
+                )));
+            }
+        } else if division == 0 {
+            return Err(AudioError::Midi(
+                "Invalid division: 0 ticks per quarter note".to_string(),
+            ));
+        }
+
+        let mut tracks = Vec::with_capacity(track_count);
+        while tracks.len() < track_count {
+            let chunk_type = reader.read_bytes(4)?;
+            let chunk_length = reader.read_u32()? as usize;
+            let chunk_data = reader.read_bytes(chunk_length)?;
+            // Unknown chunks are allowed by the spec and must be skipped.
+            if chunk_type == b"MTrk" {
+                tracks.push(read_track(chunk_data)?);
+            }
+        }
+
+        Ok(merge_tracks(&tracks, division))
+    }
+
+    pub fn messages(&self) -> &[TimedMessage] {
+        &self.messages
+    }
+
+    /// The time of the last event in seconds, including end-of-track markers.
+    pub fn length(&self) -> f64 {
+        self.length
+    }
+
+    /// The sequence name, taken from the first track.
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// The copyright notice, taken from the first track.
+    pub fn copyright(&self) -> Option<&str> {
+        self.copyright.as_deref()
+    }
+
+    /// Where the song loops back to, in seconds: a "loopStart" marker, or failing that the
+    /// first CC111.
+    pub fn loop_start(&self) -> Option<f64> {
+        self.loop_start
+    }
+
+    /// Where the song's loop ends, in seconds, from a "loopEnd" marker.
+    pub fn loop_end(&self) -> Option<f64> {
+        self.loop_end
+    }
+
+    /// The channels that play at least one note, in ascending order.
+    pub fn channels_with_notes(&self) -> Vec<u8> {
+        let mut channels: Vec<u8> = self.note_ons().map(|m| m.message.channel).collect();
+        channels.sort_unstable();
+        channels.dedup();
+        channels
+    }
+
+    /// The tracks that play at least one note, in ascending order.
+    pub fn tracks_with_notes(&self) -> Vec<usize> {
+        let mut tracks: Vec<usize> = self.note_ons().map(|m| m.track).collect();
+        tracks.sort_unstable();
+        tracks.dedup();
+        tracks
+    }
+
+    fn note_ons(&self) -> impl Iterator<Item = &TimedMessage> {
+        self.messages.iter().filter(|m| m.message.is_note_on())
+    }
+}
+
+fn read_track(data: &[u8]) -> Result<Vec<(u64, TrackEvent)>, AudioError> {
+    let mut reader = ByteReader::new(data);
+    let mut events = Vec::new();
+    let mut tick = 0u64;
+    let mut running_status = 0u8;
+
+    while !reader.is_empty() {
+        tick += reader.read_variable_length()? as u64;
+        let mut status = reader.read_u8()?;
+
+        match status {
+            0xF0 | 0xF7 => {
+                let length = reader.read_variable_length()? as usize;
+                reader.skip(length)?;
+            }
+            // System common and real-time messages do not belong in a file, but some
+            // recorded files keep them. They carry no timing, so skip them with their data.
+            0xF1 | 0xF3 => reader.skip(1)?,
+            0xF2 => reader.skip(2)?,
+            0xF4..=0xF6 | 0xF8..=0xFE => {}
+            0xFF => {
+                let meta_type = reader.read_u8()?;
+                let length = reader.read_variable_length()? as usize;
+                let data = reader.read_bytes(length)?;
+                match meta_type {
+                    0x2F => {
+                        events.push((tick, TrackEvent::EndOfTrack));
+                        // Anything after the end-of-track marker is ignored.
+                        return Ok(events);
+                    }
+                    0x02 => events.push((tick, TrackEvent::Copyright(decode_text(data)))),
+                    0x03 => events.push((tick, TrackEvent::SequenceName(decode_text(data)))),
+                    0x06 => events.push((tick, TrackEvent::Marker(decode_text(data)))),
+                    0x51 if length == 3 => {
+                        let tempo = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+                        events.push((tick, TrackEvent::Tempo(tempo)));
+                    }
+                    _ => {}
+                }
+            }
+            _ => {
+                let data1 = if status & 0x80 == 0 {
+                    // Running status: the byte we just read is the first data byte.
+                    if running_status == 0 {
+                        return Err(AudioError::Midi("Data byte without status".to_string()));
+                    }
+                    let data1 = status;
+                    status = running_status;
+                    data1
+                } else {
+                    running_status = status;
+                    reader.read_u8()?
+                };
+
+                let command = status & 0xF0;
+                let data2 = if command == 0xC0 || command == 0xD0 {
+                    0
+                } else {
+                    reader.read_u8()?
+                };
+
+                events.push((
+                    tick,
+                    TrackEvent::Channel(ChannelMessage {
+                        channel: status & 0x0F,
+                        command,
+                        data1,
+                        data2,
+                    }),
+                ));
+            }
+        }
+    }
+
+    events.push((tick, TrackEvent::EndOfTrack));
+    Ok(events)
+}
+
+fn merge_tracks(tracks: &[Vec<(u64, TrackEvent)>], division: u16) -> MidiSong {
+    // Ties go to the earlier track, then to the earlier event within a track.
+    let mut order: Vec<(u64, usize, usize)> = tracks
+        .iter()
+        .enumerate()
+        .flat_map(|(track, events)| {
+            events
+                .iter()
+                .enumerate()
+                .map(move |(index, (tick, _))| (*tick, track, index))
+        })
+        .collect();
+    order.sort_unstable();
+
+    let mut messages = Vec::new();
+    let mut current_tick = 0u64;
+    let mut current_time = 0.0;
+    let mut tempo = DEFAULT_TEMPO;
+    let mut title = None;
+    let mut copyright = None;
+    let mut loop_marker = None;
+    let mut loop_controller = None;
+    let mut loop_end = None;
+
+    for (tick, track, index) in order {
+        current_time += ticks_to_seconds(tick - current_tick, tempo, division);
+        current_tick = tick;
+
+        match &tracks[track][index].1 {
+            TrackEvent::Channel(message) => {
+                if message.command == 0xB0 && message.data1 == LOOP_START_CONTROLLER {
+                    loop_controller.get_or_insert(current_time);
+                }
+                messages.push(TimedMessage {
+                    time: current_time,
+                    track,
+                    message: *message,
+                })
+            }
+            TrackEvent::Tempo(value) => tempo = *value,
+            // In later tracks a sequence name is the name of that track, not of the song.
+            TrackEvent::SequenceName(text) if track == 0 && !text.is_empty() => {
+                title.get_or_insert_with(|| text.clone());
+            }
+            TrackEvent::Copyright(text) if track == 0 && !text.is_empty() => {
+                copyright.get_or_insert_with(|| text.clone());
+            }
+            TrackEvent::Marker(text) if text.eq_ignore_ascii_case("loopStart") => {
+                loop_marker.get_or_insert(current_time);
+            }
+            TrackEvent::Marker(text) if text.eq_ignore_ascii_case("loopEnd") => {
+                loop_end.get_or_insert(current_time);
+            }
+            TrackEvent::SequenceName(_)
+            | TrackEvent::Copyright(_)
+            | TrackEvent::Marker(_)
+            | TrackEvent::EndOfTrack => {}
+        }
+    }
+
+    MidiSong {
+        messages,
+        length: current_time,
+        title,
+        copyright,
+        loop_start: loop_marker.or(loop_controller),
+        loop_end,
+    }
+}
+
+/// Decodes a text meta event, which is UTF-8 in newer files and usually Latin-1 in older ones.
+fn decode_text(data: &[u8]) -> String {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text.to_string(),
+        Err(_) => data.iter().map(|&byte| byte as char).collect(),
+    };
+    text.trim_matches(|c: char| c == '\0' || c.is_whitespace())
+        .to_string()
+}
+
+fn ticks_to_seconds(ticks: u64, tempo: u32, division: u16) -> f64 {
+    if division & 0x8000 != 0 {
+        // SMPTE timing: frames per second times ticks per frame, independent of tempo.
+        let frames_per_second = -((division >> 8) as i8 as f64);
+        let ticks_per_frame = (division & 0xFF) as f64;
+        ticks as f64 / (frames_per_second * ticks_per_frame)
+    } else {
+        ticks as f64 * tempo as f64 / (1_000_000.0 * division as f64)
+    }
+}
+
+/// Selects which notes reach the synthesizer.
+///
+/// Only note-ons are filtered, so controllers and program changes sent from other
+/// tracks (a conductor track, for example) still set up the channels correctly.
+#[derive(Debug, Clone, Copy)]
+pub enum NoteFilter {
+    All,
+    Channel(u8),
+    Track(usize),
+}
+
+impl NoteFilter {
+    fn accepts(&self, message: &TimedMessage) -> bool {
+        if !message.message.is_note_on() {
+            return true;
+        }
+        match *self {
+            NoteFilter::All => true,
+            NoteFilter::Channel(channel) => message.message.channel == channel,
+            NoteFilter::Track(track) => message.track == track,
+        }
+    }
+}
+
+/// Feeds a `MidiSong` to a synthesizer, mirroring `rustysynth::MidiFileSequencer`.
+pub struct Sequencer {
+    synthesizer: LayeredSynthesizer,
+    song: Arc<MidiSong>,
+    filter: NoteFilter,
+    block_wrote: usize,
+    current_time: f64,
+    message_index: usize,
+    looping: Option<SequencerLoop>,
+}
+
+/// A section the sequencer jumps back over, a set number of times.
+struct SequencerLoop {
+    start: f64,
+    end: f64,
+    jumps: usize,
+    // The first message at or after `start`.
+    start_index: usize,
+    // Notes struck before `start` and still held there, as (channel, key, velocity).
+    held: Vec<(u8, u8, u8)>,
+}
+
+impl Sequencer {
+    pub fn new(synthesizer: LayeredSynthesizer, song: Arc<MidiSong>, filter: NoteFilter) -> Self {
+        Self {
+            block_wrote: synthesizer.get_block_size(),
+            synthesizer,
+            song,
+            filter,
+            current_time: 0.0,
+            message_index: 0,
+            looping: None,
+        }
+    }
+
+    pub fn render(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let block_size = self.synthesizer.get_block_size();
+        let sample_rate = self.synthesizer.get_sample_rate() as f64;
+
+        let mut wrote = 0;
+        while wrote < left.len() {
+            if self.block_wrote == block_size {
+                self.process_events();
+                self.block_wrote = 0;
+                self.current_time += block_size as f64 / sample_rate;
+            }
+
+            let rem = std::cmp::min(block_size - self.block_wrote, left.len() - wrote);
+            self.synthesizer.render(
+                &mut left[wrote..wrote + rem],
+                &mut right[wrote..wrote + rem],
+            );
+
+            self.block_wrote += rem;
+            wrote += rem;
+        }
+    }
+
+    /// Jumps to `time` without rendering the audio before it.
+    ///
+    /// Everything except note-ons is applied on the way, so programs, controllers and
+    /// pitch bend are as they would be after playing up to `time`. Notes still held at
+    /// `time` are struck again there.
+    pub fn seek(&mut self, time: f64) {
+        let messages = self.song.messages();
+        while let Some(timed) = messages.get(self.message_index) {
+            if timed.time >= time {
+                break;
+            }
+            let message = timed.message;
+            if !matches!(message.command, 0x80 | 0x90) {
+                self.synthesizer.process_midi_message(
+                    message.channel as i32,
+                    message.command as i32,
+                    message.data1 as i32,
+                    message.data2 as i32,
+                );
+            }
+            self.message_index += 1;
+        }
+
+        for (channel, key, velocity) in self.held_notes(time) {
+            self.synthesizer
+                .note_on(channel as i32, key as i32, velocity as i32);
+        }
+
+        self.current_time = time;
+        self.block_wrote = self.synthesizer.get_block_size();
+    }
+
+    /// Plays the section from `start` to `end` seconds `repetitions` times in all, jumping
+    /// back each time `end` is reached.
+    ///
+    /// Controllers carry over each jump, as in a looping game engine. Notes still sounding
+    /// at `end` are released, and notes held at `start` are struck again.
+    pub fn set_loop(&mut self, start: f64, end: f64, repetitions: usize) {
+        let messages = self.song.messages();
+        self.looping = Some(SequencerLoop {
+            start,
+            end,
+            jumps: repetitions.saturating_sub(1),
+            start_index: messages.partition_point(|timed| timed.time < start),
+            held: self.held_notes(start),
+        });
+    }
+
+    /// The notes struck before `time` and not yet released there.
+    fn held_notes(&self, time: f64) -> Vec<(u8, u8, u8)> {
+        let mut held_velocities = [[0u8; 128]; 16];
+        for timed in self
+            .song
+            .messages()
+            .iter()
+            .take_while(|timed| timed.time < time)
+        {
+            let message = timed.message;
+            let channel = message.channel as usize;
+            match message.command {
+                0x80 | 0x90 => {
+                    let held = &mut held_velocities[channel][message.data1 as usize & 0x7F];
+                    if message.command == 0x80 || message.data2 == 0 {
+                        *held = 0;
+                    } else if self.filter.accepts(timed) {
+                        *held = message.data2;
+                    }
+                }
+                // All Sound Off and All Notes Off silence the held notes too.
+                0xB0 if message.data1 == 120 || message.data1 == 123 => {
+                    held_velocities[channel] = [0; 128];
+                }
+                _ => {}
+            }
+        }
+
+        let mut held = Vec::new();
+        for (channel, velocities) in held_velocities.iter().enumerate() {
+            for (key, &velocity) in velocities.iter().enumerate() {
+                if velocity > 0 {
+                    held.push((channel as u8, key as u8, velocity));
+                }
+            }
+        }
+        held
+    }
+
+    /// Releases every sounding note and ignores the rest of the song.
+    pub fn stop(&mut self) {
+        self.synthesizer.note_off_all(false);
+        self.message_index = self.song.messages().len();
+    }
+
+    fn process_events(&mut self) {
+        let messages = self.song.messages();
+        if let Some(looping) = self.looping.as_mut() {
+            if looping.jumps > 0 && self.current_time >= looping.end {
+                looping.jumps -= 1;
+                // Catch up on the messages before `end`, leaving out notes that would be
+                // released straight away.
+                while let Some(timed) = messages.get(self.message_index) {
+                    if timed.time >= looping.end {
+                        break;
+                    }
+                    let message = timed.message;
+                    let note_on = message.command == 0x90 && message.data2 > 0;
+                    if !note_on && self.filter.accepts(timed) {
+                        self.synthesizer.process_midi_message(
+                            message.channel as i32,
+                            message.command as i32,
+                            message.data1 as i32,
+                            message.data2 as i32,
+                        );
+                    }
+                    self.message_index += 1;
+                }
+
+                // Keep the overshoot past `end`, so repetitions do not drift.
+                self.current_time -= looping.end - looping.start;
+                self.message_index = looping.start_index;
+                self.synthesizer.note_off_all(false);
+                for &(channel, key, velocity) in &looping.held {
+                    self.synthesizer
+                        .note_on(channel as i32, key as i32, velocity as i32);
+                }
+            }
+        }
+
+        while let Some(timed) = messages.get(self.message_index) {
+            if timed.time > self.current_time {
+                break;
+            }
+            if self.filter.accepts(timed) {
+                let message = timed.message;
+                self.synthesizer.process_midi_message(
+                    message.channel as i32,
+                    message.command as i32,
+                    message.data1 as i32,
+                    message.data2 as i32,
+                );
+            }
+            self.message_index += 1;
+        }
+    }
+}
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn read_bytes(&mut self, count: usize) -> Result<&'a [u8], AudioError> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| AudioError::Midi("Unexpected end of data".to_string()))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, count: usize) -> Result<(), AudioError> {
+        self.read_bytes(count).map(|_| ())
+    }
+
+    fn read_u8(&mut self) -> Result<u8, AudioError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, AudioError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, AudioError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_variable_length(&mut self) -> Result<u32, AudioError> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.read_u8()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(AudioError::Midi(
+            "Variable-length value too long".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::midi_file;
+
+    /// Each message as (time, track, command, channel, data1, data2).
+    fn messages(song: &MidiSong) -> Vec<(f64, usize, u8, u8, u8, u8)> {
+        song.messages()
+            .iter()
+            .map(|timed| {
+                let message = timed.message;
+                (
+                    timed.time,
+                    timed.track,
+                    message.command,
+                    message.channel,
+                    message.data1,
+                    message.data2,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn running_status_repeats_the_last_channel_status() {
+        // Two note-ons, a note-on of velocity 0 half a second later, then two program
+        // changes on channel 1, which take a single data byte.
+        let midi =
+            midi_file(&[b"\x00\x90\x3C\x40\x00\x3E\x40\x83\x60\x3C\x00\x00\xC1\x05\x00\x06"]);
+        let song = MidiSong::parse(&midi).unwrap();
+        assert_eq!(
+            messages(&song),
+            [
+                (0.0, 0, 0x90, 0, 0x3C, 0x40),
+                (0.0, 0, 0x90, 0, 0x3E, 0x40),
+                (0.5, 0, 0x90, 0, 0x3C, 0),
+                (0.5, 0, 0xC0, 1, 5, 0),
+                (0.5, 0, 0xC0, 1, 6, 0),
+            ]
+        );
+        assert!(MidiSong::parse(&midi_file(&[b"\x00\x3C\x40"])).is_err());
+    }
+
+    #[test]
+    fn system_messages_are_skipped_with_their_data() {
+        let midi = midi_file(&[
+            b"\x00\xF8\x00\xF2\x01\x02\x00\x90\x3C\x40\x00\xF1\x05\x00\xFE\x83\x60\x80\x3C\x00",
+        ]);
+        let song = MidiSong::parse(&midi).unwrap();
+        assert_eq!(
+            messages(&song),
+            [(0.0, 0, 0x90, 0, 0x3C, 0x40), (0.5, 0, 0x80, 0, 0x3C, 0)]
+        );
+    }
+
+    #[test]
+    fn tempo_changes_apply_from_their_tick() {
+        // 120 BPM until the conductor track slows to 60 BPM on the second beat.
+        let midi = midi_file(&[
+            b"\x83\x60\xFF\x51\x03\x0F\x42\x40",
+            b"\x00\x90\x3C\x40\x83\x60\x90\x3E\x40\x83\x60\x90\x40\x40",
+        ]);
+        let song = MidiSong::parse(&midi).unwrap();
+        let times: Vec<f64> = song.messages().iter().map(|timed| timed.time).collect();
+        assert_eq!(times, [0.0, 0.5, 1.5]);
+        assert_eq!(song.length(), 1.5);
+    }
+
+    #[test]
+    fn smpte_divisions_count_ticks_per_second() {
+        let with_division = |division: u16| {
+            // A note half a second in, at 25 frames of 40 ticks per second.
+            let mut midi = midi_file(&[b"\x83\x74\x90\x3C\x40"]);
+            midi[12..14].copy_from_slice(&division.to_be_bytes());
+            MidiSong::parse(&midi)
+        };
+        let song = with_division(0xE728).unwrap();
+        assert_eq!(song.messages()[0].time, 0.5);
+
+        // -128 frames per second, -27 frames per second, no ticks per frame and no
+        // ticks per quarter note.
+        for division in [0x8028, 0xE528, 0xE700, 0x0000] {
+            assert!(
+                matches!(with_division(division), Err(AudioError::Midi(_))),
+                "{division:#06X}"
+            );
+        }
+    }
+
+    #[test]
+    fn tracks_merge_in_time_order_with_ties_to_the_earlier_track() {
+        let midi = midi_file(&[
+            b"\x83\x60\x90\x30\x40",
+            b"\x00\xB0\x07\x64\x00\x91\x31\x40\x83\x60\x91\x32\x40",
+            b"\x81\x70\x92\x33\x40",
+        ]);
+        let song = MidiSong::parse(&midi).unwrap();
+        assert_eq!(
+            messages(&song),
+            [
+                (0.0, 1, 0xB0, 0, 0x07, 0x64),
+                (0.0, 1, 0x90, 1, 0x31, 0x40),
+                (0.25, 2, 0x90, 2, 0x33, 0x40),
+                (0.5, 0, 0x90, 0, 0x30, 0x40),
+                (0.5, 1, 0x90, 1, 0x32, 0x40),
+            ]
+        );
+    }
+
+    #[test]
+    fn note_filters_pass_everything_but_other_stems_notes() {
+        // Track 1 sets the volume of channel 0 and plays on it; track 2 plays on channel 2.
+        let midi = midi_file(&[
+            b"",
+            b"\x00\xB0\x07\x64\x00\x90\x3C\x40\x83\x60\x90\x3C\x00",
+            b"\x00\x92\x3E\x40",
+        ]);
+        let song = MidiSong::parse(&midi).unwrap();
+        assert_eq!(song.channels_with_notes(), [0, 2]);
+        assert_eq!(song.tracks_with_notes(), [1, 2]);
+
+        let accepted = |filter: NoteFilter| -> Vec<usize> {
+            (0..song.messages().len())
+                .filter(|&index| filter.accepts(&song.messages()[index]))
+                .collect()
+        };
+        // The controller, the note-on of channel 0, the note-on of channel 2, then the
+        // note-off sent as a note-on of velocity 0.
+        assert_eq!(accepted(NoteFilter::All), [0, 1, 2, 3]);
+        assert_eq!(accepted(NoteFilter::Channel(0)), [0, 1, 3]);
+        assert_eq!(accepted(NoteFilter::Channel(2)), [0, 2, 3]);
+        assert_eq!(accepted(NoteFilter::Track(1)), [0, 1, 3]);
+        assert_eq!(accepted(NoteFilter::Track(2)), [0, 2, 3]);
+    }
+}
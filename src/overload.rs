@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+
+// Samples up to this level pass through soft clipping unchanged.
+const SOFT_CLIP_KNEE: f32 = 0.5;
+
+/// Saturates a sample above SOFT_CLIP_KNEE along a tanh curve that levels off at full scale.
+///
+/// The curve leaves the knee with the slope of the linear part, so there is no kink.
+pub fn soft_clip(sample: f32) -> f32 {
+    let level = sample.abs();
+    if level <= SOFT_CLIP_KNEE {
+        return sample;
+    }
+    let headroom = 1.0 - SOFT_CLIP_KNEE;
+    let shaped = SOFT_CLIP_KNEE + headroom * ((level - SOFT_CLIP_KNEE) / headroom).tanh();
+    shaped.copysign(sample)
+}
+
+/// Computes the gain of a look-ahead brickwall limiter for each frame, from `levels`,
+/// the highest absolute sample of every frame.
+///
+/// The gain ramps down over `lookahead` frames before a peak, so no frame ends up above
+/// `ceiling`, and recovers towards unity with a time constant of `release` frames.
+pub fn limiter_gains(levels: &[f32], ceiling: f32, lookahead: usize, release: f64) -> Vec<f32> {
+    let required: Vec<f32> = levels
+        .iter()
+        .map(|&level| {
+            if level > ceiling {
+                ceiling / level
+            } else {
+                1.0
+            }
+        })
+        .collect();
+
+    // The lowest gain any of the next `lookahead` frames needs, from a queue of candidates
+    // kept in increasing order.
+    let mut held = Vec::with_capacity(required.len());
+    let mut window: VecDeque<usize> = VecDeque::new();
+    for index in 0..required.len() + lookahead - 1 {
+        if let Some(&gain) = required.get(index) {
+            while window.back().is_some_and(|&last| required[last] >= gain) {
+                window.pop_back();
+            }
+            window.push_back(index);
+        }
+        if index + 1 >= lookahead {
+            let start = index + 1 - lookahead;
+            while window.front().is_some_and(|&first| first < start) {
+                window.pop_front();
+            }
+            held.push(required[window[0]]);
+        }
+    }
+
+    // Release: the gain falls at once and rises slowly, so it stays below the held gain.
+    let coefficient = 1.0 - (-1.0 / release.max(1.0)).exp() as f32;
+    let mut gain = held.first().copied().unwrap_or(1.0);
+    for value in held.iter_mut() {
+        if *value < gain {
+            gain = *value;
+        } else {
+            gain += (*value - gain) * coefficient;
+        }
+        *value = gain;
+    }
+
+    // Attack: averaging the last `lookahead` gains turns each drop into a ramp that ends
+    // at the peak. Every gain averaged for a peak's frame was held for that peak.
+    let first = held.first().copied().unwrap_or(1.0) as f64;
+    let mut sum = first * lookahead as f64;
+    (0..held.len())
+        .map(|index| {
+            let leaving = index
+                .checked_sub(lookahead)
+                .map_or(first, |leaving| held[leaving] as f64);
+            sum += held[index] as f64 - leaving;
+            (sum / lookahead as f64) as f32
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn soft_clip_is_continuous_and_stays_below_full_scale() {
+        assert_eq!(soft_clip(0.25), 0.25);
+        assert_eq!(soft_clip(-SOFT_CLIP_KNEE), -SOFT_CLIP_KNEE);
+        let above = soft_clip(SOFT_CLIP_KNEE + 0.001);
+        assert!((above - (SOFT_CLIP_KNEE + 0.001)).abs() < 1e-5, "{above}");
+        for level in [0.75f32, 1.0, 2.0, 10.0] {
+            let shaped = soft_clip(level);
+            assert!(shaped < level && shaped <= 1.0, "{level} -> {shaped}");
+            assert_eq!(soft_clip(-level), -shaped);
+        }
+        assert!(soft_clip(1.0) < 0.9 && soft_clip(1.0) < soft_clip(2.0));
+    }
+
+    #[test]
+    fn limiter_holds_the_ceiling_and_recovers() {
+        let mut levels = vec![0.25f32; 2000];
+        levels[500] = 2.0;
+        levels[501] = 1.0;
+        let ceiling = 0.5;
+        let lookahead = 64;
+        let gains = limiter_gains(&levels, ceiling, lookahead, 100.0);
+        assert_eq!(gains.len(), levels.len());
+
+        for (level, gain) in levels.iter().zip(&gains) {
+            assert!(level * gain <= ceiling * 1.00001, "{level} * {gain}");
+            assert!(*gain <= 1.0);
+        }
+        // Untouched well before the look-ahead, ramping down within it.
+        assert_eq!(gains[500 - lookahead - 1], 1.0);
+        assert!(gains[500 - lookahead / 2] < 1.0 && gains[500 - lookahead / 2] > 0.25);
+        assert!((gains[500] - 0.25).abs() < 1e-6);
+        // Recovering over the release, monotonically.
+        assert!(gains[502..].windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(gains[1999] > 0.999);
+    }
+}
@@ -0,0 +1,207 @@
+use std::f64::consts::PI;
+
+// Zero crossings of the sinc kernel on each side of the output sample.
+const ZERO_CROSSINGS: usize = 16;
+// Kernel table resolution, in entries per input sample.
+const TABLE_RESOLUTION: usize = 256;
+// Keep the passband slightly below Nyquist so the window has room to roll off.
+const PASSBAND: f64 = 0.95;
+
+/// Converts interleaved samples between sample rates with a windowed-sinc filter.
+///
+/// Input can arrive in chunks of any size; the output is the same as resampling
+/// the whole signal at once.
+pub struct Resampler {
+    channels: usize,
+    from_rate: u32,
+    to_rate: u32,
+    ratio: f64,
+    half_width: usize,
+    kernel: Vec<f32>,
+    // Input frames still needed by upcoming output frames.
+    history: Vec<f32>,
+    // Absolute index of the first frame in `history`.
+    history_start: u64,
+    input_frames: u64,
+    output_frames: u64,
+}
+
+impl Resampler {
+    pub fn new(channels: usize, from_rate: u32, to_rate: u32) -> Self {
+        let ratio = from_rate as f64 / to_rate as f64;
+        // When downsampling the kernel is stretched so it also acts as the anti-aliasing filter.
+        let cutoff = ratio.recip().min(1.0) * PASSBAND;
+        let half_width = (ZERO_CROSSINGS as f64 / cutoff).ceil() as usize;
+
+        Self {
+            channels,
+            from_rate,
+            to_rate,
+            ratio,
+            half_width,
+            kernel: build_kernel(cutoff, half_width),
+            history: Vec::new(),
+            history_start: 0,
+            input_frames: 0,
+            output_frames: 0,
+        }
+    }
+
+    /// Consumes more input and returns every output frame it completes.
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        self.history.extend_from_slice(samples);
+        self.input_frames += (samples.len() / self.channels) as u64;
+
+        let mut output = Vec::new();
+        loop {
+            let center = (self.output_frames as f64 * self.ratio).floor() as u64;
+            if center + self.half_width as u64 >= self.input_frames {
+                break;
+            }
+            self.render_frame(&mut output);
+        }
+        self.discard_history();
+        output
+    }
+
+    /// Returns the remaining output frames, treating the input past its end as silence.
+    pub fn finish(&mut self) -> Vec<f32> {
+        let total_frames =
+            (self.input_frames * self.to_rate as u64).div_ceil(self.from_rate as u64);
+
+        let mut output = Vec::new();
+        while self.output_frames < total_frames {
+            self.render_frame(&mut output);
+        }
+        output
+    }
+
+    fn render_frame(&mut self, output: &mut Vec<f32>) {
+        let position = self.output_frames as f64 * self.ratio;
+        let center = position.floor() as i64;
+        let first = (center - self.half_width as i64 + 1).max(self.history_start as i64);
+        let last = (center + self.half_width as i64).min(self.input_frames as i64 - 1);
+
+        let output_offset = output.len();
+        output.resize(output_offset + self.channels, 0.0);
+
+        for input_frame in first..=last {
+            let distance = (input_frame as f64 - position).abs() * TABLE_RESOLUTION as f64;
+            let index = distance as usize;
+            if index + 1 >= self.kernel.len() {
+                continue;
+            }
+            let fraction = (distance - index as f64) as f32;
+            let weight =
+                self.kernel[index] + (self.kernel[index + 1] - self.kernel[index]) * fraction;
+
+            let input_offset = (input_frame as u64 - self.history_start) as usize * self.channels;
+            for channel in 0..self.channels {
+                output[output_offset + channel] += self.history[input_offset + channel] * weight;
+            }
+        }
+
+        self.output_frames += 1;
+    }
+
+    fn discard_history(&mut self) {
+        let center = (self.output_frames as f64 * self.ratio).floor() as u64;
+        let first_needed = (center + 1)
+            .saturating_sub(self.half_width as u64)
+            .min(self.input_frames);
+        if first_needed > self.history_start {
+            let frames = (first_needed - self.history_start) as usize;
+            self.history.drain(..frames * self.channels);
+            self.history_start = first_needed;
+        }
+    }
+}
+
+/// Tabulates one side of a Blackman-windowed sinc kernel.
+fn build_kernel(cutoff: f64, half_width: usize) -> Vec<f32> {
+    let length = half_width * TABLE_RESOLUTION + 2;
+    (0..length)
+        .map(|index| {
+            let t = index as f64 / TABLE_RESOLUTION as f64;
+            if t >= half_width as f64 {
+                return 0.0;
+            }
+            let x = PI * cutoff * t;
+            let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+            let w = PI * t / half_width as f64;
+            let window = 0.42 + 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+            (cutoff * sinc * window) as f32
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `frames` of a stereo tone at `frequency`, its right channel at half the level.
+    fn tone(frames: usize, frequency: f64, rate: u32) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|n| {
+                let sample = (2.0 * PI * frequency * n as f64 / rate as f64).sin() as f32;
+                [sample, 0.5 * sample]
+            })
+            .collect()
+    }
+
+    fn resample(samples: &[f32], from_rate: u32, to_rate: u32, chunk_size: usize) -> Vec<f32> {
+        let mut resampler = Resampler::new(2, from_rate, to_rate);
+        let mut output = Vec::new();
+        for chunk in samples.chunks(chunk_size * 2) {
+            output.extend(resampler.process(chunk));
+        }
+        output.extend(resampler.finish());
+        output
+    }
+
+    fn rms(samples: &[f32]) -> f64 {
+        let sum: f64 = samples.iter().map(|&s| s as f64 * s as f64).sum();
+        (sum / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn chunked_input_gives_the_same_output() {
+        let input = tone(5000, 1234.5, 44100);
+        let whole = resample(&input, 44100, 48000, input.len());
+        for chunk_size in [1, 7, 960, 4999] {
+            assert_eq!(
+                resample(&input, 44100, 48000, chunk_size),
+                whole,
+                "{chunk_size}"
+            );
+        }
+    }
+
+    #[test]
+    fn output_length_is_exact() {
+        for (from_rate, frames, expected) in [
+            (44100, 44100, 48000),
+            (44100, 1001, 1090),
+            (16000, 16000, 48000),
+            (16000, 1001, 3003),
+            (44100, 0, 0),
+        ] {
+            let output = resample(&tone(frames, 440.0, from_rate), from_rate, 48000, 256);
+            assert_eq!(
+                output.len(),
+                expected * 2,
+                "{frames} frames at {from_rate}Hz"
+            );
+        }
+    }
+
+    #[test]
+    fn tones_above_the_target_nyquist_are_removed() {
+        // Away from the edges, where the kernel runs past the ends of the input.
+        let steady = |output: &[f32]| rms(&output[2000..output.len() - 2000]);
+        let passed = steady(&resample(&tone(9600, 1000.0, 96000), 96000, 48000, 512));
+        let aliased = steady(&resample(&tone(9600, 30000.0, 96000), 96000, 48000, 512));
+        assert!(passed > 0.5, "{passed}");
+        assert!(aliased < passed * 1e-3, "{aliased} against {passed}");
+    }
+}
@@ -0,0 +1,265 @@
+use std::borrow::Cow;
+use std::io::Cursor;
+use vorbis_rs::VorbisDecoder;
+
+use crate::audio_utils::{to_i16, AudioError};
+
+// Bytes of a sample header record in the `shdr` chunk.
+const SAMPLE_HEADER_SIZE: usize = 46;
+// Sample type flag marking an Ogg Vorbis compressed sample in SF3.
+pub const VORBIS_COMPRESSED: u16 = 0x10;
+// Zero sample points the SoundFont 2 spec requires after every sample.
+pub const SAMPLE_PADDING: usize = 46;
+
+/// Converts an SF3 file, whose samples are Ogg Vorbis streams, to an SF2 file with the
+/// samples decoded to 16-bit PCM, so rustysynth can load it.
+///
+/// Other data is returned unchanged, for the SoundFont parser to accept or reject.
+pub fn decompress(data: &[u8]) -> Result<Cow<'_, [u8]>, AudioError> {
+    let Some(chunks) = riff_chunks(data) else {
+        return Ok(Cow::Borrowed(data));
+    };
+    let list = |list_type: &[u8; 4]| {
+        chunks
+            .iter()
+            .find(|(id, body)| id == b"LIST" && body.starts_with(list_type))
+            .and_then(|(_, body)| subchunks(&body[4..]))
+    };
+    let (Some(sample_data), Some(parameters)) = (list(b"sdta"), list(b"pdta")) else {
+        return Ok(Cow::Borrowed(data));
+    };
+    let Some(&(_, sample_headers)) = parameters.iter().find(|(id, _)| id == b"shdr") else {
+        return Ok(Cow::Borrowed(data));
+    };
+    let compressed = sample_headers
+        .chunks_exact(SAMPLE_HEADER_SIZE)
+        .any(|record| sample_type(record) & VORBIS_COMPRESSED != 0);
+    if !compressed {
+        return Ok(Cow::Borrowed(data));
+    }
+
+    let smpl = sample_data
+        .iter()
+        .find(|(id, _)| id == b"smpl")
+        .map_or(&[][..], |&(_, body)| body);
+    let (wave_data, sample_headers) = decode_samples(smpl, sample_headers)?;
+
+    let mut body = b"sfbk".to_vec();
+    for (id, chunk) in &chunks {
+        if id == b"LIST" && chunk.starts_with(b"sdta") {
+            let mut list = b"sdta".to_vec();
+            write_chunk(&mut list, b"smpl", &wave_data);
+            write_chunk(&mut body, b"LIST", &list);
+        } else if id == b"LIST" && chunk.starts_with(b"pdta") {
+            let mut list = b"pdta".to_vec();
+            for (id, parameter) in &parameters {
+                let parameter = if id == b"shdr" {
+                    &sample_headers[..]
+                } else {
+                    parameter
+                };
+                write_chunk(&mut list, id, parameter);
+            }
+            write_chunk(&mut body, b"LIST", &list);
+        } else {
+            write_chunk(&mut body, id, chunk);
+        }
+    }
+    let mut sf2 = Vec::with_capacity(body.len() + 8);
+    write_chunk(&mut sf2, b"RIFF", &body);
+    Ok(Cow::Owned(sf2))
+}
+
+/// Decodes the compressed samples and lays all samples out again as 16-bit PCM, each
+/// followed by its padding. Returns the new `smpl` data and sample headers.
+///
+/// A compressed sample's start and end are byte offsets of its Ogg stream in `smpl`,
+/// and its loop points count samples from its start.
+fn decode_samples(smpl: &[u8], sample_headers: &[u8]) -> Result<(Vec<u8>, Vec<u8>), AudioError> {
+    let mut wave_data = Vec::new();
+    let mut headers = sample_headers.to_vec();
+    let count = headers.len() / SAMPLE_HEADER_SIZE;
+    // The last record only terminates the list.
+    for record in headers
+        .chunks_exact_mut(SAMPLE_HEADER_SIZE)
+        .take(count.saturating_sub(1))
+    {
+        let [start, end, loop_start, loop_end] =
+            [20, 24, 28, 32].map(|offset| read_u32(record, offset) as usize);
+        let position = wave_data.len() / 2;
+        let sample_type = sample_type(record);
+
+        let (length, loop_start, loop_end) = if sample_type & VORBIS_COMPRESSED != 0 {
+            let stream = smpl.get(start..end).ok_or_else(|| {
+                AudioError::SoundFont(format!("Sample data out of range at byte {start}"))
+            })?;
+            let samples = decode_sample(stream)?;
+            for sample in &samples {
+                wave_data.extend_from_slice(&sample.to_le_bytes());
+            }
+            record[44..46].copy_from_slice(&(sample_type & !VORBIS_COMPRESSED).to_le_bytes());
+            (samples.len(), loop_start, loop_end)
+        } else {
+            let pcm = smpl.get(start * 2..end * 2).ok_or_else(|| {
+                AudioError::SoundFont(format!("Sample data out of range at point {start}"))
+            })?;
+            wave_data.extend_from_slice(pcm);
+            (
+                end - start,
+                loop_start.saturating_sub(start),
+                loop_end.saturating_sub(start),
+            )
+        };
+        wave_data.resize(wave_data.len() + SAMPLE_PADDING * 2, 0);
+
+        for (offset, value) in [
+            (20, position),
+            (24, position + length),
+            (28, position + loop_start),
+            (32, position + loop_end),
+        ] {
+            record[offset..offset + 4].copy_from_slice(&(value as u32).to_le_bytes());
+        }
+    }
+    Ok((wave_data, headers))
+}
+
+/// Decodes one sample's Ogg Vorbis stream; SF3 samples are mono.
+fn decode_sample(stream: &[u8]) -> Result<Vec<i16>, AudioError> {
+    let mut decoder = VorbisDecoder::new(Cursor::new(stream))?;
+    let mut samples = Vec::new();
+    while let Some(block) = decoder.decode_audio_block()? {
+        samples.extend(block.samples()[0].iter().map(|&sample| to_i16(sample)));
+    }
+    Ok(samples)
+}
+
+/// The chunks inside an `sfbk` RIFF form, or `None` if `data` is not one.
+fn riff_chunks(data: &[u8]) -> Option<Vec<([u8; 4], &[u8])>> {
+    if data.get(..4)? != b"RIFF" || data.get(8..12)? != b"sfbk" {
+        return None;
+    }
+    let size = read_u32(data, 4) as usize;
+    subchunks(data.get(12..(size + 8).min(data.len()))?)
+}
+
+/// Splits a RIFF or LIST body into its chunks, or returns `None` if one overruns it.
+fn subchunks(body: &[u8]) -> Option<Vec<([u8; 4], &[u8])>> {
+    let mut chunks = Vec::new();
+    let mut position = 0;
+    while position + 8 <= body.len() {
+        let id: [u8; 4] = body[position..position + 4].try_into().unwrap();
+        let size = read_u32(body, position + 4) as usize;
+        let start = position + 8;
+        chunks.push((id, body.get(start..start.checked_add(size)?)?));
+        position = start + size + size % 2;
+    }
+    Some(chunks)
+}
+
+pub fn write_chunk(output: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    output.extend_from_slice(id);
+    output.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    output.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+        output.push(0); // Pad byte
+    }
+}
+
+fn sample_type(record: &[u8]) -> u16 {
+    u16::from_le_bytes([record[44], record[45]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_utils::load_soundfont;
+    use crate::fixtures::{sine, sound_font_file, vorbis_sample, MONO_SAMPLE};
+    use rustysynth::SoundFont;
+
+    #[test]
+    fn vorbis_samples_are_decoded_into_sf2() {
+        let (first, second) = (sine(1000, 100.0), sine(700, 50.0));
+        let mut smpl = vorbis_sample(&first);
+        let split = smpl.len() as u32;
+        smpl.extend(vorbis_sample(&second));
+        let sf3 = sound_font_file(
+            3,
+            &smpl,
+            &[[0, split, 100, 900], [split, smpl.len() as u32, 0, 700]],
+            MONO_SAMPLE | VORBIS_COMPRESSED,
+        );
+        assert!(SoundFont::new(&mut Cursor::new(&sf3)).is_err());
+
+        let sf2 = decompress(&sf3).unwrap();
+        let sound_font = SoundFont::new(&mut Cursor::new(&sf2[..])).unwrap();
+        let headers = sound_font.get_sample_headers();
+        let bounds: Vec<_> = headers
+            .iter()
+            .map(|header| {
+                (
+                    header.get_start(),
+                    header.get_end(),
+                    header.get_start_loop(),
+                    header.get_end_loop(),
+                    header.get_sample_type(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            bounds,
+            [(0, 1000, 100, 900, 1), (1046, 1746, 1046, 1746, 1)]
+        );
+
+        let wave_data = sound_font.get_wave_data();
+        assert_eq!(wave_data.len(), 1746 + SAMPLE_PADDING);
+        for (decoded, original) in wave_data[1046..1746].iter().zip(&second) {
+            let decoded = *decoded as f32 / 32768.0;
+            assert!((decoded - original).abs() < 0.02, "{decoded} vs {original}");
+        }
+        assert!(wave_data[1000..1046].iter().all(|&sample| sample == 0));
+        assert_eq!(sound_font.get_presets()[0].get_name(), "Sine");
+
+        // SF2 files and anything else pass through untouched.
+        assert!(matches!(decompress(&sf2).unwrap(), Cow::Borrowed(_)));
+        assert!(matches!(decompress(b"MThd").unwrap(), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn malformed_files_are_rejected_without_panicking() {
+        let smpl = vorbis_sample(&sine(1000, 100.0));
+        let sf3 = sound_font_file(
+            3,
+            &smpl,
+            &[[0, smpl.len() as u32, 0, 1000]],
+            MONO_SAMPLE | VORBIS_COMPRESSED,
+        );
+        assert!(load_soundfont(&sf3).is_ok());
+
+        // A RIFF size too small to hold the form type.
+        let mut empty = b"RIFF\0\0\0\0sfbk".to_vec();
+        assert!(matches!(decompress(&empty).unwrap(), Cow::Borrowed(_)));
+        empty.extend_from_slice(&sf3[12..]);
+        assert!(load_soundfont(&empty).is_err());
+
+        for length in (0..sf3.len()).step_by(7) {
+            assert!(load_soundfont(&sf3[..length]).is_err(), "{length}");
+        }
+        // Sample bounds outside the sample data, and a stream that is not Ogg Vorbis.
+        for bounds in [[0, smpl.len() as u32 + 1, 0, 0], [4, 40, 0, 0]] {
+            let sf3 = sound_font_file(3, &smpl, &[bounds], MONO_SAMPLE | VORBIS_COMPRESSED);
+            assert!(load_soundfont(&sf3).is_err());
+        }
+
+        // A preset list whose terminator points past the preset zones.
+        let mut sf2 = sound_font_file(2, &[0; 200], &[[0, 50, 0, 50]], MONO_SAMPLE);
+        assert!(load_soundfont(&sf2).is_ok());
+        let terminator = sf2.windows(3).position(|name| name == b"EOP").unwrap();
+        sf2[terminator + 24..terminator + 26].copy_from_slice(&500u16.to_le_bytes());
+        assert!(load_soundfont(&sf2).is_err());
+    }
+}